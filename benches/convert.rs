@@ -0,0 +1,32 @@
+// Pixel format conversion throughput per frame size
+//
+// The binary has no library target, so the module is compiled in directly.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+#[allow(dead_code)]
+#[path = "../src/convert.rs"]
+mod convert;
+
+use convert::{Colorimetry, PixelFormat};
+
+fn bench_convert(c: &mut Criterion) {
+    let colorimetry = Colorimetry::default();
+
+    for (width, height) in [(1280u32, 720u32), (1920, 1080), (3840, 2160)] {
+        // Something other than a flat colour so nothing is trivially predictable
+        let bgra: Vec<u8> = (0..width * height * 4).map(|i| (i * 7 % 251) as u8).collect();
+
+        let mut group = c.benchmark_group(format!("{}x{}", width, height));
+        group.throughput(Throughput::Elements((width * height) as u64));
+        for format in [PixelFormat::Bgr, PixelFormat::I420, PixelFormat::Nv12] {
+            group.bench_with_input(BenchmarkId::from_parameter(format.caps_name()), &bgra, |b, bgra| {
+                b.iter(|| convert::convert(format, bgra, width, height, &colorimetry))
+            });
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, bench_convert);
+criterion_main!(benches);
@@ -0,0 +1,534 @@
+// Screen activity detection for alerts
+//
+// Each captured frame is reduced to a grid holding the average luma of every
+// CELL_SIZE square (sampling every SAMPLE_STEP pixels) and compared with the
+// previous frame's grid. A cell changed when its luma moved further than the
+// zone's sensitivity allows; a zone reports an event once enough of its cells
+// changed, then stays quiet for the cooldown. Events go to the log as JSON
+// and, when configured, to a command and an HTTP webhook.
+
+use log::{error, info, warn};
+use std::process::Command;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config::{ActivityConfig, ActivityZone};
+use crate::http;
+
+/// Edge length in pixels of the squares compared between frames
+const CELL_SIZE: usize = 16;
+
+/// Distance in pixels between the samples averaged for a cell
+const SAMPLE_STEP: usize = 4;
+
+/// Name of the zone covering the whole frame when none are configured
+const WHOLE_FRAME_ZONE: &str = "screen";
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Activity seen in one zone of a display
+#[derive(Debug, Clone, PartialEq)]
+pub struct ActivityEvent {
+    pub display: usize,
+    pub zone: String,
+    /// Fraction of the zone that changed (0.0 - 1.0)
+    pub changed: f32,
+}
+
+impl ActivityEvent {
+    /// The event as a JSON object, stamped with `time`
+    pub fn to_json(&self, time: &str) -> String {
+        format!(
+            "{{\"event\":\"activity\",\"display\":{},\"zone\":\"{}\",\"changed\":{:.4},\"time\":\"{}\"}}",
+            self.display,
+            json_escape(&self.zone),
+            self.changed,
+            json_escape(time)
+        )
+    }
+}
+
+struct Zone {
+    name: String,
+    // Bounds as fractions of the frame
+    x: f32,
+    y: f32,
+    width: f32,
+    height: f32,
+    // Largest luma change of a cell that is ignored
+    luma_threshold: u8,
+    min_area: f32,
+    last_event: Option<Instant>,
+}
+
+struct DetectorState {
+    columns: usize,
+    rows: usize,
+    // Average luma of every cell of the previous frame, row by row
+    grid: Vec<u8>,
+    zones: Vec<Zone>,
+}
+
+pub struct ActivityDetector {
+    slot: usize,
+    cooldown: Duration,
+    command: String,
+    webhook: String,
+    state: Mutex<DetectorState>,
+}
+
+impl ActivityDetector {
+    pub fn new(slot: usize, config: &ActivityConfig) -> Self {
+        let whole_frame = [ActivityZone {
+            name: WHOLE_FRAME_ZONE.to_string(),
+            ..ActivityZone::default()
+        }];
+        let zones = if config.zones.is_empty() { &whole_frame[..] } else { &config.zones[..] };
+
+        let zones = zones
+            .iter()
+            .map(|zone| Zone {
+                name: zone.name.clone(),
+                x: zone.x,
+                y: zone.y,
+                width: zone.width,
+                height: zone.height,
+                luma_threshold: luma_threshold(zone.sensitivity.unwrap_or(config.sensitivity)),
+                min_area: zone.min_area.unwrap_or(config.min_area),
+                last_event: None,
+            })
+            .collect();
+
+        Self {
+            slot,
+            cooldown: Duration::from_secs(config.cooldown as u64),
+            command: config.command.clone(),
+            webhook: config.webhook.clone(),
+            state: Mutex::new(DetectorState {
+                columns: 0,
+                rows: 0,
+                grid: Vec::new(),
+                zones,
+            }),
+        }
+    }
+
+    /// Look for activity in a captured BGRA frame and report it
+    pub fn update(&self, frame: &[u8], width: u32, height: u32) {
+        for event in self.detect(frame, width, height, Instant::now()) {
+            self.report(&event);
+        }
+    }
+
+    /// Events caused by a BGRA frame captured at `now`, compared with the
+    /// previous one. Depends only on the frames and times passed in.
+    pub fn detect(&self, frame: &[u8], width: u32, height: u32, now: Instant) -> Vec<ActivityEvent> {
+        let (columns, rows, grid) = match luma_grid(frame, width as usize, height as usize) {
+            Some(grid) => grid,
+            None => return Vec::new(),
+        };
+
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+
+        // First frame, or the size changed: nothing to compare with yet
+        if (state.columns, state.rows) != (columns, rows) || state.grid.len() != grid.len() {
+            state.columns = columns;
+            state.rows = rows;
+            state.grid = grid;
+            return Vec::new();
+        }
+
+        let differences: Vec<u8> = grid
+            .iter()
+            .zip(&state.grid)
+            .map(|(current, previous)| current.abs_diff(*previous))
+            .collect();
+
+        let mut events = Vec::new();
+        for zone in &mut state.zones {
+            let left = ((zone.x * columns as f32).floor().max(0.0) as usize).min(columns);
+            let top = ((zone.y * rows as f32).floor().max(0.0) as usize).min(rows);
+            let right = (((zone.x + zone.width) * columns as f32).ceil().max(0.0) as usize).min(columns);
+            let bottom = (((zone.y + zone.height) * rows as f32).ceil().max(0.0) as usize).min(rows);
+            if left >= right || top >= bottom {
+                continue;
+            }
+
+            let changed_cells = (top..bottom)
+                .flat_map(|row| differences[row * columns + left..row * columns + right].iter())
+                .filter(|difference| **difference > zone.luma_threshold)
+                .count();
+            let changed = changed_cells as f32 / ((right - left) * (bottom - top)) as f32;
+
+            let cooled_down = zone
+                .last_event
+                .is_none_or(|last| now.saturating_duration_since(last) >= self.cooldown);
+            if changed_cells > 0 && changed >= zone.min_area && cooled_down {
+                zone.last_event = Some(now);
+                events.push(ActivityEvent {
+                    display: self.slot,
+                    zone: zone.name.clone(),
+                    changed,
+                });
+            }
+        }
+
+        state.grid = grid;
+        events
+    }
+
+    // Log the event and hand it to the command and webhook
+    fn report(&self, event: &ActivityEvent) {
+        let time = glib::DateTime::now_utc()
+            .and_then(|now| now.format("%Y-%m-%dT%H:%M:%SZ"))
+            .map(|time| time.to_string())
+            .unwrap_or_default();
+        let json = event.to_json(&time);
+        info!("Activity event: {}", json);
+
+        if !self.command.is_empty() {
+            let (shell, flag) = if cfg!(windows) { ("cmd", "/C") } else { ("sh", "-c") };
+            let child = Command::new(shell)
+                .arg(flag)
+                .arg(&self.command)
+                .env("ACTIVITY_EVENT", &json)
+                .env("ACTIVITY_DISPLAY", event.display.to_string())
+                .env("ACTIVITY_ZONE", &event.zone)
+                .env("ACTIVITY_CHANGED", format!("{:.4}", event.changed))
+                .spawn();
+            match child {
+                // Reap it without holding up the capture thread
+                Ok(mut child) => {
+                    thread::spawn(move || child.wait());
+                }
+                Err(e) => error!("Failed to run activity command: {}", e),
+            }
+        }
+
+        if !self.webhook.is_empty() {
+            let webhook = self.webhook.clone();
+            thread::spawn(move || {
+                match http::post(&webhook, "application/json", json.as_bytes(), WEBHOOK_TIMEOUT) {
+                    Ok((status, _)) if (200..300).contains(&status) => {}
+                    Ok((status, body)) => warn!("Activity webhook {} answered {}: {}", webhook, status, body),
+                    Err(e) => warn!("Activity webhook {} failed: {:#}", webhook, e),
+                }
+            });
+        }
+    }
+}
+
+// Largest per-cell luma change ignored at a sensitivity of 1-100
+fn luma_threshold(sensitivity: u32) -> u8 {
+    (1 + (100 - sensitivity.clamp(1, 100)) * 64 / 100) as u8
+}
+
+// Average luma of each cell of a BGRA frame: (columns, rows, cells)
+fn luma_grid(frame: &[u8], width: usize, height: usize) -> Option<(usize, usize, Vec<u8>)> {
+    if width == 0 || height == 0 {
+        return None;
+    }
+    // Rows may be padded, so derive the stride from the buffer
+    let stride = frame.len() / height;
+    if stride < width * 4 {
+        return None;
+    }
+
+    let columns = width.div_ceil(CELL_SIZE);
+    let rows = height.div_ceil(CELL_SIZE);
+    let mut sums = vec![0u32; columns * rows];
+    let mut counts = vec![0u32; columns * rows];
+
+    for y in (0..height).step_by(SAMPLE_STEP) {
+        let row = &frame[y * stride..y * stride + width * 4];
+        let cells = (y / CELL_SIZE) * columns;
+        for x in (0..width).step_by(SAMPLE_STEP) {
+            let pixel = &row[x * 4..x * 4 + 3];
+            // BT.601 luma from B, G, R
+            let luma = (pixel[0] as u32 * 29 + pixel[1] as u32 * 150 + pixel[2] as u32 * 77) >> 8;
+            sums[cells + x / CELL_SIZE] += luma;
+            counts[cells + x / CELL_SIZE] += 1;
+        }
+    }
+
+    let grid = sums
+        .iter()
+        .zip(&counts)
+        .map(|(sum, count)| (sum / (*count).max(1)) as u8)
+        .collect();
+    Some((columns, rows, grid))
+}
+
+fn json_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WIDTH: u32 = 640;
+    const HEIGHT: u32 = 480;
+
+    // Gray BGRA frame with the pixels in (left, top, right, bottom) set to `value`
+    fn frame(width: u32, height: u32, rect: Option<(u32, u32, u32, u32)>, value: u8) -> Vec<u8> {
+        let mut frame = vec![20u8; (width * height * 4) as usize];
+        if let Some((left, top, right, bottom)) = rect {
+            for y in top..bottom {
+                for x in left..right {
+                    let i = ((y * width + x) * 4) as usize;
+                    frame[i..i + 3].fill(value);
+                }
+            }
+        }
+        frame
+    }
+
+    fn background() -> Vec<u8> {
+        frame(WIDTH, HEIGHT, None, 20)
+    }
+
+    fn with_rect(rect: (u32, u32, u32, u32), value: u8) -> Vec<u8> {
+        frame(WIDTH, HEIGHT, Some(rect), value)
+    }
+
+    fn config() -> ActivityConfig {
+        ActivityConfig {
+            enabled: true,
+            ..ActivityConfig::default()
+        }
+    }
+
+    fn zone(name: &str, x: f32, y: f32, width: f32, height: f32) -> ActivityZone {
+        ActivityZone {
+            name: name.to_string(),
+            x,
+            y,
+            width,
+            height,
+            ..ActivityZone::default()
+        }
+    }
+
+    fn seconds(start: Instant, seconds: u64) -> Instant {
+        start + Duration::from_secs(seconds)
+    }
+
+    // Zones that report an event for `next` after `previous`
+    fn zones_triggered(detector: &ActivityDetector, previous: &[u8], next: &[u8]) -> Vec<String> {
+        let start = Instant::now();
+        assert!(detector.detect(previous, WIDTH, HEIGHT, start).is_empty());
+        detector
+            .detect(next, WIDTH, HEIGHT, seconds(start, 1))
+            .into_iter()
+            .map(|event| event.zone)
+            .collect()
+    }
+
+    #[test]
+    fn first_and_identical_frames_are_quiet() {
+        let detector = ActivityDetector::new(0, &config());
+        let start = Instant::now();
+        assert!(detector.detect(&background(), WIDTH, HEIGHT, start).is_empty());
+        assert!(detector.detect(&background(), WIDTH, HEIGHT, seconds(start, 1)).is_empty());
+    }
+
+    #[test]
+    fn change_reports_the_whole_frame_zone() {
+        let detector = ActivityDetector::new(3, &config());
+        let start = Instant::now();
+        detector.detect(&background(), WIDTH, HEIGHT, start);
+
+        // 100x100 pixels touch 7x7 of the 40x30 cells
+        let events = detector.detect(&with_rect((100, 100, 200, 200), 250), WIDTH, HEIGHT, seconds(start, 1));
+        assert_eq!(
+            events,
+            vec![ActivityEvent {
+                display: 3,
+                zone: WHOLE_FRAME_ZONE.to_string(),
+                changed: 49.0 / 1200.0,
+            }]
+        );
+    }
+
+    #[test]
+    fn zones_only_see_their_part_of_the_frame() {
+        let mut config = config();
+        config.zones = vec![
+            zone("left", 0.0, 0.0, 0.5, 1.0),
+            zone("right", 0.5, 0.0, 0.5, 1.0),
+            zone("bottom", 0.0, 0.75, 1.0, 0.25),
+            // Outside the frame, never reports
+            zone("outside", 1.0, 0.0, 0.5, 1.0),
+        ];
+
+        let detector = ActivityDetector::new(0, &config);
+        let events = zones_triggered(&detector, &background(), &with_rect((400, 0, 500, 100), 250));
+        assert_eq!(events, vec!["right"]);
+
+        let detector = ActivityDetector::new(0, &config);
+        let events = zones_triggered(&detector, &background(), &with_rect((0, 400, 640, 480), 250));
+        assert_eq!(events, vec!["left", "right", "bottom"]);
+    }
+
+    #[test]
+    fn zone_bounds_round_outwards_to_whole_cells() {
+        // 0.01 of the width is less than a cell but still covers cell 0
+        let mut config = config();
+        config.zones = vec![zone("corner", 0.0, 0.0, 0.01, 0.01)];
+        let detector = ActivityDetector::new(0, &config);
+        let events = zones_triggered(&detector, &background(), &with_rect((12, 12, 16, 16), 250));
+        assert_eq!(events, vec!["corner"]);
+    }
+
+    #[test]
+    fn sensitivity_maps_to_a_luma_threshold() {
+        assert_eq!(luma_threshold(100), 1);
+        assert_eq!(luma_threshold(80), 13);
+        assert_eq!(luma_threshold(50), 33);
+        assert_eq!(luma_threshold(1), 64);
+        // Out of range values are clamped
+        assert_eq!(luma_threshold(0), 64);
+        assert_eq!(luma_threshold(500), 1);
+
+        // A change of 10 is noise at 80 but activity at 100
+        let brighter = with_rect((0, 0, WIDTH, HEIGHT), 30);
+        let detector = ActivityDetector::new(0, &config());
+        assert!(zones_triggered(&detector, &background(), &brighter).is_empty());
+
+        let mut sensitive = config();
+        sensitive.sensitivity = 100;
+        let detector = ActivityDetector::new(0, &sensitive);
+        assert_eq!(zones_triggered(&detector, &background(), &brighter), vec!["screen"]);
+
+        // Zones can override the sensitivity
+        let mut config = config();
+        config.zones = vec![
+            zone("default", 0.0, 0.0, 0.5, 1.0),
+            ActivityZone {
+                sensitivity: Some(100),
+                ..zone("sensitive", 0.5, 0.0, 0.5, 1.0)
+            },
+        ];
+        let detector = ActivityDetector::new(0, &config);
+        assert_eq!(zones_triggered(&detector, &background(), &brighter), vec!["sensitive"]);
+    }
+
+    #[test]
+    fn changes_below_min_area_are_ignored() {
+        // 1% of the 1200 cells is 12 cells; a 16 pixel high strip covers
+        // one cell per 16 pixels of width
+        let config = config();
+        assert_eq!(config.min_area, 0.01);
+
+        let detector = ActivityDetector::new(0, &config);
+        assert!(zones_triggered(&detector, &background(), &with_rect((0, 0, 176, 16), 250)).is_empty());
+
+        let detector = ActivityDetector::new(0, &config);
+        assert_eq!(zones_triggered(&detector, &background(), &with_rect((0, 0, 192, 16), 250)), vec!["screen"]);
+
+        // Zones can override it
+        let mut config = config;
+        config.zones = vec![ActivityZone {
+            min_area: Some(0.5),
+            ..zone("half", 0.0, 0.0, 1.0, 1.0)
+        }];
+        let detector = ActivityDetector::new(0, &config);
+        assert!(zones_triggered(&detector, &background(), &with_rect((0, 0, 640, 224), 250)).is_empty());
+        let detector = ActivityDetector::new(0, &config);
+        assert_eq!(zones_triggered(&detector, &background(), &with_rect((0, 0, 640, 240), 250)), vec!["half"]);
+    }
+
+    #[test]
+    fn zones_stay_quiet_during_the_cooldown() {
+        let mut config = config();
+        config.cooldown = 30;
+        config.zones = vec![zone("left", 0.0, 0.0, 0.5, 1.0), zone("right", 0.5, 0.0, 0.5, 1.0)];
+        let detector = ActivityDetector::new(0, &config);
+        let left = with_rect((0, 0, 320, 480), 250);
+        let right = with_rect((320, 0, 640, 480), 250);
+
+        let start = Instant::now();
+        let zones = |frame: &[u8], at: u64| -> Vec<String> {
+            detector
+                .detect(frame, WIDTH, HEIGHT, seconds(start, at))
+                .into_iter()
+                .map(|event| event.zone)
+                .collect()
+        };
+
+        assert!(zones(&background(), 0).is_empty());
+        assert_eq!(zones(&left, 1), vec!["left"]);
+
+        // The left zone is cooling down, the right one is not
+        assert!(zones(&background(), 2).is_empty());
+        assert_eq!(zones(&right, 3), vec!["right"]);
+
+        // Both halves change, both are cooling down
+        assert!(zones(&left, 20).is_empty());
+
+        // Changes during the cooldown still update the reference frame, so
+        // only the left half differs from it here
+        assert_eq!(zones(&background(), 31), vec!["left"]);
+        assert!(zones(&right, 32).is_empty());
+        assert_eq!(zones(&background(), 33), vec!["right"]);
+    }
+
+    #[test]
+    fn size_change_starts_over() {
+        let detector = ActivityDetector::new(0, &config());
+        let start = Instant::now();
+        detector.detect(&background(), WIDTH, HEIGHT, start);
+
+        // Nothing to compare a new size with
+        let small = frame(320, 240, None, 20);
+        assert!(detector.detect(&small, 320, 240, seconds(start, 1)).is_empty());
+
+        let changed = frame(320, 240, Some((0, 0, 320, 240)), 250);
+        assert_eq!(detector.detect(&changed, 320, 240, seconds(start, 2)).len(), 1);
+
+        // Unusable frames are skipped without losing the reference
+        assert!(detector.detect(&[], 0, 0, seconds(start, 3)).is_empty());
+        assert!(detector.detect(&changed[..100], 320, 240, seconds(start, 4)).is_empty());
+        assert!(detector.detect(&changed, 320, 240, seconds(start, 5)).is_empty());
+    }
+
+    #[test]
+    fn row_padding_is_ignored() {
+        let detector = ActivityDetector::new(0, &config());
+        let stride = (WIDTH as usize + 8) * 4;
+        let mut padded = vec![20u8; stride * HEIGHT as usize];
+        let start = Instant::now();
+        detector.detect(&padded, WIDTH, HEIGHT, start);
+
+        for row in padded.chunks_exact_mut(stride) {
+            row[WIDTH as usize * 4..].fill(255);
+        }
+        assert!(detector.detect(&padded, WIDTH, HEIGHT, seconds(start, 1)).is_empty());
+    }
+
+    #[test]
+    fn events_are_json() {
+        let event = ActivityEvent {
+            display: 2,
+            zone: "say \"hi\"\n".to_string(),
+            changed: 0.25,
+        };
+        assert_eq!(
+            event.to_json("2024-01-02T03:04:05Z"),
+            "{\"event\":\"activity\",\"display\":2,\"zone\":\"say \\\"hi\\\"\\u000a\",\
+             \"changed\":0.2500,\"time\":\"2024-01-02T03:04:05Z\"}"
+        );
+    }
+}
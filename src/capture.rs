@@ -1,13 +1,72 @@
 use anyhow::{Context, Result};
 use display_info::DisplayInfo;
-use log::{error, info};
+use log::{error, info, warn};
 use scrap::{Capturer, Display};
+use std::io;
+use std::ops::Deref;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread;
 use std::time::{Duration, Instant};
 
+use crate::activity::ActivityDetector;
+use crate::config::{Config, StreamConfig, WindowConfig};
+use crate::convert::{self, Colorimetry, PixelFormat};
+use crate::damage::{Damage, DamageTracker, Rect};
+use crate::pacing::FramePacer;
 use crate::rtsp::RtspMount;
+use crate::snapshot::LatestFrame;
+
+/// Delay before the first capture restart, doubled after each failure
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Size window streams are announced with until the window is first found
+pub const WINDOW_INITIAL_SIZE: (u32, u32) = (1280, 720);
+
+/// Capture loop settings derived from the configuration
+#[derive(Debug, Clone)]
+pub struct CaptureSettings {
+    pub frame_rate: u32,
+    pub skip_unchanged_frames: bool,
+    pub min_frame_rate: u32,
+    pub adaptive_frame_rate: bool,
+    pub capture_backend: String,
+    pub pixel_format: PixelFormat,
+    /// YUV conversion of the stream the thread feeds
+    pub colorimetry: Colorimetry,
+}
+
+impl CaptureSettings {
+    pub fn from_config(config: &Config, stream: &StreamConfig) -> Self {
+        Self {
+            frame_rate: config.frame_rate,
+            skip_unchanged_frames: config.skip_unchanged_frames,
+            min_frame_rate: config.min_frame_rate,
+            adaptive_frame_rate: config.adaptive_frame_rate,
+            capture_backend: config.capture_backend.clone(),
+            pixel_format: config.pixel_format(),
+            colorimetry: stream.colorimetry(),
+        }
+    }
+}
+
+/// Everything a capture thread delivers its frames to
+#[derive(Clone, Default)]
+pub struct FrameOutputs {
+    /// Streams fed with converted frames
+    pub rtsp_mounts: Vec<RtspMount>,
+    /// Copy of the most recent frame for HTTP snapshots
+    pub latest_frame: Option<Arc<LatestFrame>>,
+    /// Detector looking for screen activity in the captured frames
+    pub activity: Option<Arc<ActivityDetector>>,
+}
+
+impl FrameOutputs {
+    pub fn is_empty(&self) -> bool {
+        self.rtsp_mounts.is_empty() && self.latest_frame.is_none() && self.activity.is_none()
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct DisplayMetadata {
@@ -66,59 +125,265 @@ pub fn get_displays() -> Result<Vec<DisplayMetadata>> {
 }
 
 
-fn capture_display_thread(
-    index: usize,
-    rtsp_mount: RtspMount,
-    frame_rate: u32,
+/// What a capture thread captures
+#[derive(Debug, Clone)]
+pub enum CaptureTarget {
+    /// A whole display, found again by its desktop position on every restart
+    Display(DisplayMetadata),
+    /// A single application window
+    Window(WindowConfig),
+}
+
+impl CaptureTarget {
+    // Open a new source of frames for this target
+    fn open(&self, settings: &CaptureSettings) -> Result<Box<dyn FrameSource>> {
+        match self {
+            #[cfg(target_os = "linux")]
+            CaptureTarget::Display(display_meta) if settings.capture_backend == "x11" => {
+                let current = find_display(display_meta)?;
+                Ok(Box::new(crate::x11::screen::ScreenSource::open(&current)?))
+            }
+            CaptureTarget::Display(display_meta) => {
+                // The GStreamer-native backends use scrap for composite streams
+                if !matches!(settings.capture_backend.as_str(), "scrap" | "ximagesrc" | "pipewiresrc") {
+                    warn!("Capture backend '{}' is not available, using scrap", settings.capture_backend);
+                }
+                Ok(Box::new(ScrapSource::open(display_meta)?))
+            }
+            #[cfg(target_os = "linux")]
+            CaptureTarget::Window(window) => Ok(Box::new(crate::x11::window::WindowSource::open(window)?)),
+            #[cfg(not(target_os = "linux"))]
+            CaptureTarget::Window(_) => anyhow::bail!("Window capture is only supported on Linux"),
+        }
+    }
+}
+
+/// A captured BGRA frame, borrowed from the source that produced it
+pub struct CapturedFrame<'a> {
+    pixels: Pixels<'a>,
+    width: u32,
+    height: u32,
+    dirty_rects: Option<&'a [Rect]>,
+}
+
+enum Pixels<'a> {
+    Scrap(scrap::Frame<'a>),
+    Buffer(&'a [u8]),
+}
+
+impl<'a> CapturedFrame<'a> {
+    /// A `width` x `height` frame, whose rows may be padded
+    pub fn new(buffer: &'a [u8], width: u32, height: u32) -> Self {
+        Self {
+            pixels: Pixels::Buffer(buffer),
+            width,
+            height,
+            dirty_rects: None,
+        }
+    }
+    
+    /// A frame along with the regions that changed since the previous one,
+    /// for sources that track changes themselves
+    pub fn damaged(buffer: &'a [u8], width: u32, height: u32, dirty_rects: &'a [Rect]) -> Self {
+        Self {
+            dirty_rects: Some(dirty_rects),
+            ..Self::new(buffer, width, height)
+        }
+    }
+    
+    /// Size of this frame, which may differ from the previous one's
+    pub fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+    
+    pub fn dirty_rects(&self) -> Option<&[Rect]> {
+        self.dirty_rects
+    }
+}
+
+impl Deref for CapturedFrame<'_> {
+    type Target = [u8];
+    
+    fn deref(&self) -> &[u8] {
+        match &self.pixels {
+            Pixels::Scrap(frame) => frame,
+            Pixels::Buffer(buffer) => buffer,
+        }
+    }
+}
+
+/// A source of BGRA frames driven by a capture thread
+pub trait FrameSource {
+    /// Grab the next frame. `WouldBlock` means no frame is available right now
+    fn frame(&mut self) -> io::Result<CapturedFrame<'_>>;
+    
+    /// Whether there is anything to capture; false e.g. while a window is
+    /// minimised, in which case `frame` reports `WouldBlock`
+    fn available(&self) -> bool {
+        true
+    }
+}
+
+// Whole-display capture through scrap
+struct ScrapSource {
+    capturer: Capturer,
+    width: u32,
+    height: u32,
+}
+
+impl ScrapSource {
+    fn open(display_meta: &DisplayMetadata) -> Result<Self> {
+        let current = find_display(display_meta)?;
+        
+        let displays = Display::all().context("Failed to enumerate displays")?;
+        
+        if current.index >= displays.len() {
+            anyhow::bail!("Display index {} out of bounds (only {} displays found)", 
+                          current.index, displays.len());
+        }
+        
+        // Get dimensions for BGR conversion
+        let width = displays[current.index].width() as u32;
+        let height = displays[current.index].height() as u32;
+        
+        // Create a capturer
+        // This takes ownership of the display
+        let capturer = Capturer::new(displays.into_iter().nth(current.index).unwrap())
+            .context("Failed to create screen capturer")?;
+        
+        Ok(Self {
+            capturer,
+            width,
+            height,
+        })
+    }
+}
+
+impl FrameSource for ScrapSource {
+    fn frame(&mut self) -> io::Result<CapturedFrame<'_>> {
+        let frame = self.capturer.frame()?;
+        Ok(CapturedFrame {
+            pixels: Pixels::Scrap(frame),
+            width: self.width,
+            height: self.height,
+            dirty_rects: None,
+        })
+    }
+}
+
+// Look the display up again on every restart: displays can be hotplugged,
+// so its enumeration index and geometry may have changed
+fn find_display(display_meta: &DisplayMetadata) -> Result<DisplayMetadata> {
+    get_displays()?
+        .into_iter()
+        .find(|d| d.x == display_meta.x && d.y == display_meta.y)
+        .context("Display is no longer connected")
+}
+
+fn capture_thread(
+    name: String,
+    target: CaptureTarget,
+    outputs: FrameOutputs,
+    settings: CaptureSettings,
     running: Arc<AtomicBool>
 ) -> Result<thread::JoinHandle<()>> {
-    // Create a separate thread to own the Display and Capturer
+    // Create a separate thread to own the capturer
     let handle = thread::spawn(move || {
-        // Perform display capture within the thread
-        match capture_frames(index, rtsp_mount, frame_rate, running) {
-            Ok(_) => info!("Capture thread for display {} completed", index),
-            Err(e) => error!("Capture thread for display {} failed: {}", index, e),
+        // Perform capture within the thread
+        match capture_frames(&name, target, outputs, settings, running) {
+            Ok(_) => info!("Capture thread for {} completed", name),
+            Err(e) => error!("Capture thread for {} failed: {}", name, e),
         }
     });
     
     Ok(handle)
 }
 
-// This function is called within the thread and handles the actual frame capture
+// This function is called within the thread and supervises the capture,
+// restarting it with exponential backoff whenever the capturer fails
+// (display mode change, screen lock, driver reset, window closed, ...)
 fn capture_frames(
-    display_index: usize,
-    rtsp_mount: RtspMount,
-    frame_rate: u32,
+    name: &str,
+    target: CaptureTarget,
+    outputs: FrameOutputs,
+    settings: CaptureSettings,
     running: Arc<AtomicBool>
 ) -> Result<()> {
-    // Get displays now, within the thread
-    let displays = Display::all().context("Failed to enumerate displays")?;
+    // Size the mounts were created with
+    let mut frame_size = match &target {
+        CaptureTarget::Display(display_meta) => (display_meta.width, display_meta.height),
+        CaptureTarget::Window(_) => (WINDOW_INITIAL_SIZE.0, WINDOW_INITIAL_SIZE.1),
+    };
+    let mut backoff = INITIAL_BACKOFF;
     
-    if display_index >= displays.len() {
-        anyhow::bail!("Display index {} out of bounds (only {} displays found)", 
-                      display_index, displays.len());
+    while running.load(Ordering::SeqCst) {
+        match capture_session(
+            name,
+            &target,
+            &outputs,
+            &settings,
+            &running,
+            &mut frame_size,
+            &mut backoff,
+        ) {
+            Ok(()) => break,
+            Err(e) => {
+                error!("Capture of {} failed: {:#}", name, e);
+                warn!("Restarting capture of {} in {:?}", name, backoff);
+                
+                // The mount sends placeholder frames to clients in the meantime
+                set_available(&outputs.rtsp_mounts, false);
+                wait_while_running(backoff, &running);
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        }
     }
     
-    // Get a reference to the display
-    // Get dimensions for BGR conversion
-    let width = displays[display_index].width() as u32;
-    let height = displays[display_index].height() as u32;
+    // Mounts outliving the thread, e.g. mosaic tiles of an unplugged
+    // display, show the placeholder
+    set_available(&outputs.rtsp_mounts, false);
+    info!("Capture thread for {} exiting", name);
+    Ok(())
+}
+
+// Runs a single frame source until shutdown (Ok) or until capturing fails (Err)
+fn capture_session(
+    name: &str,
+    target: &CaptureTarget,
+    outputs: &FrameOutputs,
+    settings: &CaptureSettings,
+    running: &AtomicBool,
+    frame_size: &mut (u32, u32),
+    backoff: &mut Duration,
+) -> Result<()> {
+    let mut source = target.open(settings)?;
+    let rtsp_mounts = &outputs.rtsp_mounts;
     
-    // Create a capturer
-    // This takes ownership of the display
-    let mut capturer = Capturer::new(displays.into_iter().nth(display_index).unwrap())
-        .context("Failed to create screen capturer")?;
+    info!("Started capture for {}", name);
+    let mut available = true;
+    set_available(rtsp_mounts, true);
     
-    info!("Started capture thread for display {}", display_index);
+    let mut pacer = FramePacer::new(
+        settings.min_frame_rate,
+        settings.frame_rate,
+        settings.adaptive_frame_rate,
+    );
+    let keep_alive_delay =
+        Duration::from_micros((1_000_000.0 / settings.min_frame_rate.max(1) as f64) as u64);
     
-    let frame_delay = Duration::from_micros((1_000_000.0 / frame_rate as f64) as u64);
+    // Change detection state: the last frame we sent is re-sent at the
+    // keep-alive rate while the screen stays static, whether we skipped
+    // unchanged frames or the capturer had no new frame (WouldBlock)
+    let mut damage_tracker = DamageTracker::new(frame_size.0, frame_size.1);
+    let mut last_frame: Option<Vec<u8>> = None;
+    let mut last_push = Instant::now();
     
     // Main capture loop
     while running.load(Ordering::SeqCst) {
         let start_time = Instant::now();
         
         // Capture frame
-        match capturer.frame() {
+        match source.frame() {
             Ok(frame) => {
                 if frame.is_empty() {
                     // Occasionally, we might get an empty frame, just wait a bit and try again
@@ -126,63 +391,145 @@ fn capture_frames(
                     continue;
                 }
                 
-                // Convert frame to BGR format expected by GStreamer video sink
-                // scrap gives us BGRA, we need to strip the alpha channel
-                let bgr_frame = convert_bgra_to_bgr(&frame, width, height);
+                // Renegotiate the stream if the display mode changed or the window was resized
+                let (width, height) = frame.size();
+                if *frame_size != (width, height) {
+                    info!(
+                        "{} resolution changed from {}x{} to {}x{}",
+                        name, frame_size.0, frame_size.1, width, height
+                    );
+                    for rtsp_mount in rtsp_mounts {
+                        rtsp_mount.set_resolution(width, height);
+                    }
+                    *frame_size = (width, height);
+                    damage_tracker = DamageTracker::new(width, height);
+                    last_frame = None;
+                }
+                
+                // Damage is only needed for skipping frames or adapting the rate
+                let changed = if settings.skip_unchanged_frames || settings.adaptive_frame_rate {
+                    // Prefer the regions reported by the backend over comparing pixels
+                    let damage = match frame.dirty_rects() {
+                        Some(rects) => Damage::from_rects(rects, width, height),
+                        None => damage_tracker.update(&frame),
+                    };
+                    pacer.update(damage.changed_fraction());
+                    !settings.skip_unchanged_frames || !damage.is_empty()
+                } else {
+                    true
+                };
+                
+                // Capture is healthy again
+                *backoff = INITIAL_BACKOFF;
+                if !available {
+                    available = true;
+                    set_available(rtsp_mounts, true);
+                }
                 
-                // Push the frame to the RTSP stream
-                if let Err(e) = rtsp_mount.push_frame(&bgr_frame) {
-                    error!("Failed to push frame to RTSP stream: {}", e);
-                    // Don't break immediately, try again
+                // Snapshots only copy frames while someone is asking for
+                // them; an unchanged frame is stored if a request is waiting
+                if let Some(latest_frame) = &outputs.latest_frame {
+                    if changed || last_frame.is_none() || latest_frame.needs_frame() {
+                        latest_frame.store(&frame, width, height);
+                    }
+                }
+                
+                if changed || last_frame.is_none() {
+                    if let Some(activity) = &outputs.activity {
+                        activity.update(&frame, width, height);
+                    }
+                    
+                    // Convert the BGRA frame to the format the media expects
+                    let converted = convert::convert(
+                        settings.pixel_format,
+                        &frame,
+                        width,
+                        height,
+                        &settings.colorimetry,
+                    );
+                    
+                    // Push the frame to the RTSP streams
+                    for rtsp_mount in rtsp_mounts {
+                        if let Err(e) = rtsp_mount.push_frame(&converted, pacer.frame_delay()) {
+                            error!("Failed to push frame to RTSP stream: {}", e);
+                            // Don't break immediately, try again
+                        }
+                    }
+                    last_push = Instant::now();
+                    last_frame = Some(converted);
+                } else if last_push.elapsed() >= keep_alive_delay {
+                    // Screen is static, re-send the previous frame to keep clients alive
+                    push_keep_alive(rtsp_mounts, last_frame.as_deref(), keep_alive_delay);
+                    last_push = Instant::now();
                 }
             }
             Err(error) => {
                 // Some capture errors can be transient
                 if error.kind() == std::io::ErrorKind::WouldBlock {
+                    if !source.available() {
+                        // Nothing to show, e.g. a minimised window: leave
+                        // the clients to the placeholder
+                        if available {
+                            available = false;
+                            set_available(rtsp_mounts, false);
+                        }
+                    } else if last_push.elapsed() >= keep_alive_delay {
+                        // DXGI reports an unchanged screen this way, so
+                        // static screens are kept alive from here
+                        push_keep_alive(rtsp_mounts, last_frame.as_deref(), keep_alive_delay);
+                        last_push = Instant::now();
+                    }
                     thread::sleep(Duration::from_millis(5));
                     continue;
                 }
-                error!("Error capturing frame from display {}: {}", display_index, error);
-                break;
+                return Err(error).context("Error capturing frame");
             }
         };
         
         // Sleep to maintain desired frame rate
+        let frame_delay = pacer.frame_delay();
         let elapsed = start_time.elapsed();
         if elapsed < frame_delay {
             thread::sleep(frame_delay - elapsed);
         }
     }
     
-    info!("Capture thread for display {} exiting", display_index);
     Ok(())
 }
 
-// Entry point for starting a capture thread
-pub fn start_capture_thread(
-    display_index: usize,
-    _display_meta: DisplayMetadata,  // Metadata kept for possible future use
-    rtsp_mount: RtspMount,
-    frame_rate: u32,
-    running: Arc<AtomicBool>,
-) -> Result<thread::JoinHandle<()>> {
-    capture_display_thread(display_index, rtsp_mount, frame_rate, running)
+fn set_available(rtsp_mounts: &[RtspMount], available: bool) {
+    for rtsp_mount in rtsp_mounts {
+        rtsp_mount.set_available(available);
+    }
 }
 
-// Convert BGRA format from scrap to BGR format needed by GStreamer
-fn convert_bgra_to_bgr(bgra: &[u8], width: u32, height: u32) -> Vec<u8> {
-    let pixel_count = (width * height) as usize;
-    let mut bgr = Vec::with_capacity(pixel_count * 3);
-    
-    for i in 0..pixel_count {
-        let bgra_offset = i * 4;
-        if bgra_offset + 2 < bgra.len() {
-            bgr.push(bgra[bgra_offset]);     // B
-            bgr.push(bgra[bgra_offset + 1]); // G
-            bgr.push(bgra[bgra_offset + 2]); // R
-            // Skip alpha channel
+// Re-send the previous frame, shown for `duration`, so clients of a static
+// screen don't time out
+fn push_keep_alive(rtsp_mounts: &[RtspMount], last_frame: Option<&[u8]>, duration: Duration) {
+    if let Some(converted) = last_frame {
+        for rtsp_mount in rtsp_mounts {
+            if let Err(e) = rtsp_mount.push_frame(converted, duration) {
+                error!("Failed to push keep-alive frame to RTSP stream: {}", e);
+            }
         }
     }
-    
-    bgr
-}
\ No newline at end of file
+}
+
+// Sleep for `wait`, returning early on shutdown
+fn wait_while_running(wait: Duration, running: &AtomicBool) {
+    let deadline = Instant::now() + wait;
+    while running.load(Ordering::SeqCst) && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+// Entry point for starting a capture thread; `name` is used in log messages
+pub fn start_capture_thread(
+    name: String,
+    target: CaptureTarget,
+    outputs: FrameOutputs,
+    settings: CaptureSettings,
+    running: Arc<AtomicBool>,
+) -> Result<thread::JoinHandle<()>> {
+    capture_thread(name, target, outputs, settings, running)
+}
@@ -0,0 +1,422 @@
+// Clips of "what just happened" for incident review
+//
+// Every display's encoded video of the last pre_roll seconds is kept in
+// memory as whole GOPs, so the buffer always starts at a keyframe. A trigger
+// (POST /clip/display<N>, or the `clip` command which sends that request to
+// the running instance) writes the buffered video followed by the next
+// post_roll seconds of live video to an MP4 file. A display has one clip
+// written at a time; triggers during its post-roll are refused, as that clip
+// already covers them. With ONVIF users configured, triggers need their
+// credentials like every other HTTP endpoint.
+
+use anyhow::{Context, Result};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use gstreamer_app::AppSrc;
+use log::{error, info, warn};
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tiny_http::{Method, Request, Response};
+
+use crate::config::{ClipConfig, OnvifUser};
+use crate::encoder::EncodedFrame;
+use crate::http::{self, HttpServer};
+
+/// Clip buffer of each display, by display slot
+pub type ClipBuffers = Arc<Mutex<HashMap<usize, Arc<ClipBuffer>>>>;
+
+const PATH_PREFIX: &str = "/clip/";
+
+/// Longest wait for the muxer to finish a clip file
+const FINISH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Longest wait for the running instance to answer a clip request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Whole GOPs, each starting with its keyframe and its start time
+struct GopRing<T> {
+    gops: VecDeque<(Duration, Vec<T>)>,
+    last_pts: Option<Duration>,
+}
+
+impl<T: Clone> GopRing<T> {
+    fn new() -> Self {
+        Self {
+            gops: VecDeque::new(),
+            last_pts: None,
+        }
+    }
+
+    // Add a frame presented at `pts`, forgetting GOPs not needed to cover
+    // the last `pre_roll`; frames before the first keyframe are dropped
+    fn push(&mut self, frame: &T, pts: Duration, keyframe: bool, pre_roll: Duration) {
+        // The encoder was restarted and its timestamps with it, so the
+        // buffered video can't be joined to what comes next
+        if self.last_pts.is_some_and(|last| pts < last) {
+            self.gops.clear();
+        }
+        self.last_pts = Some(pts);
+
+        if keyframe {
+            self.gops.push_back((pts, vec![frame.clone()]));
+        } else if let Some((_, gop)) = self.gops.back_mut() {
+            gop.push(frame.clone());
+        }
+
+        // Keep the newest GOP that starts at least pre_roll ago and all
+        // the ones after it
+        while self.gops.len() > 1 && self.gops[1].0 + pre_roll <= pts {
+            self.gops.pop_front();
+        }
+    }
+
+    fn frames(&self) -> Vec<T> {
+        self.gops.iter().flat_map(|(_, gop)| gop.iter().cloned()).collect()
+    }
+}
+
+struct BufferState {
+    gops: GopRing<EncodedFrame>,
+    // Clips being written that still want live frames
+    clips: Vec<mpsc::Sender<EncodedFrame>>,
+}
+
+pub struct ClipBuffer {
+    slot: usize,
+    pre_roll: Duration,
+    post_roll: Duration,
+    directory: PathBuf,
+    state: Mutex<BufferState>,
+    // Set while a clip of this display is being written
+    saving: Arc<AtomicBool>,
+}
+
+impl ClipBuffer {
+    pub fn new(slot: usize, config: &ClipConfig) -> Self {
+        Self {
+            slot,
+            pre_roll: Duration::from_secs(config.pre_roll as u64),
+            post_roll: Duration::from_secs(config.post_roll as u64),
+            directory: PathBuf::from(&config.directory),
+            state: Mutex::new(BufferState {
+                gops: GopRing::new(),
+                clips: Vec::new(),
+            }),
+            saving: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Add an encoded frame, forgetting GOPs that are no longer needed
+    pub fn push(&self, frame: &EncodedFrame) {
+        let mut state = self.state.lock().unwrap();
+
+        // Clips whose post-roll is over have dropped their receiver
+        state.clips.retain(|clip| clip.send(frame.clone()).is_ok());
+        state.gops.push(frame, frame.pts, frame.keyframe, self.pre_roll);
+    }
+
+    /// Start saving a clip of the buffered and upcoming video; returns the
+    /// file it is written to, which is complete after the post-roll, or None
+    /// while a clip of this display is still being written
+    pub fn save(&self) -> Result<Option<PathBuf>> {
+        if self.saving.swap(true, Ordering::SeqCst) {
+            return Ok(None);
+        }
+        let result = self.start_clip();
+        if result.is_err() {
+            self.saving.store(false, Ordering::SeqCst);
+        }
+        result.map(Some)
+    }
+
+    fn start_clip(&self) -> Result<PathBuf> {
+        let now = glib::DateTime::now_local().context("Failed to read the system clock")?;
+        let time = now.format("%Y-%m-%d_%H-%M-%S").context("Failed to format time")?;
+        fs::create_dir_all(&self.directory)
+            .with_context(|| format!("Failed to create {}", self.directory.display()))?;
+        let path = self.directory.join(format!(
+            "display{}-{}-{:03}.mp4",
+            self.slot,
+            time,
+            now.microsecond() / 1000
+        ));
+
+        let launch_str = format!(
+            "appsrc name=source format=time \
+             caps=\"video/x-h264,stream-format=byte-stream,alignment=au\" ! \
+             h264parse ! mp4mux ! filesink location=\"{}\"",
+            path.display()
+        );
+        let pipeline = gst::parse_launch(&launch_str)
+            .context("Failed to create clip pipeline")?
+            .downcast::<gst::Pipeline>()
+            .map_err(|_| anyhow::anyhow!("Clip pipeline is not a pipeline"))?;
+        let appsrc = pipeline
+            .by_name("source")
+            .and_then(|source| source.downcast::<AppSrc>().ok())
+            .context("No appsrc in clip pipeline")?;
+        pipeline
+            .set_state(gst::State::Playing)
+            .context("Failed to start clip pipeline")?;
+
+        // Taking the pre-roll and subscribing to live frames under one lock
+        // leaves no gap between them
+        let (live_tx, live_rx) = mpsc::channel();
+        let pre_roll: Vec<EncodedFrame> = {
+            let mut state = self.state.lock().unwrap();
+            state.clips.push(live_tx);
+            state.gops.frames()
+        };
+
+        info!("Saving clip of display {} to {}", self.slot, path.display());
+
+        let slot = self.slot;
+        let post_roll = self.post_roll;
+        let clip_path = path.clone();
+        let saving = self.saving.clone();
+        thread::spawn(move || {
+            match write_clip(&pipeline, &appsrc, pre_roll, &live_rx, post_roll) {
+                Ok(()) => info!("Saved clip of display {} to {}", slot, clip_path.display()),
+                Err(e) => error!("Failed to save clip {}: {:#}", clip_path.display(), e),
+            }
+            if let Err(e) = pipeline.set_state(gst::State::Null) {
+                error!("Failed to stop clip pipeline: {}", e);
+            }
+            saving.store(false, Ordering::SeqCst);
+        });
+
+        Ok(path)
+    }
+}
+
+// Write the pre-roll, then live frames until the post-roll is over, and
+// wait for the file to be finished
+fn write_clip(
+    pipeline: &gst::Pipeline,
+    appsrc: &AppSrc,
+    pre_roll: Vec<EncodedFrame>,
+    live: &mpsc::Receiver<EncodedFrame>,
+    post_roll: Duration,
+) -> Result<()> {
+    // Timestamps start at zero with the clip's first keyframe
+    let mut start: Option<Duration> = None;
+    let mut last_pts: Option<Duration> = None;
+    let mut push = |frame: &EncodedFrame| -> Result<bool> {
+        // A restarted encoder starts its timestamps over; end the clip
+        // with what came before
+        if last_pts.is_some_and(|last| frame.pts < last) {
+            return Ok(false);
+        }
+        last_pts = Some(frame.pts);
+
+        if start.is_none() && !frame.keyframe {
+            return Ok(true);
+        }
+        let start = *start.get_or_insert(frame.pts);
+
+        let mut buffer = frame.buffer.copy();
+        {
+            let buffer = buffer.get_mut().unwrap();
+            let time = gst::ClockTime::from_nseconds(frame.pts.saturating_sub(start).as_nanos() as u64);
+            buffer.set_pts(time);
+            buffer.set_dts(time);
+        }
+        appsrc.push_buffer(buffer).context("Clip pipeline stopped accepting frames")?;
+        Ok(true)
+    };
+
+    for frame in &pre_roll {
+        push(frame)?;
+    }
+
+    let deadline = Instant::now() + post_roll;
+    loop {
+        match live.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(frame) => {
+                if !push(&frame)? {
+                    warn!("Encoder restarted before the clip's post-roll was over");
+                    break;
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => break,
+            // The display went away; keep what we have
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                warn!("Stream ended before the clip's post-roll was over");
+                break;
+            }
+        }
+    }
+
+    appsrc.end_of_stream().context("Failed to end clip")?;
+    let bus = pipeline.bus().context("Clip pipeline has no bus")?;
+    let message = bus.timed_pop_filtered(
+        gst::ClockTime::from_nseconds(FINISH_TIMEOUT.as_nanos() as u64),
+        &[gst::MessageType::Eos, gst::MessageType::Error],
+    );
+    match message.as_ref().map(|message| message.view()) {
+        Some(gst::MessageView::Eos(_)) => Ok(()),
+        Some(gst::MessageView::Error(err)) => {
+            anyhow::bail!("{} ({:?})", err.error(), err.debug())
+        }
+        _ => anyhow::bail!("Timed out finishing the file"),
+    }
+}
+
+/// Save clips on POST /clip/display<N>; the response is the clip's path
+pub fn register(http_server: &HttpServer, buffers: ClipBuffers) {
+    http_server.route(PATH_PREFIX, move |request| handle(request, &buffers));
+}
+
+fn handle(request: Request, buffers: &ClipBuffers) {
+    if *request.method() != Method::Post {
+        let _ = request.respond(Response::empty(405));
+        return;
+    }
+
+    let buffer = request
+        .url()
+        .split('?')
+        .next()
+        .and_then(|path| path.strip_prefix(PATH_PREFIX))
+        .and_then(|name| name.strip_prefix("display"))
+        .and_then(|slot| slot.parse::<usize>().ok())
+        .and_then(|slot| buffers.lock().unwrap().get(&slot).cloned());
+    let buffer = match buffer {
+        Some(buffer) => buffer,
+        None => {
+            let _ = request.respond(Response::empty(404));
+            return;
+        }
+    };
+
+    let response = match buffer.save() {
+        Ok(Some(path)) => Response::from_string(path.display().to_string()).with_status_code(202),
+        Ok(None) => Response::from_string(format!("A clip of display {} is already being saved", buffer.slot))
+            .with_status_code(409),
+        Err(e) => {
+            error!("Failed to save clip of display {}: {:#}", buffer.slot, e);
+            Response::from_string(format!("{:#}", e)).with_status_code(500)
+        }
+    };
+    let _ = request.respond(response.with_header(http::header("Content-Type", "text/plain")));
+}
+
+/// Ask the instance running on this machine to save a clip of a display,
+/// as `user` if the HTTP endpoints need authentication; returns the path of
+/// the clip
+pub fn request(http_port: u16, slot: usize, user: Option<&OnvifUser>) -> Result<String> {
+    let url = format!("http://127.0.0.1:{}{}display{}", http_port, PATH_PREFIX, slot);
+    let authorization = user.map(http::basic_authorization);
+    let headers: Vec<(&str, &str)> = authorization
+        .iter()
+        .map(|value| ("Authorization", value.as_str()))
+        .collect();
+    let (status, body) = http::post_with_headers(&url, "text/plain", &headers, &[], REQUEST_TIMEOUT)
+        .context("Failed to reach the running streamer")?;
+
+    match status {
+        202 => Ok(body),
+        404 => anyhow::bail!("Display {} is not being buffered (are clips enabled?)", slot),
+        409 => anyhow::bail!("{}", body),
+        _ => anyhow::bail!("Clip request failed: {} {}", status, body),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PRE_ROLL: Duration = Duration::from_secs(10);
+
+    fn ms(value: u64) -> Duration {
+        Duration::from_millis(value)
+    }
+
+    // Frames are numbered by their time in milliseconds
+    fn push(ring: &mut GopRing<u64>, time: u64, keyframe: bool) {
+        ring.push(&time, ms(time), keyframe, PRE_ROLL);
+    }
+
+    #[test]
+    fn frames_before_the_first_keyframe_are_dropped() {
+        let mut ring = GopRing::new();
+        push(&mut ring, 0, false);
+        push(&mut ring, 100, false);
+        assert!(ring.frames().is_empty());
+
+        push(&mut ring, 200, true);
+        push(&mut ring, 300, false);
+        assert_eq!(ring.frames(), [200, 300]);
+    }
+
+    #[test]
+    fn buffer_keeps_whole_gops_covering_the_pre_roll() {
+        let mut ring = GopRing::new();
+        // A keyframe every 4 seconds, a frame every second
+        for time in (0..=30_000).step_by(1000) {
+            push(&mut ring, time, time % 4000 == 0);
+        }
+
+        // 30s - 10s = 20s, which a GOP starts at exactly
+        let frames = ring.frames();
+        assert_eq!(frames.first(), Some(&20_000));
+        assert_eq!(frames.last(), Some(&30_000));
+        assert_eq!(frames.len(), 11);
+
+        // One second later the GOP starting at 20s is still needed
+        push(&mut ring, 31_000, false);
+        assert_eq!(ring.frames().first(), Some(&20_000));
+    }
+
+    #[test]
+    fn buffer_restarts_when_timestamps_go_back() {
+        let mut ring = GopRing::new();
+        for time in (0..=5000).step_by(1000) {
+            push(&mut ring, time, time == 0);
+        }
+
+        // The restarted encoder's first frame is a keyframe at 0 again
+        push(&mut ring, 0, true);
+        push(&mut ring, 1000, false);
+        assert_eq!(ring.frames(), [0, 1000]);
+
+        // Until the next keyframe there is nothing to start a clip with
+        push(&mut ring, 500, false);
+        assert!(ring.frames().is_empty());
+    }
+
+    #[test]
+    fn buffer_starts_at_a_keyframe_before_the_pre_roll() {
+        let mut ring = GopRing::new();
+        push(&mut ring, 0, true);
+        for time in (1000..=14_000).step_by(1000) {
+            push(&mut ring, time, time == 7000);
+        }
+
+        // 10 seconds back is 4s, inside the GOP that started at 0
+        assert_eq!(ring.frames().first(), Some(&0));
+
+        push(&mut ring, 17_000, false);
+        assert_eq!(ring.frames().first(), Some(&7000));
+    }
+
+    #[test]
+    fn long_gops_are_kept_whole() {
+        let mut ring = GopRing::new();
+        push(&mut ring, 0, true);
+        for time in (1000..=60_000).step_by(1000) {
+            push(&mut ring, time, false);
+        }
+
+        // Nothing newer to start from, so the whole minute stays
+        let frames = ring.frames();
+        assert_eq!(frames.len(), 61);
+        assert_eq!(frames.first(), Some(&0));
+    }
+}
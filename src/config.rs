@@ -1,8 +1,11 @@
 use anyhow::{Context, Result};
+use log::warn;
 use serde::{Deserialize, Serialize};
 use std::fs;
 use std::io;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+
+use crate::convert::{ColorMatrix, Colorimetry, PixelFormat};
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Config {
@@ -11,6 +14,14 @@ pub struct Config {
     
     /// Port for the RTSP server
     pub rtsp_port: u16,
+
+    /// Serve streams with the built-in RTSP server (disable to only publish)
+    #[serde(default = "default_rtsp_enabled")]
+    pub rtsp_enabled: bool,
+
+    /// Port for the HTTP server (ONVIF services, snapshots)
+    #[serde(default = "default_http_port")]
+    pub http_port: u16,
     
     /// Frames per second to capture and stream
     pub frame_rate: u32,
@@ -23,6 +34,625 @@ pub struct Config {
     
     /// List of display indices to capture (empty = all displays)
     pub displays: Vec<usize>,
+
+    /// Skip encoding frames that are identical to the previous frame
+    #[serde(default = "default_skip_unchanged_frames")]
+    pub skip_unchanged_frames: bool,
+
+    /// Frames per second re-sent while the screen is unchanged (keep-alive rate)
+    #[serde(default = "default_min_frame_rate")]
+    pub min_frame_rate: u32,
+
+    /// Vary the frame rate between min_frame_rate and frame_rate with screen activity
+    #[serde(default)]
+    pub adaptive_frame_rate: bool,
+
+    /// Display capture implementation: "scrap", "x11" for XShm/XDamage on Linux,
+    /// or "ximagesrc"/"pipewiresrc" to capture inside the GStreamer pipeline
+    #[serde(default = "default_capture_backend")]
+    pub capture_backend: String,
+
+    /// Format of the frames pushed into the streams: "bgr", or "i420"/"nv12"
+    /// to convert to YUV in the capture thread instead of in the pipeline
+    #[serde(default = "default_pixel_format")]
+    pub pixel_format: String,
+
+    /// PipeWire node of each display for the "pipewiresrc" backend, by display
+    /// index; displays without one are shared through xdg-desktop-portal
+    #[serde(default)]
+    pub pipewire_nodes: Vec<String>,
+
+    /// X display to capture on Linux, e.g. ":0" (empty = $DISPLAY)
+    #[serde(default)]
+    pub x11_display: String,
+
+    /// Seconds between checks for connected/disconnected displays (0 = disabled)
+    #[serde(default = "default_hotplug_interval")]
+    pub hotplug_interval: u32,
+
+    /// Frames sent to clients while a display can't be captured
+    #[serde(default)]
+    pub placeholder: PlaceholderConfig,
+
+    /// Single stream showing several displays side by side
+    #[serde(default)]
+    pub mosaic: MosaicConfig,
+
+    /// Single stream of the whole virtual desktop spanning all displays
+    #[serde(default)]
+    pub desktop: DesktopConfig,
+
+    /// ONVIF integration so NVRs can find and add the streams by themselves
+    #[serde(default)]
+    pub onvif: OnvifConfig,
+
+    /// JPEG snapshots of each display served over HTTP
+    #[serde(default)]
+    pub snapshot: SnapshotConfig,
+
+    /// MJPEG streams of each display served over HTTP
+    #[serde(default)]
+    pub mjpeg: MjpegConfig,
+
+    /// HLS streams of each display served over HTTP
+    #[serde(default)]
+    pub hls: HlsConfig,
+
+    /// WebRTC (WHEP) endpoint of each display served over HTTP
+    #[serde(default)]
+    pub whep: WhepConfig,
+
+    /// Local recording of displays to segmented files
+    #[serde(default)]
+    pub record: RecordConfig,
+
+    /// Clips of recent video saved on request
+    #[serde(default)]
+    pub clips: ClipConfig,
+
+    /// Per-stream settings
+    #[serde(default)]
+    pub streams: Vec<StreamConfig>,
+
+    /// Application windows to capture, each served at /window/<name> (Linux/X11 only)
+    #[serde(default)]
+    pub windows: Vec<WindowConfig>,
+}
+
+impl Config {
+    /// The configured pixel format, BGR if it isn't recognised
+    pub fn pixel_format(&self) -> PixelFormat {
+        PixelFormat::from_name(&self.pixel_format).unwrap_or_else(|| {
+            warn!("Unknown pixel format '{}', using BGR", self.pixel_format);
+            PixelFormat::Bgr
+        })
+    }
+
+    /// Settings for the stream of display `display`: the entry for that
+    /// display if there is one, else the entry for all streams, else defaults
+    pub fn stream_config(&self, display: usize) -> StreamConfig {
+        self.streams
+            .iter()
+            .find(|s| s.display == Some(display))
+            .or_else(|| self.default_stream_config())
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Settings for the stream of the window named `name`, looked up like
+    /// those of a display
+    pub fn window_stream_config(&self, name: &str) -> StreamConfig {
+        self.streams
+            .iter()
+            .find(|s| s.window.as_deref() == Some(name))
+            .or_else(|| self.default_stream_config())
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    // The entry without a display or window, which applies to all streams
+    fn default_stream_config(&self) -> Option<&StreamConfig> {
+        self.streams.iter().find(|s| s.display.is_none() && s.window.is_none())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct MosaicConfig {
+    /// Whether to serve the /mosaic stream
+    pub enabled: bool,
+
+    /// Display indices to include (empty = all displays found at startup)
+    pub displays: Vec<usize>,
+
+    /// "grid" for equal tiles, "desktop" for the displays' real arrangement
+    pub layout: String,
+
+    /// Output width in pixels
+    pub width: u32,
+
+    /// Output height in pixels
+    pub height: u32,
+}
+
+impl Default for MosaicConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            displays: Vec::new(),
+            layout: "grid".to_string(),
+            width: 1920,
+            height: 1080,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct DesktopConfig {
+    /// Whether to serve the /desktop stream
+    pub enabled: bool,
+
+    /// Colour of the areas not covered by any display, as #RRGGBB
+    pub background: String,
+}
+
+impl Default for DesktopConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            background: "#000000".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct OnvifConfig {
+    /// Answer WS-Discovery probes and announce the streamer on the network
+    pub discovery: bool,
+
+    /// Serve the ONVIF device and media services on the HTTP port
+    pub enabled: bool,
+
+    /// Device name shown by NVRs (empty = hostname)
+    pub name: String,
+
+    /// Free-form location, e.g. "Control room" (empty = none)
+    pub location: String,
+
+    /// Accounts accepted in WS-UsernameToken headers, and as HTTP Basic
+    /// credentials by the other HTTP endpoints (empty = no authentication)
+    pub users: Vec<OnvifUser>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct OnvifUser {
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct SnapshotConfig {
+    /// Serve /snapshot/display<N>.jpg on the HTTP port
+    pub enabled: bool,
+
+    /// JPEG quality (1-100) used when the request doesn't ask for one
+    pub quality: u8,
+}
+
+impl Default for SnapshotConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            quality: 80,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct MjpegConfig {
+    /// Serve /mjpeg/display<N> on the HTTP port
+    pub enabled: bool,
+
+    /// Most pictures per second sent to each client
+    pub frame_rate: u32,
+
+    /// JPEG quality (1-100)
+    pub quality: u8,
+
+    /// Widest picture sent, larger displays are scaled down (0 = native size)
+    pub max_width: u32,
+}
+
+impl Default for MjpegConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            frame_rate: 5,
+            quality: 70,
+            max_width: 0,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct HlsConfig {
+    /// Serve /hls/display<N>/index.m3u8 on the HTTP port
+    pub enabled: bool,
+
+    /// Target segment length in seconds; segments are cut at keyframes
+    pub segment_duration: u32,
+
+    /// Number of segments listed in the playlist
+    pub playlist_length: u32,
+}
+
+impl Default for HlsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            segment_duration: 2,
+            playlist_length: 5,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct WhepConfig {
+    /// Accept WHEP offers at /whep/display<N> on the HTTP port
+    pub enabled: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct RecordConfig {
+    /// Record displays to files in `directory`
+    pub enabled: bool,
+
+    /// Display indices to record (empty = all streamed displays)
+    pub displays: Vec<usize>,
+
+    /// Root directory; files go to <directory>/display<N>/<YYYY-MM-DD>/
+    pub directory: String,
+
+    /// Container: "mp4" (fragmented, playable after a crash) or "mkv"
+    pub format: String,
+
+    /// Length of each file in seconds
+    pub segment_duration: u32,
+
+    /// Oldest files are deleted once all recordings take more than this
+    /// many megabytes (0 = no limit)
+    pub max_size_mb: u64,
+}
+
+impl Default for RecordConfig {
+    fn default() -> Self {
+        let mut directory = dirs::data_local_dir().unwrap_or_else(|| PathBuf::from("."));
+        directory.push("display_rtsp_streamer");
+        directory.push("recordings");
+
+        Self {
+            enabled: false,
+            displays: Vec::new(),
+            directory: directory.to_string_lossy().into_owned(),
+            format: "mp4".to_string(),
+            segment_duration: 300,
+            max_size_mb: 10240,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct ClipConfig {
+    /// Keep the last `pre_roll` seconds of every display in memory and save
+    /// clips when POST /clip/display<N> is requested
+    pub enabled: bool,
+
+    /// Seconds of video before the trigger included in a clip
+    pub pre_roll: u32,
+
+    /// Seconds of video after the trigger included in a clip
+    pub post_roll: u32,
+
+    /// Directory clips are saved to
+    pub directory: String,
+}
+
+impl Default for ClipConfig {
+    fn default() -> Self {
+        let mut directory = dirs::data_local_dir().unwrap_or_else(|| PathBuf::from("."));
+        directory.push("display_rtsp_streamer");
+        directory.push("clips");
+
+        Self {
+            enabled: false,
+            pre_roll: 60,
+            post_roll: 60,
+            directory: directory.to_string_lossy().into_owned(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct StreamConfig {
+    /// Display index (the N in /displayN) these settings apply to (none = all displays)
+    pub display: Option<usize>,
+
+    /// Window name (the <name> in /window/<name>) these settings apply to
+    /// instead of a display. Publishing and activity detection are only
+    /// available for displays.
+    pub window: Option<String>,
+
+    /// Clockwise rotation in degrees: 0, 90, 180 or 270
+    pub rotate: u32,
+
+    /// Mirror the picture left to right (applied before rotating)
+    pub flip_horizontal: bool,
+
+    /// Mirror the picture top to bottom (applied before rotating)
+    pub flip_vertical: bool,
+
+    /// YUV matrix of the encoded video: "bt601" or "bt709"
+    pub color_matrix: String,
+
+    /// Encode full range (0-255) instead of limited range (16-235) video
+    pub full_range: bool,
+
+    /// Text burned into the stream
+    pub overlay: OverlayConfig,
+
+    /// Image composited onto the stream
+    pub watermark: WatermarkConfig,
+
+    /// Servers to push the stream to: rtsp://, rtmp:// or srt:// URLs, in
+    /// which {display} and {hostname} are replaced
+    pub publish: Vec<String>,
+
+    /// Detection of screen activity
+    pub activity: ActivityConfig,
+}
+
+impl Default for StreamConfig {
+    fn default() -> Self {
+        Self {
+            display: None,
+            window: None,
+            rotate: 0,
+            flip_horizontal: false,
+            flip_vertical: false,
+            color_matrix: "bt709".to_string(),
+            full_range: false,
+            overlay: OverlayConfig::default(),
+            watermark: WatermarkConfig::default(),
+            publish: Vec::new(),
+            activity: ActivityConfig::default(),
+        }
+    }
+}
+
+impl StreamConfig {
+    /// The configured colorimetry, BT.709 if the matrix isn't recognised
+    pub fn colorimetry(&self) -> Colorimetry {
+        let matrix = match self.color_matrix.to_ascii_lowercase().as_str() {
+            "bt601" => ColorMatrix::Bt601,
+            "bt709" => ColorMatrix::Bt709,
+            other => {
+                warn!("Unknown color matrix '{}', using bt709", other);
+                ColorMatrix::Bt709
+            }
+        };
+
+        Colorimetry {
+            matrix,
+            full_range: self.full_range,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct ActivityConfig {
+    /// Report activity on the stream's captured frames
+    pub enabled: bool,
+
+    /// Fraction of a zone (0.0-1.0) that must change for an event
+    pub min_area: f32,
+
+    /// How small a change counts (1-100, higher = more sensitive)
+    pub sensitivity: u32,
+
+    /// Seconds after an event before the same zone reports again
+    pub cooldown: u32,
+
+    /// Command run for each event, with the event in ACTIVITY_* variables
+    pub command: String,
+
+    /// http:// URL each event is POSTed to as JSON
+    pub webhook: String,
+
+    /// Regions watched separately (none = the whole frame)
+    pub zones: Vec<ActivityZone>,
+}
+
+impl Default for ActivityConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            min_area: 0.01,
+            sensitivity: 80,
+            cooldown: 30,
+            command: String::new(),
+            webhook: String::new(),
+            zones: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct ActivityZone {
+    /// Name reported in events
+    pub name: String,
+
+    /// Left edge, as a fraction of the frame width
+    pub x: f32,
+
+    /// Top edge, as a fraction of the frame height
+    pub y: f32,
+
+    /// Width, as a fraction of the frame width
+    pub width: f32,
+
+    /// Height, as a fraction of the frame height
+    pub height: f32,
+
+    /// Overrides the stream's min_area for this zone
+    pub min_area: Option<f32>,
+
+    /// Overrides the stream's sensitivity for this zone
+    pub sensitivity: Option<u32>,
+}
+
+impl Default for ActivityZone {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            x: 0.0,
+            y: 0.0,
+            width: 1.0,
+            height: 1.0,
+            min_area: None,
+            sensitivity: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct OverlayConfig {
+    /// Whether to burn the text into the stream
+    pub enabled: bool,
+
+    /// Text to show ({hostname}, {display} and {time} are replaced)
+    pub format: String,
+
+    /// strftime-style format used for {time}
+    pub time_format: String,
+
+    /// Where to put the text: top-left, top, top-right, left, center, right,
+    /// bottom-left, bottom or bottom-right
+    pub position: String,
+
+    /// Font size of the text
+    pub font_size: u32,
+}
+
+impl Default for OverlayConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            format: "{hostname} - {display} - {time}".to_string(),
+            time_format: "%Y-%m-%d %H:%M:%S".to_string(),
+            position: "top-left".to_string(),
+            font_size: 18,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct WatermarkConfig {
+    /// PNG image to composite onto the stream (empty = no watermark)
+    pub image: String,
+
+    /// Where to put the image (same names as the text overlay position)
+    pub position: String,
+
+    /// Distance from the frame edges in pixels
+    pub margin: u32,
+
+    /// Opacity of the image (0.0 - 1.0)
+    pub opacity: f64,
+
+    /// Scale factor applied to the image size
+    pub scale: f64,
+}
+
+impl Default for WatermarkConfig {
+    fn default() -> Self {
+        Self {
+            image: String::new(),
+            position: "bottom-right".to_string(),
+            margin: 16,
+            opacity: 0.8,
+            scale: 1.0,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct WindowConfig {
+    /// Stream name, the <name> in /window/<name>
+    pub name: String,
+
+    /// Match windows whose title contains this text (empty = any)
+    pub title: String,
+
+    /// Match windows whose WM_CLASS instance or class name equals this (empty = any)
+    pub class: String,
+
+    /// Match windows owned by this process ID
+    pub pid: Option<u32>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct PlaceholderConfig {
+    /// Send placeholder frames while a capture is unavailable
+    pub enabled: bool,
+
+    /// Seconds the capture must be failing (or e.g. its window minimised)
+    /// before the placeholder is shown
+    pub timeout: u32,
+
+    /// Frames per second of the placeholder
+    pub frame_rate: u32,
+
+    /// Background colour as #RRGGBB
+    pub color: String,
+
+    /// Text shown in the middle of the frame ({display} = display name)
+    pub text: String,
+
+    /// Font size of the text
+    pub font_size: u32,
+
+    /// PNG image shown instead of the colour and text (empty = none)
+    pub image: String,
+}
+
+impl Default for PlaceholderConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            timeout: 5,
+            frame_rate: 1,
+            color: "#000000".to_string(),
+            text: "{display} unavailable".to_string(),
+            font_size: 36,
+            image: String::new(),
+        }
+    }
 }
 
 impl Default for Config {
@@ -30,14 +660,64 @@ impl Default for Config {
         Self {
             server_address: local_ip_address(),
             rtsp_port: 8554,
+            rtsp_enabled: default_rtsp_enabled(),
+            http_port: default_http_port(),
             frame_rate: 15,
             quality: 7,
             capture_cursor: true,
             displays: Vec::new(),
+            skip_unchanged_frames: default_skip_unchanged_frames(),
+            min_frame_rate: default_min_frame_rate(),
+            adaptive_frame_rate: false,
+            capture_backend: default_capture_backend(),
+            pixel_format: default_pixel_format(),
+            pipewire_nodes: Vec::new(),
+            x11_display: String::new(),
+            hotplug_interval: default_hotplug_interval(),
+            placeholder: PlaceholderConfig::default(),
+            mosaic: MosaicConfig::default(),
+            desktop: DesktopConfig::default(),
+            onvif: OnvifConfig::default(),
+            snapshot: SnapshotConfig::default(),
+            mjpeg: MjpegConfig::default(),
+            hls: HlsConfig::default(),
+            whep: WhepConfig::default(),
+            record: RecordConfig::default(),
+            clips: ClipConfig::default(),
+            streams: Vec::new(),
+            windows: Vec::new(),
         }
     }
 }
 
+fn default_rtsp_enabled() -> bool {
+    true
+}
+
+fn default_http_port() -> u16 {
+    8080
+}
+
+fn default_skip_unchanged_frames() -> bool {
+    true
+}
+
+fn default_min_frame_rate() -> u32 {
+    1
+}
+
+fn default_capture_backend() -> String {
+    "scrap".to_string()
+}
+
+fn default_pixel_format() -> String {
+    "bgr".to_string()
+}
+
+fn default_hotplug_interval() -> u32 {
+    5
+}
+
 fn local_ip_address() -> String {
     match local_ip_address::local_ip() {
         Ok(ip) => ip.to_string(),
@@ -74,4 +754,45 @@ pub fn save_config(path: &Path, config: &Config) -> Result<()> {
     fs::write(path, contents).context("Failed to write config file")?;
     
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stream(display: Option<usize>, window: Option<&str>, rotate: u32) -> StreamConfig {
+        StreamConfig {
+            display,
+            window: window.map(str::to_string),
+            rotate,
+            ..StreamConfig::default()
+        }
+    }
+
+    fn config(streams: Vec<StreamConfig>) -> Config {
+        Config {
+            streams,
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn streams_use_their_own_entry_or_the_default_one() {
+        let config = config(vec![
+            stream(None, Some("browser"), 90),
+            stream(Some(1), None, 270),
+            stream(None, None, 180),
+        ]);
+        assert_eq!(config.stream_config(1).rotate, 270);
+        assert_eq!(config.stream_config(0).rotate, 180);
+        assert_eq!(config.window_stream_config("browser").rotate, 90);
+        assert_eq!(config.window_stream_config("terminal").rotate, 180);
+    }
+
+    #[test]
+    fn window_entries_are_not_the_default() {
+        let config = config(vec![stream(None, Some("browser"), 90)]);
+        assert_eq!(config.stream_config(0).rotate, 0);
+        assert_eq!(config.window_stream_config("terminal").rotate, 0);
+    }
+}
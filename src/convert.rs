@@ -0,0 +1,948 @@
+// Pixel format conversion of captured BGRA frames
+//
+// Captured frames are converted to the format pushed into the media: packed
+// BGR, or planar I420 / semi-planar NV12 so the pipeline's videoconvert has
+// nothing left to do. BGR packing, luma and the 2x2 chroma averaging have
+// SSSE3, AVX2 and NEON versions picked at runtime; the scalar code is the
+// fallback, handles the ends of rows, and is the reference the SIMD versions
+// must match bit for bit.
+//
+// The YUV matrix and range are per stream (see `Colorimetry`) and are stated
+// in the caps, so conversions in the pipeline and the encoder's VUI agree
+// with what was done here.
+
+use log::info;
+use once_cell::sync::Lazy;
+
+/// Format of the frames pushed by the capture threads
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    Bgr,
+    I420,
+    Nv12,
+}
+
+impl PixelFormat {
+    /// Parse a configuration value, None if unknown
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "bgr" => Some(PixelFormat::Bgr),
+            "i420" => Some(PixelFormat::I420),
+            "nv12" => Some(PixelFormat::Nv12),
+            _ => None,
+        }
+    }
+
+    /// Format name in GStreamer caps
+    pub fn caps_name(&self) -> &'static str {
+        match self {
+            PixelFormat::Bgr => "BGR",
+            PixelFormat::I420 => "I420",
+            PixelFormat::Nv12 => "NV12",
+        }
+    }
+}
+
+// Fixed-point (x256) RGB to YUV coefficients
+#[derive(Debug, Clone, Copy)]
+struct YuvCoefficients {
+    /// R, G, B weights of Y; must sum to at most 256
+    y: [u16; 3],
+    /// R, G, B weights of U (Cb)
+    u: [i32; 3],
+    /// R, G, B weights of V (Cr)
+    v: [i32; 3],
+    /// Added to Y: 16 for limited range, 0 for full range
+    y_offset: u8,
+}
+
+/// RGB to YUV matrix
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorMatrix {
+    Bt601,
+    Bt709,
+}
+
+/// How YUV frames of a stream are encoded, and signalled to players
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Colorimetry {
+    pub matrix: ColorMatrix,
+    /// 0-255 luma instead of 16-235
+    pub full_range: bool,
+}
+
+impl Default for Colorimetry {
+    fn default() -> Self {
+        Self {
+            matrix: ColorMatrix::Bt709,
+            full_range: false,
+        }
+    }
+}
+
+impl Colorimetry {
+    /// Colorimetry in GStreamer caps, as range:matrix:transfer:primaries.
+    /// x264enc copies it into the stream's VUI.
+    pub fn caps_name(&self) -> &'static str {
+        match (self.matrix, self.full_range) {
+            (ColorMatrix::Bt601, false) => "bt601",
+            (ColorMatrix::Bt601, true) => "1:4:5:4",
+            (ColorMatrix::Bt709, false) => "bt709",
+            (ColorMatrix::Bt709, true) => "1:3:5:1",
+        }
+    }
+
+    fn coefficients(&self) -> &'static YuvCoefficients {
+        match (self.matrix, self.full_range) {
+            (ColorMatrix::Bt601, false) => &BT601_LIMITED,
+            (ColorMatrix::Bt601, true) => &BT601_FULL,
+            (ColorMatrix::Bt709, false) => &BT709_LIMITED,
+            (ColorMatrix::Bt709, true) => &BT709_FULL,
+        }
+    }
+}
+
+const BT601_LIMITED: YuvCoefficients = YuvCoefficients {
+    y: [66, 129, 25],
+    u: [-38, -74, 112],
+    v: [112, -94, -18],
+    y_offset: 16,
+};
+
+const BT601_FULL: YuvCoefficients = YuvCoefficients {
+    y: [77, 150, 29],
+    u: [-43, -85, 128],
+    v: [128, -107, -21],
+    y_offset: 0,
+};
+
+const BT709_LIMITED: YuvCoefficients = YuvCoefficients {
+    y: [47, 157, 16],
+    u: [-26, -86, 112],
+    v: [112, -102, -10],
+    y_offset: 16,
+};
+
+const BT709_FULL: YuvCoefficients = YuvCoefficients {
+    y: [54, 183, 19],
+    u: [-29, -99, 128],
+    v: [128, -116, -12],
+    y_offset: 0,
+};
+
+/// Convert a BGRA frame to `format`. YUV output uses GStreamer's default
+/// plane layout (rows padded to 4 bytes); BGR output is tightly packed.
+pub fn convert(
+    format: PixelFormat,
+    bgra: &[u8],
+    width: u32,
+    height: u32,
+    colorimetry: &Colorimetry,
+) -> Vec<u8> {
+    let coefficients = colorimetry.coefficients();
+    match format {
+        PixelFormat::Bgr => bgra_to_bgr(bgra, width, height),
+        PixelFormat::I420 => bgra_to_yuv(bgra, width, height, coefficients, false),
+        PixelFormat::Nv12 => bgra_to_yuv(bgra, width, height, coefficients, true),
+    }
+}
+
+// Instruction set used for the per-pixel loops, detected once
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Isa {
+    Scalar,
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    Ssse3,
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    Avx2,
+    #[cfg(target_arch = "aarch64")]
+    Neon,
+}
+
+static ISA: Lazy<Isa> = Lazy::new(|| {
+    let isa = detect_isa();
+    info!("Using {:?} pixel format conversion", isa);
+    isa
+});
+
+fn detect_isa() -> Isa {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        if is_x86_feature_detected!("avx2") {
+            return Isa::Avx2;
+        }
+        if is_x86_feature_detected!("ssse3") {
+            return Isa::Ssse3;
+        }
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        if std::arch::is_aarch64_feature_detected!("neon") {
+            return Isa::Neon;
+        }
+    }
+    Isa::Scalar
+}
+
+// Rows may be padded, so derive the stride from the buffer
+fn source_stride(bgra: &[u8], width: u32, height: u32) -> usize {
+    if height == 0 {
+        return width as usize * 4;
+    }
+    (bgra.len() / height as usize).max(width as usize * 4)
+}
+
+fn bgra_to_bgr(bgra: &[u8], width: u32, height: u32) -> Vec<u8> {
+    let (width, height) = (width as usize, height as usize);
+    let src_stride = source_stride(bgra, width as u32, height as u32);
+    let row_bytes = width * 3;
+    let mut bgr = vec![0u8; row_bytes * height];
+
+    for y in 0..height {
+        let src = &bgra[y * src_stride..];
+        if src.len() < width * 4 {
+            break;
+        }
+        // The SIMD loops may write a few bytes past the end of the row;
+        // they're overwritten when the next row is converted
+        let dst = &mut bgr[y * row_bytes..];
+        let done = bgr_row_simd(*ISA, src, dst, width);
+        bgr_row_scalar(&src[done * 4..], &mut dst[done * 3..], width - done);
+    }
+
+    bgr
+}
+
+/// Scalar reference for BGR packing
+fn bgr_row_scalar(src: &[u8], dst: &mut [u8], width: usize) {
+    for (pixel, out) in src.chunks_exact(4).zip(dst.chunks_exact_mut(3)).take(width) {
+        out.copy_from_slice(&pixel[..3]);
+    }
+}
+
+// Pack as many pixels as the SIMD path handles, returning how many
+fn bgr_row_simd(isa: Isa, src: &[u8], dst: &mut [u8], width: usize) -> usize {
+    match isa {
+        Isa::Scalar => 0,
+        // SAFETY: the instruction set was detected at runtime
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        Isa::Ssse3 => unsafe { x86::bgr_row_ssse3(src, dst, width) },
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        Isa::Avx2 => unsafe { x86::bgr_row_avx2(src, dst, width) },
+        #[cfg(target_arch = "aarch64")]
+        Isa::Neon => unsafe { neon::bgr_row_neon(src, dst, width) },
+    }
+}
+
+// GStreamer's default row stride: rounded up to 4 bytes
+fn padded(bytes: usize) -> usize {
+    (bytes + 3) & !3
+}
+
+fn bgra_to_yuv(
+    bgra: &[u8],
+    width: u32,
+    height: u32,
+    coefficients: &YuvCoefficients,
+    interleaved_chroma: bool,
+) -> Vec<u8> {
+    let (width, height) = (width as usize, height as usize);
+    let src_stride = source_stride(bgra, width as u32, height as u32);
+    let (chroma_width, chroma_height) = (width.div_ceil(2), height.div_ceil(2));
+
+    let y_stride = padded(width);
+    // GStreamer starts the chroma planes after an even number of luma rows
+    let y_size = y_stride * height.next_multiple_of(2);
+    let chroma_stride = if interleaved_chroma {
+        padded(chroma_width * 2)
+    } else {
+        padded(chroma_width)
+    };
+    let chroma_size = chroma_stride * chroma_height;
+    let total = if interleaved_chroma {
+        y_size + chroma_size
+    } else {
+        y_size + chroma_size * 2
+    };
+
+    let mut yuv = vec![0u8; total];
+    if bgra.len() < src_stride * height {
+        return yuv;
+    }
+
+    let (y_plane, chroma) = yuv.split_at_mut(y_size);
+    for y in 0..height {
+        let src = &bgra[y * src_stride..];
+        let dst = &mut y_plane[y * y_stride..(y + 1) * y_stride];
+        let done = luma_row_simd(*ISA, src, dst, width, coefficients);
+        luma_row_scalar(&src[done * 4..], &mut dst[done..], width - done, coefficients);
+    }
+
+    // With interleaved chroma the first "plane" is the whole UV plane
+    let (u_plane, v_plane) = chroma.split_at_mut(chroma_size);
+    for cy in 0..chroma_height {
+        // Odd heights repeat the last row
+        let top = &bgra[cy * 2 * src_stride..];
+        let bottom = &bgra[(cy * 2 + 1).min(height - 1) * src_stride..];
+
+        let row = cy * chroma_stride..(cy + 1) * chroma_stride;
+        let mut out = if interleaved_chroma {
+            ChromaRow::Interleaved(&mut u_plane[row])
+        } else {
+            ChromaRow::Planar(&mut u_plane[row.clone()], &mut v_plane[row])
+        };
+        let done = chroma_row_simd(*ISA, top, bottom, width, &mut out, coefficients);
+        chroma_row_scalar(top, bottom, width, done, &mut out, coefficients);
+    }
+
+    yuv
+}
+
+// One row of the chroma plane(s)
+enum ChromaRow<'a> {
+    /// U and V rows of I420
+    Planar(&'a mut [u8], &'a mut [u8]),
+    /// UV row of NV12
+    Interleaved(&'a mut [u8]),
+}
+
+impl ChromaRow<'_> {
+    fn set(&mut self, cx: usize, u: u8, v: u8) {
+        match self {
+            ChromaRow::Planar(u_row, v_row) => {
+                u_row[cx] = u;
+                v_row[cx] = v;
+            }
+            ChromaRow::Interleaved(uv_row) => {
+                uv_row[cx * 2] = u;
+                uv_row[cx * 2 + 1] = v;
+            }
+        }
+    }
+}
+
+/// Scalar reference for chroma: the samples from `start` to the end of the
+/// row, each from the average of a 2x2 block of the `top` and `bottom` rows
+fn chroma_row_scalar(
+    top: &[u8],
+    bottom: &[u8],
+    width: usize,
+    start: usize,
+    out: &mut ChromaRow,
+    coefficients: &YuvCoefficients,
+) {
+    for cx in start..width.div_ceil(2) {
+        // Odd widths repeat the last column
+        let left = cx * 2 * 4;
+        let right = (cx * 2 + 1).min(width - 1) * 4;
+        let mut sum = [0i32; 3];
+        for pixel in [&top[left..], &top[right..], &bottom[left..], &bottom[right..]] {
+            sum[0] += pixel[2] as i32;
+            sum[1] += pixel[1] as i32;
+            sum[2] += pixel[0] as i32;
+        }
+        let rgb = sum.map(|s| (s + 2) >> 2);
+
+        out.set(cx, chroma_sample(&rgb, &coefficients.u), chroma_sample(&rgb, &coefficients.v));
+    }
+}
+
+// Compute as many chroma samples as the SIMD path handles from the start of
+// the row, returning how many
+fn chroma_row_simd(
+    isa: Isa,
+    top: &[u8],
+    bottom: &[u8],
+    width: usize,
+    out: &mut ChromaRow,
+    coefficients: &YuvCoefficients,
+) -> usize {
+    match isa {
+        Isa::Scalar => 0,
+        // SAFETY: the instruction set was detected at runtime
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        Isa::Ssse3 => unsafe { x86::chroma_row_ssse3(top, bottom, width, out, coefficients) },
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        Isa::Avx2 => unsafe { x86::chroma_row_avx2(top, bottom, width, out, coefficients) },
+        #[cfg(target_arch = "aarch64")]
+        Isa::Neon => unsafe { neon::chroma_row_neon(top, bottom, width, out, coefficients) },
+    }
+}
+
+fn chroma_sample(rgb: &[i32; 3], weights: &[i32; 3]) -> u8 {
+    let value = (weights[0] * rgb[0] + weights[1] * rgb[1] + weights[2] * rgb[2] + 128) >> 8;
+    (value + 128).clamp(0, 255) as u8
+}
+
+/// Scalar reference for luma
+fn luma_row_scalar(src: &[u8], dst: &mut [u8], width: usize, coefficients: &YuvCoefficients) {
+    let [wr, wg, wb] = coefficients.y.map(u32::from);
+    for (pixel, out) in src.chunks_exact(4).zip(dst.iter_mut()).take(width) {
+        let (b, g, r) = (pixel[0] as u32, pixel[1] as u32, pixel[2] as u32);
+        *out = ((wr * r + wg * g + wb * b + 128) >> 8) as u8 + coefficients.y_offset;
+    }
+}
+
+// Compute as much luma as the SIMD path handles, returning how many pixels
+fn luma_row_simd(isa: Isa, src: &[u8], dst: &mut [u8], width: usize, coefficients: &YuvCoefficients) -> usize {
+    match isa {
+        Isa::Scalar => 0,
+        // SAFETY: the instruction set was detected at runtime
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        Isa::Ssse3 => unsafe { x86::luma_row_ssse3(src, dst, width, coefficients) },
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        Isa::Avx2 => unsafe { x86::luma_row_avx2(src, dst, width, coefficients) },
+        #[cfg(target_arch = "aarch64")]
+        Isa::Neon => unsafe { neon::luma_row_neon(src, dst, width, coefficients) },
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod x86 {
+    #[cfg(target_arch = "x86")]
+    use std::arch::x86::*;
+    #[cfg(target_arch = "x86_64")]
+    use std::arch::x86_64::*;
+
+    use super::{ChromaRow, YuvCoefficients};
+
+    // Moves B, G, R of four BGRA pixels to the first 12 bytes
+    const BGR_SHUFFLE: [i8; 16] = [0, 1, 2, 4, 5, 6, 8, 9, 10, 12, 13, 14, -1, -1, -1, -1];
+
+    #[target_feature(enable = "ssse3")]
+    pub unsafe fn bgr_row_ssse3(src: &[u8], dst: &mut [u8], width: usize) -> usize {
+        let shuffle = _mm_loadu_si128(BGR_SHUFFLE.as_ptr() as *const __m128i);
+        let mut x = 0;
+        // Each store writes 16 bytes of which 12 are kept
+        while x + 4 <= width && x * 3 + 16 <= dst.len() {
+            let pixels = _mm_loadu_si128(src.as_ptr().add(x * 4) as *const __m128i);
+            let packed = _mm_shuffle_epi8(pixels, shuffle);
+            _mm_storeu_si128(dst.as_mut_ptr().add(x * 3) as *mut __m128i, packed);
+            x += 4;
+        }
+        x
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn bgr_row_avx2(src: &[u8], dst: &mut [u8], width: usize) -> usize {
+        let shuffle = _mm256_broadcastsi128_si256(_mm_loadu_si128(BGR_SHUFFLE.as_ptr() as *const __m128i));
+        let mut x = 0;
+        // The shuffle works per 128-bit lane, so each lane is stored separately
+        while x + 8 <= width && x * 3 + 28 <= dst.len() {
+            let pixels = _mm256_loadu_si256(src.as_ptr().add(x * 4) as *const __m256i);
+            let packed = _mm256_shuffle_epi8(pixels, shuffle);
+            let out = dst.as_mut_ptr().add(x * 3);
+            _mm_storeu_si128(out as *mut __m128i, _mm256_castsi256_si128(packed));
+            _mm_storeu_si128(out.add(12) as *mut __m128i, _mm256_extracti128_si256(packed, 1));
+            x += 8;
+        }
+        x
+    }
+
+    // B, G, R, A weights for _mm_madd_epi16 on pixels widened to 16 bits
+    fn luma_weights(coefficients: &YuvCoefficients) -> [i16; 8] {
+        let [r, g, b] = coefficients.y.map(|w| w as i16);
+        [b, g, r, 0, b, g, r, 0]
+    }
+
+    // Sums of the weighted channels of four pixels, as 32-bit lanes
+    #[target_feature(enable = "ssse3")]
+    unsafe fn luma4_ssse3(pixels: __m128i, weights: __m128i) -> __m128i {
+        let zero = _mm_setzero_si128();
+        let low = _mm_madd_epi16(_mm_unpacklo_epi8(pixels, zero), weights);
+        let high = _mm_madd_epi16(_mm_unpackhi_epi8(pixels, zero), weights);
+        let sums = _mm_hadd_epi32(low, high);
+        _mm_srli_epi32(_mm_add_epi32(sums, _mm_set1_epi32(128)), 8)
+    }
+
+    #[target_feature(enable = "ssse3")]
+    pub unsafe fn luma_row_ssse3(src: &[u8], dst: &mut [u8], width: usize, coefficients: &YuvCoefficients) -> usize {
+        let weights = _mm_loadu_si128(luma_weights(coefficients).as_ptr() as *const __m128i);
+        let offset = _mm_set1_epi8(coefficients.y_offset as i8);
+        let mut x = 0;
+        while x + 16 <= width {
+            let p = src.as_ptr().add(x * 4) as *const __m128i;
+            let y0 = luma4_ssse3(_mm_loadu_si128(p), weights);
+            let y1 = luma4_ssse3(_mm_loadu_si128(p.add(1)), weights);
+            let y2 = luma4_ssse3(_mm_loadu_si128(p.add(2)), weights);
+            let y3 = luma4_ssse3(_mm_loadu_si128(p.add(3)), weights);
+            let packed = _mm_packus_epi16(_mm_packs_epi32(y0, y1), _mm_packs_epi32(y2, y3));
+            let luma = _mm_add_epi8(packed, offset);
+            _mm_storeu_si128(dst.as_mut_ptr().add(x) as *mut __m128i, luma);
+            x += 16;
+        }
+        x
+    }
+
+    #[target_feature(enable = "avx2")]
+    unsafe fn luma8_avx2(pixels: __m256i, weights: __m256i) -> __m256i {
+        let zero = _mm256_setzero_si256();
+        let low = _mm256_madd_epi16(_mm256_unpacklo_epi8(pixels, zero), weights);
+        let high = _mm256_madd_epi16(_mm256_unpackhi_epi8(pixels, zero), weights);
+        let sums = _mm256_hadd_epi32(low, high);
+        _mm256_srli_epi32(_mm256_add_epi32(sums, _mm256_set1_epi32(128)), 8)
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn luma_row_avx2(src: &[u8], dst: &mut [u8], width: usize, coefficients: &YuvCoefficients) -> usize {
+        let weights = _mm256_broadcastsi128_si256(
+            _mm_loadu_si128(luma_weights(coefficients).as_ptr() as *const __m128i),
+        );
+        let offset = _mm256_set1_epi8(coefficients.y_offset as i8);
+        // Packing interleaves the 128-bit lanes; this puts the pixels back in order
+        let order = _mm256_setr_epi32(0, 4, 1, 5, 2, 6, 3, 7);
+        let mut x = 0;
+        while x + 32 <= width {
+            let p = src.as_ptr().add(x * 4) as *const __m256i;
+            let y0 = luma8_avx2(_mm256_loadu_si256(p), weights);
+            let y1 = luma8_avx2(_mm256_loadu_si256(p.add(1)), weights);
+            let y2 = luma8_avx2(_mm256_loadu_si256(p.add(2)), weights);
+            let y3 = luma8_avx2(_mm256_loadu_si256(p.add(3)), weights);
+            let packed = _mm256_packus_epi16(_mm256_packs_epi32(y0, y1), _mm256_packs_epi32(y2, y3));
+            let luma = _mm256_add_epi8(_mm256_permutevar8x32_epi32(packed, order), offset);
+            _mm256_storeu_si256(dst.as_mut_ptr().add(x) as *mut __m256i, luma);
+            x += 32;
+        }
+        x
+    }
+
+    // B, G, R, A weights of U and V for _mm_madd_epi16
+    fn chroma_weights(coefficients: &YuvCoefficients) -> ([i16; 8], [i16; 8]) {
+        let weights = |[r, g, b]: [i32; 3]| {
+            let [r, g, b] = [r as i16, g as i16, b as i16];
+            [b, g, r, 0, b, g, r, 0]
+        };
+        (weights(coefficients.u), weights(coefficients.v))
+    }
+
+    // Rounded averages of the 2x2 blocks of four pixels of two rows: B, G,
+    // R, A of the two blocks as 16-bit lanes. The AVX2 version does the
+    // same in each 128-bit lane.
+    #[target_feature(enable = "ssse3")]
+    unsafe fn average2x2_ssse3(top: __m128i, bottom: __m128i) -> __m128i {
+        let zero = _mm_setzero_si128();
+        let low = _mm_add_epi16(_mm_unpacklo_epi8(top, zero), _mm_unpacklo_epi8(bottom, zero));
+        let high = _mm_add_epi16(_mm_unpackhi_epi8(top, zero), _mm_unpackhi_epi8(bottom, zero));
+        let sums = _mm_add_epi16(_mm_unpacklo_epi64(low, high), _mm_unpackhi_epi64(low, high));
+        _mm_srli_epi16(_mm_add_epi16(sums, _mm_set1_epi16(2)), 2)
+    }
+
+    // Turn weighted sums (32-bit) into chroma samples: round, shift and
+    // centre around 128
+    #[target_feature(enable = "ssse3")]
+    unsafe fn chroma4_ssse3(sums: __m128i) -> __m128i {
+        let shifted = _mm_srai_epi32(_mm_add_epi32(sums, _mm_set1_epi32(128)), 8);
+        _mm_add_epi32(shifted, _mm_set1_epi32(128))
+    }
+
+    // Store 8 U samples followed by 8 V samples
+    #[target_feature(enable = "ssse3")]
+    unsafe fn store_chroma8_ssse3(out: &mut ChromaRow, cx: usize, uv: __m128i) {
+        match out {
+            ChromaRow::Planar(u_row, v_row) => {
+                _mm_storel_epi64(u_row.as_mut_ptr().add(cx) as *mut __m128i, uv);
+                _mm_storel_epi64(v_row.as_mut_ptr().add(cx) as *mut __m128i, _mm_srli_si128(uv, 8));
+            }
+            ChromaRow::Interleaved(uv_row) => {
+                let interleaved = _mm_unpacklo_epi8(uv, _mm_srli_si128(uv, 8));
+                _mm_storeu_si128(uv_row.as_mut_ptr().add(cx * 2) as *mut __m128i, interleaved);
+            }
+        }
+    }
+
+    #[target_feature(enable = "ssse3")]
+    pub unsafe fn chroma_row_ssse3(
+        top: &[u8],
+        bottom: &[u8],
+        width: usize,
+        out: &mut ChromaRow,
+        coefficients: &YuvCoefficients,
+    ) -> usize {
+        let (u_weights, v_weights) = chroma_weights(coefficients);
+        let u_weights = _mm_loadu_si128(u_weights.as_ptr() as *const __m128i);
+        let v_weights = _mm_loadu_si128(v_weights.as_ptr() as *const __m128i);
+        // Only whole 2x2 blocks, within both rows
+        let blocks = (width / 2).min(top.len() / 8).min(bottom.len() / 8);
+        let mut cx = 0;
+        while cx + 8 <= blocks {
+            let t = top.as_ptr().add(cx * 8) as *const __m128i;
+            let b = bottom.as_ptr().add(cx * 8) as *const __m128i;
+            let mut u = [_mm_setzero_si128(); 4];
+            let mut v = [_mm_setzero_si128(); 4];
+            for i in 0..4 {
+                let average = average2x2_ssse3(_mm_loadu_si128(t.add(i)), _mm_loadu_si128(b.add(i)));
+                u[i] = _mm_madd_epi16(average, u_weights);
+                v[i] = _mm_madd_epi16(average, v_weights);
+            }
+            let u = _mm_packs_epi32(
+                chroma4_ssse3(_mm_hadd_epi32(u[0], u[1])),
+                chroma4_ssse3(_mm_hadd_epi32(u[2], u[3])),
+            );
+            let v = _mm_packs_epi32(
+                chroma4_ssse3(_mm_hadd_epi32(v[0], v[1])),
+                chroma4_ssse3(_mm_hadd_epi32(v[2], v[3])),
+            );
+            store_chroma8_ssse3(out, cx, _mm_packus_epi16(u, v));
+            cx += 8;
+        }
+        cx
+    }
+
+    #[target_feature(enable = "avx2")]
+    unsafe fn average2x2_avx2(top: __m256i, bottom: __m256i) -> __m256i {
+        let zero = _mm256_setzero_si256();
+        let low = _mm256_add_epi16(_mm256_unpacklo_epi8(top, zero), _mm256_unpacklo_epi8(bottom, zero));
+        let high = _mm256_add_epi16(_mm256_unpackhi_epi8(top, zero), _mm256_unpackhi_epi8(bottom, zero));
+        let sums = _mm256_add_epi16(_mm256_unpacklo_epi64(low, high), _mm256_unpackhi_epi64(low, high));
+        _mm256_srli_epi16(_mm256_add_epi16(sums, _mm256_set1_epi16(2)), 2)
+    }
+
+    #[target_feature(enable = "avx2")]
+    unsafe fn chroma8_avx2(sums: __m256i) -> __m256i {
+        let shifted = _mm256_srai_epi32(_mm256_add_epi32(sums, _mm256_set1_epi32(128)), 8);
+        _mm256_add_epi32(shifted, _mm256_set1_epi32(128))
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn chroma_row_avx2(
+        top: &[u8],
+        bottom: &[u8],
+        width: usize,
+        out: &mut ChromaRow,
+        coefficients: &YuvCoefficients,
+    ) -> usize {
+        let (u_weights, v_weights) = chroma_weights(coefficients);
+        let u_weights = _mm256_broadcastsi128_si256(_mm_loadu_si128(u_weights.as_ptr() as *const __m128i));
+        let v_weights = _mm256_broadcastsi128_si256(_mm_loadu_si128(v_weights.as_ptr() as *const __m128i));
+        // Each 128-bit lane holds two blocks after the averaging and samples
+        // come out of the horizontal adds lane by lane; these put them back
+        // in order, first as 32-bit sums and then as packed bytes
+        let sum_order = _mm256_setr_epi32(0, 1, 4, 5, 2, 3, 6, 7);
+        let byte_order = _mm256_setr_epi32(0, 4, 1, 5, 2, 6, 3, 7);
+        let blocks = (width / 2).min(top.len() / 8).min(bottom.len() / 8);
+        let mut cx = 0;
+        while cx + 16 <= blocks {
+            let t = top.as_ptr().add(cx * 8) as *const __m256i;
+            let b = bottom.as_ptr().add(cx * 8) as *const __m256i;
+            let mut u = [_mm256_setzero_si256(); 4];
+            let mut v = [_mm256_setzero_si256(); 4];
+            for i in 0..4 {
+                let average = average2x2_avx2(_mm256_loadu_si256(t.add(i)), _mm256_loadu_si256(b.add(i)));
+                u[i] = _mm256_madd_epi16(average, u_weights);
+                v[i] = _mm256_madd_epi16(average, v_weights);
+            }
+            let samples = |a: __m256i, b: __m256i| {
+                _mm256_permutevar8x32_epi32(chroma8_avx2(_mm256_hadd_epi32(a, b)), sum_order)
+            };
+            let u = _mm256_packs_epi32(samples(u[0], u[1]), samples(u[2], u[3]));
+            let v = _mm256_packs_epi32(samples(v[0], v[1]), samples(v[2], v[3]));
+            let uv = _mm256_permutevar8x32_epi32(_mm256_packus_epi16(u, v), byte_order);
+
+            // 16 U samples in the low lane, 16 V samples in the high lane
+            let (u, v) = (_mm256_castsi256_si128(uv), _mm256_extracti128_si256(uv, 1));
+            match out {
+                ChromaRow::Planar(u_row, v_row) => {
+                    _mm_storeu_si128(u_row.as_mut_ptr().add(cx) as *mut __m128i, u);
+                    _mm_storeu_si128(v_row.as_mut_ptr().add(cx) as *mut __m128i, v);
+                }
+                ChromaRow::Interleaved(uv_row) => {
+                    let dst = uv_row.as_mut_ptr().add(cx * 2) as *mut __m128i;
+                    _mm_storeu_si128(dst, _mm_unpacklo_epi8(u, v));
+                    _mm_storeu_si128(dst.add(1), _mm_unpackhi_epi8(u, v));
+                }
+            }
+            cx += 16;
+        }
+        cx
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod neon {
+    use std::arch::aarch64::*;
+
+    use super::{ChromaRow, YuvCoefficients};
+
+    #[target_feature(enable = "neon")]
+    pub unsafe fn bgr_row_neon(src: &[u8], dst: &mut [u8], width: usize) -> usize {
+        let mut x = 0;
+        while x + 16 <= width {
+            let pixels = vld4q_u8(src.as_ptr().add(x * 4));
+            vst3q_u8(dst.as_mut_ptr().add(x * 3), uint8x16x3_t(pixels.0, pixels.1, pixels.2));
+            x += 16;
+        }
+        x
+    }
+
+    #[target_feature(enable = "neon")]
+    pub unsafe fn luma_row_neon(src: &[u8], dst: &mut [u8], width: usize, coefficients: &YuvCoefficients) -> usize {
+        // Weights are multiplied as bytes; the scalar code handles anything larger
+        if coefficients.y.iter().any(|w| *w > 255) {
+            return 0;
+        }
+        // Weights sum to at most 256, so the sums fit in 16 bits
+        let [wr, wg, wb] = coefficients.y.map(|w| vdup_n_u8(w as u8));
+        let rounding = vdupq_n_u16(128);
+        let offset = vdupq_n_u8(coefficients.y_offset);
+
+        let mut x = 0;
+        while x + 16 <= width {
+            let pixels = vld4q_u8(src.as_ptr().add(x * 4));
+            let (b, g, r) = (pixels.0, pixels.1, pixels.2);
+
+            let mut low = vmlal_u8(rounding, vget_low_u8(r), wr);
+            low = vmlal_u8(low, vget_low_u8(g), wg);
+            low = vmlal_u8(low, vget_low_u8(b), wb);
+            let mut high = vmlal_u8(rounding, vget_high_u8(r), wr);
+            high = vmlal_u8(high, vget_high_u8(g), wg);
+            high = vmlal_u8(high, vget_high_u8(b), wb);
+
+            let luma = vcombine_u8(vshrn_n_u16(low, 8), vshrn_n_u16(high, 8));
+            vst1q_u8(dst.as_mut_ptr().add(x), vaddq_u8(luma, offset));
+            x += 16;
+        }
+        x
+    }
+
+    // Weighted sum of averaged R, G, B as chroma samples: round, shift,
+    // centre around 128 and clamp
+    #[target_feature(enable = "neon")]
+    unsafe fn chroma8_neon(r: int16x8_t, g: int16x8_t, b: int16x8_t, weights: [i32; 3]) -> uint8x8_t {
+        let [wr, wg, wb] = weights.map(|w| w as i16);
+        let rounding = vdupq_n_s32(128);
+        let mut low = vmlal_n_s16(rounding, vget_low_s16(r), wr);
+        low = vmlal_n_s16(low, vget_low_s16(g), wg);
+        low = vmlal_n_s16(low, vget_low_s16(b), wb);
+        let mut high = vmlal_n_s16(rounding, vget_high_s16(r), wr);
+        high = vmlal_n_s16(high, vget_high_s16(g), wg);
+        high = vmlal_n_s16(high, vget_high_s16(b), wb);
+
+        let center = vdupq_n_s32(128);
+        let low = vaddq_s32(vshrq_n_s32(low, 8), center);
+        let high = vaddq_s32(vshrq_n_s32(high, 8), center);
+        vqmovun_s16(vcombine_s16(vqmovn_s32(low), vqmovn_s32(high)))
+    }
+
+    #[target_feature(enable = "neon")]
+    pub unsafe fn chroma_row_neon(
+        top: &[u8],
+        bottom: &[u8],
+        width: usize,
+        out: &mut ChromaRow,
+        coefficients: &YuvCoefficients,
+    ) -> usize {
+        // Only whole 2x2 blocks, within both rows
+        let blocks = (width / 2).min(top.len() / 8).min(bottom.len() / 8);
+        let mut cx = 0;
+        while cx + 8 <= blocks {
+            let t = vld4q_u8(top.as_ptr().add(cx * 8));
+            let b = vld4q_u8(bottom.as_ptr().add(cx * 8));
+            // Pairwise sums of each row, then the rounded 2x2 average
+            let average = |top: uint8x16_t, bottom: uint8x16_t| {
+                vreinterpretq_s16_u16(vrshrq_n_u16(vpadalq_u8(vpaddlq_u8(top), bottom), 2))
+            };
+            let (blue, green, red) = (average(t.0, b.0), average(t.1, b.1), average(t.2, b.2));
+
+            let u = chroma8_neon(red, green, blue, coefficients.u);
+            let v = chroma8_neon(red, green, blue, coefficients.v);
+            match out {
+                ChromaRow::Planar(u_row, v_row) => {
+                    vst1_u8(u_row.as_mut_ptr().add(cx), u);
+                    vst1_u8(v_row.as_mut_ptr().add(cx), v);
+                }
+                ChromaRow::Interleaved(uv_row) => vst2_u8(uv_row.as_mut_ptr().add(cx * 2), uint8x8x2_t(u, v)),
+            }
+            cx += 8;
+        }
+        cx
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    const ALL_COEFFICIENTS: [&YuvCoefficients; 4] = [&BT601_LIMITED, &BT601_FULL, &BT709_LIMITED, &BT709_FULL];
+
+    // SIMD paths this machine can run
+    fn simd_isas() -> Vec<Isa> {
+        #[allow(unused_mut)]
+        let mut isas = Vec::new();
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        {
+            if is_x86_feature_detected!("ssse3") {
+                isas.push(Isa::Ssse3);
+            }
+            if is_x86_feature_detected!("avx2") {
+                isas.push(Isa::Avx2);
+            }
+        }
+        #[cfg(target_arch = "aarch64")]
+        {
+            if std::arch::is_aarch64_feature_detected!("neon") {
+                isas.push(Isa::Neon);
+            }
+        }
+        isas
+    }
+
+    fn row() -> impl Strategy<Value = (usize, Vec<u8>)> {
+        (0usize..200).prop_flat_map(|width| (Just(width), proptest::collection::vec(any::<u8>(), width * 4)))
+    }
+
+    // Two rows of the same width, for chroma
+    fn row_pair() -> impl Strategy<Value = (usize, Vec<u8>, Vec<u8>)> {
+        (1usize..200).prop_flat_map(|width| {
+            (
+                Just(width),
+                proptest::collection::vec(any::<u8>(), width * 4),
+                proptest::collection::vec(any::<u8>(), width * 4),
+            )
+        })
+    }
+
+    // U and V of a chroma row written by `convert`, as two vectors
+    fn chroma_row(
+        convert: impl Fn(&mut ChromaRow),
+        width: usize,
+        interleaved: bool,
+    ) -> (Vec<u8>, Vec<u8>) {
+        let chroma_width = width.div_ceil(2);
+        if interleaved {
+            let mut uv = vec![0u8; padded(chroma_width * 2)];
+            convert(&mut ChromaRow::Interleaved(&mut uv));
+            uv.truncate(chroma_width * 2);
+            (uv.iter().step_by(2).copied().collect(), uv.iter().skip(1).step_by(2).copied().collect())
+        } else {
+            let (mut u, mut v) = (vec![0u8; padded(chroma_width)], vec![0u8; padded(chroma_width)]);
+            convert(&mut ChromaRow::Planar(&mut u, &mut v));
+            u.truncate(chroma_width);
+            v.truncate(chroma_width);
+            (u, v)
+        }
+    }
+
+    fn frame() -> impl Strategy<Value = (u32, u32, Vec<u8>)> {
+        (1u32..70, 1u32..20).prop_flat_map(|(width, height)| {
+            (
+                Just(width),
+                Just(height),
+                proptest::collection::vec(any::<u8>(), (width * height * 4) as usize),
+            )
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn simd_bgr_rows_match_scalar((width, src) in row()) {
+            let mut expected = vec![0u8; width * 3];
+            bgr_row_scalar(&src, &mut expected, width);
+
+            for isa in simd_isas() {
+                let mut dst = vec![0u8; width * 3];
+                let done = bgr_row_simd(isa, &src, &mut dst, width);
+                prop_assert!(done <= width);
+                bgr_row_scalar(&src[done * 4..], &mut dst[done * 3..], width - done);
+                prop_assert_eq!(&dst, &expected, "{:?}, width {}", isa, width);
+            }
+        }
+
+        #[test]
+        fn simd_luma_rows_match_scalar((width, src) in row(), matrix in 0usize..4) {
+            let coefficients = ALL_COEFFICIENTS[matrix];
+            let mut expected = vec![0u8; width];
+            luma_row_scalar(&src, &mut expected, width, coefficients);
+
+            for isa in simd_isas() {
+                let mut dst = vec![0u8; width];
+                let done = luma_row_simd(isa, &src, &mut dst, width, coefficients);
+                prop_assert!(done <= width);
+                luma_row_scalar(&src[done * 4..], &mut dst[done..], width - done, coefficients);
+                prop_assert_eq!(&dst, &expected, "{:?}, width {}", isa, width);
+            }
+        }
+
+        #[test]
+        fn simd_chroma_rows_match_scalar(
+            (width, top, bottom) in row_pair(),
+            matrix in 0usize..4,
+            interleaved in any::<bool>(),
+            last_row in any::<bool>(),
+        ) {
+            let coefficients = ALL_COEFFICIENTS[matrix];
+            // The last row of an odd-height frame is its own bottom row
+            let bottom = if last_row { &top } else { &bottom };
+            let expected = chroma_row(
+                |out| chroma_row_scalar(&top, bottom, width, 0, out, coefficients),
+                width,
+                interleaved,
+            );
+
+            for isa in simd_isas() {
+                let actual = chroma_row(
+                    |out| {
+                        let done = chroma_row_simd(isa, &top, bottom, width, out, coefficients);
+                        assert!(done <= width / 2);
+                        chroma_row_scalar(&top, bottom, width, done, out, coefficients);
+                    },
+                    width,
+                    interleaved,
+                );
+                prop_assert_eq!(&actual, &expected, "{:?}, width {}", isa, width);
+            }
+        }
+
+        #[test]
+        fn yuv_planes_follow_gstreamer_layout((width, height, bgra) in frame()) {
+            let (w, h) = (width as usize, height as usize);
+            let colorimetry = Colorimetry::default();
+            let y_size = padded(w) * h.next_multiple_of(2);
+            let chroma_height = h.div_ceil(2);
+
+            let i420 = convert(PixelFormat::I420, &bgra, width, height, &colorimetry);
+            prop_assert_eq!(i420.len(), y_size + padded(w.div_ceil(2)) * chroma_height * 2);
+            let nv12 = convert(PixelFormat::Nv12, &bgra, width, height, &colorimetry);
+            prop_assert_eq!(nv12.len(), y_size + padded(w.div_ceil(2) * 2) * chroma_height);
+
+            // Luma of the last row, then the first chroma samples where
+            // GStreamer looks for them
+            let mut luma = vec![0u8; w];
+            luma_row_scalar(&bgra[(h - 1) * w * 4..], &mut luma, w, colorimetry.coefficients());
+            prop_assert_eq!(&i420[(h - 1) * padded(w)..][..w], &luma[..]);
+            prop_assert_eq!(i420[y_size], nv12[y_size]);
+        }
+
+        #[test]
+        fn bgr_matches_source((width, height, bgra) in frame()) {
+            let bgr = convert(PixelFormat::Bgr, &bgra, width, height, &Colorimetry::default());
+            let expected: Vec<u8> = bgra.chunks_exact(4).flat_map(|pixel| pixel[..3].to_vec()).collect();
+            prop_assert_eq!(bgr, expected);
+        }
+    }
+
+    #[test]
+    fn odd_height_chroma_starts_after_padded_luma() {
+        // 3x3 of pure white: luma 235, chroma neutral
+        let bgra = vec![255u8; 3 * 3 * 4];
+        let i420 = convert(PixelFormat::I420, &bgra, 3, 3, &Colorimetry::default());
+
+        // Y: 4 bytes x 4 rows, U and V: 4 bytes x 2 rows each
+        assert_eq!(i420.len(), 16 + 8 + 8);
+        assert_eq!(&i420[..3], &[235, 235, 235]);
+        assert_eq!(&i420[12..16], &[0, 0, 0, 0]);
+        assert_eq!(&i420[16..18], &[128, 128]);
+        assert_eq!(&i420[24..26], &[128, 128]);
+    }
+
+    #[test]
+    fn padded_rows_are_skipped() {
+        // 2x2 frame with rows padded to 16 bytes
+        let mut bgra = vec![0xEEu8; 2 * 16];
+        bgra[..8].copy_from_slice(&[1, 2, 3, 0, 4, 5, 6, 0]);
+        bgra[16..24].copy_from_slice(&[7, 8, 9, 0, 10, 11, 12, 0]);
+
+        let bgr = convert(PixelFormat::Bgr, &bgra, 2, 2, &Colorimetry::default());
+        assert_eq!(bgr, vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12]);
+    }
+}
@@ -0,0 +1,276 @@
+// Change detection between consecutive captured frames
+//
+// Frames are split into square tiles and each tile is compared row by row
+// against the previous frame. Static screens then cost a memcmp per frame
+// instead of a full conversion and encode. Backends that know which parts of
+// the screen changed (X11 XDamage) report dirty rectangles instead, which are
+// mapped onto the same tiles without looking at the pixels.
+
+/// Edge length of a comparison tile in pixels
+const TILE_SIZE: u32 = 32;
+
+/// A changed region of a frame, in frame pixels
+#[derive(Debug, Clone, Copy)]
+pub struct Rect {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Result of comparing a frame against the previous one
+#[derive(Debug, Clone, Copy)]
+pub struct Damage {
+    pub changed_tiles: usize,
+    pub total_tiles: usize,
+}
+
+impl Damage {
+    /// Damage covering the tiles touched by `rects` in a `width` x `height` frame
+    pub fn from_rects(rects: &[Rect], width: u32, height: u32) -> Self {
+        let columns = tiles_for(width);
+        let rows = tiles_for(height);
+        let mut changed = vec![false; columns * rows];
+
+        for rect in rects {
+            // Clip to the frame
+            let left = rect.x.max(0) as u32;
+            let top = rect.y.max(0) as u32;
+            let right = (rect.x + rect.width as i32).clamp(0, width as i32) as u32;
+            let bottom = (rect.y + rect.height as i32).clamp(0, height as i32) as u32;
+            if left >= right || top >= bottom {
+                continue;
+            }
+
+            for row in (top / TILE_SIZE)..=((bottom - 1) / TILE_SIZE) {
+                for column in (left / TILE_SIZE)..=((right - 1) / TILE_SIZE) {
+                    changed[row as usize * columns + column as usize] = true;
+                }
+            }
+        }
+
+        Damage {
+            changed_tiles: changed.iter().filter(|c| **c).count(),
+            total_tiles: columns * rows,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changed_tiles == 0
+    }
+
+    /// Fraction of the frame area covered by changed tiles (0.0 - 1.0)
+    pub fn changed_fraction(&self) -> f32 {
+        if self.total_tiles == 0 {
+            return 0.0;
+        }
+        self.changed_tiles as f32 / self.total_tiles as f32
+    }
+}
+
+pub struct DamageTracker {
+    width: u32,
+    height: u32,
+    previous: Vec<u8>,
+    // Scratch flags, one per tile column of the current band
+    changed_columns: Vec<bool>,
+}
+
+impl DamageTracker {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            previous: Vec::new(),
+            changed_columns: vec![false; tiles_for(width)],
+        }
+    }
+
+    /// Compare a BGRA frame with the previous one and remember it for next time
+    pub fn update(&mut self, frame: &[u8]) -> Damage {
+        let columns = tiles_for(self.width);
+        let rows = tiles_for(self.height);
+        let total_tiles = columns * rows;
+
+        // First frame, or the frame layout changed: everything is damaged
+        if self.previous.len() != frame.len() || self.height == 0 {
+            self.previous = frame.to_vec();
+            return Damage {
+                changed_tiles: total_tiles,
+                total_tiles,
+            };
+        }
+
+        // Rows may be padded, so derive the stride from the buffer
+        let stride = frame.len() / self.height as usize;
+        let tile_bytes = TILE_SIZE as usize * 4;
+        let row_bytes = (self.width as usize * 4).min(stride);
+
+        let mut changed_tiles = 0;
+
+        for band in 0..rows {
+            self.changed_columns.iter_mut().for_each(|c| *c = false);
+
+            let first_row = band * TILE_SIZE as usize;
+            let last_row = (first_row + TILE_SIZE as usize).min(self.height as usize);
+
+            for y in first_row..last_row {
+                let row_start = y * stride;
+                let current = &frame[row_start..row_start + row_bytes];
+                let previous = &self.previous[row_start..row_start + row_bytes];
+
+                // Cheap whole-row check before looking at individual tiles
+                if current == previous {
+                    continue;
+                }
+
+                for (column, changed) in self.changed_columns.iter_mut().enumerate() {
+                    if *changed {
+                        continue;
+                    }
+                    let start = column * tile_bytes;
+                    let end = (start + tile_bytes).min(row_bytes);
+                    if current[start..end] != previous[start..end] {
+                        *changed = true;
+                    }
+                }
+            }
+
+            changed_tiles += self.changed_columns.iter().filter(|c| **c).count();
+        }
+
+        if changed_tiles > 0 {
+            self.previous.copy_from_slice(frame);
+        }
+
+        Damage {
+            changed_tiles,
+            total_tiles,
+        }
+    }
+}
+
+fn tiles_for(pixels: u32) -> usize {
+    pixels.div_ceil(TILE_SIZE) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rect(x: i32, y: i32, width: u32, height: u32) -> Rect {
+        Rect { x, y, width, height }
+    }
+
+    // BGRA frame of a single gray level with `stride` bytes per row
+    fn frame(width: u32, height: u32, stride: usize, value: u8) -> Vec<u8> {
+        assert!(stride >= width as usize * 4);
+        vec![value; stride * height as usize]
+    }
+
+    fn set_pixel(frame: &mut [u8], stride: usize, x: usize, y: usize) {
+        frame[y * stride + x * 4] ^= 0xff;
+    }
+
+    #[test]
+    fn rects_map_to_the_tiles_they_touch() {
+        // 100x70 is 4x3 tiles, the last column and row partial
+        let damage = Damage::from_rects(&[rect(0, 0, 1, 1)], 100, 70);
+        assert_eq!((damage.changed_tiles, damage.total_tiles), (1, 12));
+
+        // Crossing a tile boundary in both directions
+        let damage = Damage::from_rects(&[rect(31, 31, 2, 2)], 100, 70);
+        assert_eq!(damage.changed_tiles, 4);
+
+        // Overlapping rects count each tile once
+        let damage = Damage::from_rects(&[rect(0, 0, 64, 32), rect(32, 0, 32, 32)], 100, 70);
+        assert_eq!(damage.changed_tiles, 2);
+
+        let damage = Damage::from_rects(&[rect(0, 0, 100, 70)], 100, 70);
+        assert_eq!(damage.changed_fraction(), 1.0);
+    }
+
+    #[test]
+    fn rects_are_clipped_to_the_frame() {
+        let damage = Damage::from_rects(&[rect(-50, -50, 60, 60)], 100, 70);
+        assert_eq!(damage.changed_tiles, 1);
+
+        // Columns 2-3 and rows 1-2
+        let damage = Damage::from_rects(&[rect(90, 60, 500, 500)], 100, 70);
+        assert_eq!(damage.changed_tiles, 4);
+
+        let outside = [rect(100, 0, 10, 10), rect(0, -20, 10, 20), rect(5, 5, 0, 10)];
+        assert!(Damage::from_rects(&outside, 100, 70).is_empty());
+    }
+
+    #[test]
+    fn empty_frame_has_no_damage() {
+        let damage = Damage::from_rects(&[rect(0, 0, 10, 10)], 0, 0);
+        assert!(damage.is_empty());
+        assert_eq!(damage.changed_fraction(), 0.0);
+    }
+
+    #[test]
+    fn first_frame_is_fully_damaged() {
+        let mut tracker = DamageTracker::new(100, 70);
+        let damage = tracker.update(&frame(100, 70, 400, 0));
+        assert_eq!((damage.changed_tiles, damage.total_tiles), (12, 12));
+    }
+
+    #[test]
+    fn identical_frames_have_no_damage() {
+        let mut tracker = DamageTracker::new(100, 70);
+        tracker.update(&frame(100, 70, 400, 7));
+        assert!(tracker.update(&frame(100, 70, 400, 7)).is_empty());
+    }
+
+    #[test]
+    fn changed_pixels_damage_their_tiles() {
+        let stride = 400;
+        let mut tracker = DamageTracker::new(100, 70);
+        let base = frame(100, 70, stride, 0);
+        tracker.update(&base);
+
+        let mut changed = base.clone();
+        // Tile (0, 0), tile (3, 2) at the partial bottom-right corner, and
+        // tile (1, 1) twice
+        set_pixel(&mut changed, stride, 0, 0);
+        set_pixel(&mut changed, stride, 99, 69);
+        set_pixel(&mut changed, stride, 33, 33);
+        set_pixel(&mut changed, stride, 63, 63);
+        let damage = tracker.update(&changed);
+        assert_eq!(damage.changed_tiles, 3);
+        assert_eq!(damage.changed_fraction(), 3.0 / 12.0);
+
+        // The changed frame became the reference
+        assert!(tracker.update(&changed).is_empty());
+        assert_eq!(tracker.update(&base).changed_tiles, 3);
+    }
+
+    #[test]
+    fn row_padding_is_ignored() {
+        // 40 pixels wide with rows padded to 192 bytes
+        let stride = 192;
+        let mut tracker = DamageTracker::new(40, 40);
+        let base = frame(40, 40, stride, 0);
+        tracker.update(&base);
+
+        let mut padded = base.clone();
+        padded[stride - 1] = 0xff;
+        padded[stride * 39 + 170] = 0xff;
+        assert!(tracker.update(&padded).is_empty());
+
+        // Pixel 39 of row 5 lives in the second tile column
+        let mut changed = base.clone();
+        set_pixel(&mut changed, stride, 39, 5);
+        assert_eq!(tracker.update(&changed).changed_tiles, 1);
+    }
+
+    #[test]
+    fn size_change_damages_everything() {
+        let mut tracker = DamageTracker::new(64, 64);
+        tracker.update(&frame(64, 64, 256, 0));
+        let damage = tracker.update(&frame(64, 64, 320, 0));
+        assert_eq!(damage.changed_tiles, damage.total_tiles);
+    }
+}
@@ -0,0 +1,465 @@
+// ONVIF WS-Discovery so NVRs find the streamer on their own
+//
+// Listens on the WS-Discovery multicast group (239.255.255.250:3702) and
+// answers Probe messages for ONVIF NetworkVideoTransmitters with a
+// ProbeMatch pointing at our device service. A Hello is multicast on start
+// and a Bye on shutdown so NVRs that are already running notice us.
+
+use anyhow::{Context, Result};
+use log::{debug, error, info, warn};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::Config;
+
+const MULTICAST_ADDRESS: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+const PORT: u16 = 3702;
+
+/// Largest datagram we accept; Probe messages are well under this
+const MAX_MESSAGE_SIZE: usize = 8192;
+
+const ACTION_HELLO: &str = "http://schemas.xmlsoap.org/ws/2005/04/discovery/Hello";
+const ACTION_BYE: &str = "http://schemas.xmlsoap.org/ws/2005/04/discovery/Bye";
+const ACTION_PROBE_MATCHES: &str = "http://schemas.xmlsoap.org/ws/2005/04/discovery/ProbeMatches";
+const ACTION_PROBE: &str = "http://schemas.xmlsoap.org/ws/2005/04/discovery/Probe";
+const TO_DISCOVERY: &str = "urn:schemas-xmlsoap-org:ws:2005:04:discovery";
+const TO_ANONYMOUS: &str = "http://schemas.xmlsoap.org/ws/2004/08/addressing/role/anonymous";
+
+/// Device types we answer probes for
+const TYPES: &str = "dn:NetworkVideoTransmitter tds:Device";
+
+/// What we announce about ourselves
+struct Device {
+    endpoint: String,
+    xaddrs: String,
+    scopes: String,
+    // AppSequence: the instance changes on every start, messages are numbered
+    instance_id: u64,
+    message_number: AtomicU64,
+}
+
+impl Device {
+    fn from_config(config: &Config) -> Self {
+        let name = if config.onvif.name.is_empty() {
+            glib::host_name().to_string()
+        } else {
+            config.onvif.name.clone()
+        };
+
+        let mut scopes = vec![
+            "onvif://www.onvif.org/type/video_encoder".to_string(),
+            "onvif://www.onvif.org/Profile/Streaming".to_string(),
+            format!("onvif://www.onvif.org/name/{}", scope_value(&name)),
+            "onvif://www.onvif.org/hardware/display_rtsp_streamer".to_string(),
+        ];
+        if !config.onvif.location.is_empty() {
+            scopes.push(format!("onvif://www.onvif.org/location/{}", scope_value(&config.onvif.location)));
+        }
+
+        Self {
+            endpoint: endpoint_reference(config),
+            xaddrs: device_service_url(config),
+            scopes: scopes.join(" "),
+            instance_id: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(1),
+            message_number: AtomicU64::new(1),
+        }
+    }
+
+    fn app_sequence(&self) -> String {
+        format!(
+            "<wsd:AppSequence InstanceId=\"{}\" MessageNumber=\"{}\"/>",
+            self.instance_id,
+            self.message_number.fetch_add(1, Ordering::SeqCst)
+        )
+    }
+
+    fn hello(&self) -> String {
+        envelope(
+            ACTION_HELLO,
+            TO_DISCOVERY,
+            None,
+            &self.app_sequence(),
+            &format!(
+                "<wsd:Hello>{}<wsd:MetadataVersion>1</wsd:MetadataVersion></wsd:Hello>",
+                self.description()
+            ),
+        )
+    }
+
+    fn bye(&self) -> String {
+        envelope(
+            ACTION_BYE,
+            TO_DISCOVERY,
+            None,
+            &self.app_sequence(),
+            &format!(
+                "<wsd:Bye><wsa:EndpointReference><wsa:Address>{}</wsa:Address></wsa:EndpointReference></wsd:Bye>",
+                self.endpoint
+            ),
+        )
+    }
+
+    fn probe_matches(&self, relates_to: &str) -> String {
+        envelope(
+            ACTION_PROBE_MATCHES,
+            TO_ANONYMOUS,
+            Some(relates_to),
+            &self.app_sequence(),
+            &format!(
+                "<wsd:ProbeMatches><wsd:ProbeMatch>{}<wsd:MetadataVersion>1</wsd:MetadataVersion>\
+                 </wsd:ProbeMatch></wsd:ProbeMatches>",
+                self.description()
+            ),
+        )
+    }
+
+    // Endpoint reference, types, scopes and addresses, shared by Hello and ProbeMatch
+    fn description(&self) -> String {
+        format!(
+            "<wsa:EndpointReference><wsa:Address>{}</wsa:Address></wsa:EndpointReference>\
+             <wsd:Types>{}</wsd:Types><wsd:Scopes>{}</wsd:Scopes><wsd:XAddrs>{}</wsd:XAddrs>",
+            self.endpoint, TYPES, self.scopes, self.xaddrs
+        )
+    }
+}
+
+/// URL of the ONVIF device service advertised in XAddrs
+pub fn device_service_url(config: &Config) -> String {
+    format!("http://{}:{}/onvif/device_service", config.server_address, config.http_port)
+}
+
+/// Stable WS-Addressing endpoint of this machine, as a urn:uuid
+pub fn endpoint_reference(config: &Config) -> String {
+    let name = format!("{}:{}:{}", glib::host_name(), config.http_port, config.rtsp_port);
+    format!("urn:uuid:{}", name_uuid(&name))
+}
+
+/// Answers WS-Discovery probes until dropped
+pub struct DiscoveryResponder {
+    device: Arc<Device>,
+    socket: UdpSocket,
+    running: Arc<AtomicBool>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl DiscoveryResponder {
+    pub fn start(config: &Config) -> Result<Self> {
+        let socket = multicast_socket().context("Failed to open WS-Discovery socket")?;
+        let device = Arc::new(Device::from_config(config));
+        let running = Arc::new(AtomicBool::new(true));
+
+        send_multicast(&socket, &device.hello());
+
+        let handle = {
+            let socket = socket.try_clone().context("Failed to clone WS-Discovery socket")?;
+            let device = device.clone();
+            let running = running.clone();
+            thread::spawn(move || run_responder(socket, device, running))
+        };
+
+        info!("WS-Discovery responder started, announcing {}", device.xaddrs);
+
+        Ok(Self {
+            device,
+            socket,
+            running,
+            handle: Some(handle),
+        })
+    }
+}
+
+impl Drop for DiscoveryResponder {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            if let Err(e) = handle.join() {
+                error!("Error joining WS-Discovery thread: {:?}", e);
+            }
+        }
+        send_multicast(&self.socket, &self.device.bye());
+        info!("WS-Discovery responder stopped");
+    }
+}
+
+fn run_responder(socket: UdpSocket, device: Arc<Device>, running: Arc<AtomicBool>) {
+    let mut buffer = [0u8; MAX_MESSAGE_SIZE];
+
+    while running.load(Ordering::SeqCst) {
+        let (length, sender) = match socket.recv_from(&mut buffer) {
+            Ok(received) => received,
+            // Read timeout, check whether we should stop
+            Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => continue,
+            Err(e) => {
+                error!("WS-Discovery receive failed: {}", e);
+                thread::sleep(Duration::from_secs(1));
+                continue;
+            }
+        };
+
+        let message = String::from_utf8_lossy(&buffer[..length]);
+        let reply = match answer(&device, &message) {
+            Some(reply) => reply,
+            None => continue,
+        };
+
+        debug!("WS-Discovery probe from {}", sender);
+        if let Err(e) = socket.send_to(reply.as_bytes(), sender) {
+            warn!("Failed to answer WS-Discovery probe from {}: {}", sender, e);
+        }
+    }
+}
+
+// ProbeMatches for a Probe looking for us, None for anything else
+fn answer(device: &Device, message: &str) -> Option<String> {
+    if element_text(message, "Action").map(str::trim) != Some(ACTION_PROBE) {
+        return None;
+    }
+
+    // An empty Types matches any device
+    if let Some(types) = element_text(message, "Types") {
+        let wanted = types.split_whitespace().any(|t| {
+            let local = t.rsplit(':').next().unwrap_or(t);
+            local == "NetworkVideoTransmitter" || local == "Device"
+        });
+        if !types.trim().is_empty() && !wanted {
+            return None;
+        }
+    }
+
+    match element_text(message, "MessageID") {
+        Some(id) => Some(device.probe_matches(id.trim())),
+        None => {
+            debug!("Ignoring WS-Discovery probe without MessageID");
+            None
+        }
+    }
+}
+
+/// Probe the network and return the XAddrs of every device that answers
+/// within `timeout`
+pub fn probe(timeout: Duration) -> Result<Vec<(SocketAddr, String)>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).context("Failed to open probe socket")?;
+    socket.set_read_timeout(Some(Duration::from_millis(200)))?;
+
+    socket
+        .send_to(probe_message().as_bytes(), (MULTICAST_ADDRESS, PORT))
+        .context("Failed to send probe")?;
+
+    let deadline = std::time::Instant::now() + timeout;
+    let mut buffer = [0u8; MAX_MESSAGE_SIZE];
+    let mut found = Vec::new();
+    while std::time::Instant::now() < deadline {
+        match socket.recv_from(&mut buffer) {
+            Ok((length, sender)) => {
+                if let Some(xaddrs) = reply_xaddrs(&String::from_utf8_lossy(&buffer[..length])) {
+                    found.push((sender, xaddrs));
+                }
+            }
+            Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => {}
+            Err(e) => return Err(e).context("Failed to receive probe matches"),
+        }
+    }
+
+    Ok(found)
+}
+
+fn probe_message() -> String {
+    envelope(
+        ACTION_PROBE,
+        TO_DISCOVERY,
+        None,
+        "",
+        "<wsd:Probe><wsd:Types>dn:NetworkVideoTransmitter</wsd:Types></wsd:Probe>",
+    )
+}
+
+// Device service addresses announced in a ProbeMatches
+fn reply_xaddrs(reply: &str) -> Option<String> {
+    element_text(reply, "XAddrs").map(|xaddrs| xaddrs.trim().to_string())
+}
+
+// Socket bound to the discovery port that shares it with other responders
+// (Windows runs its own WS-Discovery service on 3702)
+fn multicast_socket() -> Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, PORT).into())?;
+    socket.join_multicast_v4(&MULTICAST_ADDRESS, &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_multicast_ttl_v4(1)?;
+    socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+    Ok(socket.into())
+}
+
+fn send_multicast(socket: &UdpSocket, message: &str) {
+    if let Err(e) = socket.send_to(message.as_bytes(), (MULTICAST_ADDRESS, PORT)) {
+        warn!("Failed to send WS-Discovery announcement: {}", e);
+    }
+}
+
+fn envelope(action: &str, to: &str, relates_to: Option<&str>, app_sequence: &str, body: &str) -> String {
+    let relates_to = relates_to
+        .map(|id| format!("<wsa:RelatesTo>{}</wsa:RelatesTo>", escape(id)))
+        .unwrap_or_default();
+
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+         <soap:Envelope xmlns:soap=\"http://www.w3.org/2003/05/soap-envelope\" \
+         xmlns:wsa=\"http://schemas.xmlsoap.org/ws/2004/08/addressing\" \
+         xmlns:wsd=\"http://schemas.xmlsoap.org/ws/2005/04/discovery\" \
+         xmlns:dn=\"http://www.onvif.org/ver10/network/wsdl\" \
+         xmlns:tds=\"http://www.onvif.org/ver10/device/wsdl\">\
+         <soap:Header><wsa:MessageID>{}</wsa:MessageID>{}<wsa:To>{}</wsa:To>\
+         <wsa:Action>{}</wsa:Action>{}</soap:Header>\
+         <soap:Body>{}</soap:Body></soap:Envelope>",
+        message_id(), relates_to, to, action, app_sequence, body
+    )
+}
+
+// Unique ID for each message we send
+fn message_id() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    let name = format!("{}:{}:{}", nanos, std::process::id(), COUNTER.fetch_add(1, Ordering::SeqCst));
+    format!("urn:uuid:{}", name_uuid(&name))
+}
+
+// Namespace of the name-based UUIDs of this program (b77a64f6-8705-47e3-9924-9fea5609359b)
+const UUID_NAMESPACE: [u8; 16] = [
+    0xb7, 0x7a, 0x64, 0xf6, 0x87, 0x05, 0x47, 0xe3, 0x99, 0x24, 0x9f, 0xea, 0x56, 0x09, 0x35, 0x9b,
+];
+
+// The same name always gives the same UUID, whatever the platform or Rust release
+fn name_uuid(name: &str) -> String {
+    uuid_v5(&UUID_NAMESPACE, name)
+}
+
+// RFC 4122 name-based UUID: SHA-1 of the namespace and the name, with the
+// version (5) and variant bits set
+fn uuid_v5(namespace: &[u8; 16], name: &str) -> String {
+    let mut hasher = sha1_smol::Sha1::new();
+    hasher.update(namespace);
+    hasher.update(name.as_bytes());
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(&hasher.digest().bytes()[..16]);
+    bytes[6] = (bytes[6] & 0x0f) | 0x50;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+/// Text content of the first element with the given local name, whatever
+/// its namespace prefix
+pub fn element_text<'a>(xml: &'a str, local_name: &str) -> Option<&'a str> {
+    element(xml, local_name).map(|(_, content)| content)
+}
+
+/// Opening tag (name and attributes) and text content of the first element
+/// with the given local name
+pub fn element<'a>(xml: &'a str, local_name: &str) -> Option<(&'a str, &'a str)> {
+    let mut rest = xml;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+        let tag_end = rest.find('>')?;
+        let tag = &rest[..tag_end];
+        let name = tag.split_whitespace().next().unwrap_or("");
+        let local = name.rsplit(':').next().unwrap_or(name);
+
+        if local == local_name && !tag.ends_with('/') {
+            let content = &rest[tag_end + 1..];
+            let end = content.find("</")?;
+            return Some((tag, &content[..end]));
+        }
+        rest = &rest[tag_end + 1..];
+    }
+    None
+}
+
+/// Escape text for use in XML content
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// Scope values are URI path segments
+fn scope_value(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device() -> Device {
+        Device {
+            endpoint: format!("urn:uuid:{}", name_uuid("nvr-host:8080:8554")),
+            xaddrs: "http://192.168.1.20:8080/onvif/device_service".to_string(),
+            scopes: "onvif://www.onvif.org/name/lobby".to_string(),
+            instance_id: 1,
+            message_number: AtomicU64::new(1),
+        }
+    }
+
+    #[test]
+    fn uuid_v5_matches_rfc_4122() {
+        // The DNS namespace, 6ba7b810-9dad-11d1-80b4-00c04fd430c8
+        let dns = [
+            0x6b, 0xa7, 0xb8, 0x10, 0x9d, 0xad, 0x11, 0xd1, 0x80, 0xb4, 0x00, 0xc0, 0x4f, 0xd4, 0x30, 0xc8,
+        ];
+        assert_eq!(uuid_v5(&dns, "python.org"), "886313e1-3b8a-5372-9b90-0c9aee199e5d");
+    }
+
+    #[test]
+    fn name_uuid_is_fixed() {
+        // Must never change, NVRs know the device by it
+        assert_eq!(name_uuid("nvr-host:8080:8554"), "ecebe178-a696-5132-9a9b-7baacdefbcfe");
+        assert_ne!(name_uuid("nvr-host:8080:8555"), name_uuid("nvr-host:8080:8554"));
+    }
+
+    #[test]
+    fn message_ids_are_unique() {
+        assert_ne!(message_id(), message_id());
+    }
+
+    #[test]
+    fn probe_round_trip() {
+        let device = device();
+        let probe = probe_message();
+        let reply = answer(&device, &probe).expect("probe not answered");
+
+        assert_eq!(element_text(&reply, "Action").map(str::trim), Some(ACTION_PROBE_MATCHES));
+        assert_eq!(element_text(&reply, "RelatesTo"), element_text(&probe, "MessageID"));
+        assert_eq!(element_text(&reply, "Address"), Some(device.endpoint.as_str()));
+        assert_eq!(reply_xaddrs(&reply).as_deref(), Some(device.xaddrs.as_str()));
+    }
+
+    #[test]
+    fn other_probes_are_ignored() {
+        let device = device();
+        let printer = probe_message().replace("dn:NetworkVideoTransmitter", "wprt:PrintDeviceType");
+        assert!(answer(&device, &printer).is_none());
+        assert!(answer(&device, &device.hello()).is_none());
+
+        // Any device matches a probe without types
+        let any = probe_message().replace("dn:NetworkVideoTransmitter", "");
+        assert!(answer(&device, &any).is_some());
+    }
+}
@@ -0,0 +1,436 @@
+// H.264 encoding of a display outside the RTSP server
+//
+// RTSP media only run while clients are connected, but HLS and the other
+// HTTP/file outputs need encoded video outside of them. Each display that has
+// such outputs gets one encoder, fed by its capture thread like an RTSP
+// mount, whose access units are handed to every subscriber. Publishing,
+// recording and clips keep it running; for HLS and WebRTC viewers it only
+// runs while someone is watching, and stops IDLE_TIMEOUT after that. A
+// pipeline that fails is restarted with backoff, its timestamps starting
+// over, which subscribers take as a discontinuity.
+
+use anyhow::{Context, Result};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use gstreamer_app::{AppSink, AppSinkCallbacks, AppSrc};
+use log::{error, info, warn};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config::{Config, StreamConfig};
+use crate::overlay;
+use crate::publish::Backoff;
+use crate::rtsp::{self, MediaSource, RtspMount};
+use crate::watermark;
+
+/// One encoded H.264 access unit
+#[derive(Clone)]
+pub struct EncodedFrame {
+    /// Annex B byte stream; keyframes carry SPS and PPS
+    pub buffer: gst::Buffer,
+    /// Presentation time, in the encoder's running time
+    pub pts: Duration,
+    pub keyframe: bool,
+}
+
+impl EncodedFrame {
+    /// The access unit without timestamps, for an appsrc in another pipeline
+    /// that stamps buffers with its own clock (do-timestamp)
+    pub fn unstamped_buffer(&self) -> gst::Buffer {
+        let mut buffer = self.buffer.copy();
+        {
+            let buffer = buffer.get_mut().unwrap();
+            buffer.set_pts(gst::ClockTime::NONE);
+            buffer.set_dts(gst::ClockTime::NONE);
+        }
+        buffer
+    }
+}
+
+/// Encoder of each display that has one, by display slot
+pub type Encoders = Arc<Mutex<HashMap<usize, Arc<Encoder>>>>;
+
+/// How long an on-demand encoder keeps running after it was last wanted
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+type Subscriber = Box<dyn FnMut(&EncodedFrame) -> bool + Send>;
+
+// Who wants the encoder's output
+struct Demand {
+    // Outputs that need video all the time (publishing, recording, clips)
+    always_on: bool,
+    // Live viewers, see `Encoder::hold`
+    holds: usize,
+    last_wanted: Instant,
+    playing: bool,
+    // When the pipeline was last started
+    started: Instant,
+    // Set after a failure: the pipeline stays stopped until then
+    retry_at: Option<Instant>,
+}
+
+// Starts and stops the pipeline as its output is wanted
+struct Activity {
+    name: String,
+    pipeline: gst::Pipeline,
+    appsrc: AppSrc,
+    mount: RtspMount,
+    demand: Mutex<Demand>,
+    // Set from the bus when the pipeline fails
+    failed: Arc<AtomicBool>,
+}
+
+impl Activity {
+    fn wake(&self) {
+        let mut demand = self.demand.lock().unwrap();
+        demand.last_wanted = Instant::now();
+        if !demand.playing && demand.retry_at.is_none() {
+            self.start(&mut demand);
+        }
+    }
+
+    fn start(&self, demand: &mut Demand) {
+        match self.pipeline.set_state(gst::State::Playing) {
+            Ok(_) => {
+                self.mount.connect(self.appsrc.clone());
+                demand.playing = true;
+                demand.started = Instant::now();
+                info!("Started encoder for {}", self.name);
+            }
+            Err(e) => error!("Failed to start encoder for {}: {}", self.name, e),
+        }
+    }
+
+    // Stop a failed pipeline and start it again once the backoff is over,
+    // if it is still wanted then
+    fn restart_if_failed(&self, backoff: &mut Backoff, now: Instant) {
+        let mut demand = self.demand.lock().unwrap();
+        if self.failed.swap(false, Ordering::SeqCst) && demand.playing {
+            self.mount.disconnect();
+            if let Err(e) = self.pipeline.set_state(gst::State::Null) {
+                error!("Failed to stop encoder for {}: {}", self.name, e);
+            }
+            demand.playing = false;
+
+            let delay = backoff.after_failure(now.saturating_duration_since(demand.started));
+            warn!("Restarting encoder for {} in {:?}", self.name, delay);
+            demand.retry_at = Some(now + delay);
+        }
+
+        if demand.retry_at.is_some_and(|retry_at| now >= retry_at) {
+            demand.retry_at = None;
+            if !demand.idle_since(now) {
+                self.start(&mut demand);
+            }
+        }
+    }
+
+    fn stop_if_idle(&self, now: Instant) {
+        let mut demand = self.demand.lock().unwrap();
+        if !demand.playing || !demand.idle_since(now) {
+            return;
+        }
+
+        self.mount.disconnect();
+        if let Err(e) = self.pipeline.set_state(gst::State::Null) {
+            error!("Failed to stop encoder for {}: {}", self.name, e);
+        }
+        demand.playing = false;
+        info!("Stopped encoder for {}, nobody is watching", self.name);
+    }
+}
+
+impl Demand {
+    fn idle_since(&self, now: Instant) -> bool {
+        !self.always_on && self.holds == 0 && now.saturating_duration_since(self.last_wanted) >= IDLE_TIMEOUT
+    }
+}
+
+pub struct Encoder {
+    appsink: AppSink,
+    activity: Arc<Activity>,
+    subscribers: Arc<Mutex<Vec<Subscriber>>>,
+    running: Arc<AtomicBool>,
+    supervisor_handle: Option<thread::JoinHandle<()>>,
+}
+
+/// Keeps an on-demand encoder running until dropped
+pub struct EncoderHold {
+    activity: Arc<Activity>,
+}
+
+impl Drop for EncoderHold {
+    fn drop(&mut self) {
+        let mut demand = self.activity.demand.lock().unwrap();
+        demand.holds -= 1;
+        demand.last_wanted = Instant::now();
+    }
+}
+
+impl Encoder {
+    /// Set up the encoder of a display's stream, running right away when
+    /// `always_on` and otherwise once wanted. A keyframe is forced at least
+    /// every `keyframe_interval`, however rarely frames arrive.
+    pub fn start(
+        name: &str,
+        width: u32,
+        height: u32,
+        config: &Config,
+        stream: &StreamConfig,
+        keyframe_interval: Duration,
+        always_on: bool,
+    ) -> Result<Self> {
+        rtsp::init()?;
+
+        // h264parse repeats SPS/PPS before every keyframe, so any keyframe is
+        // a place to start decoding
+        let launch_str = format!(
+            "{}x264enc tune=zerolatency speed-preset=ultrafast key-int-max={} ! \
+             h264parse config-interval=-1 ! video/x-h264,stream-format=byte-stream,alignment=au ! \
+             appsink name=sink sync=false",
+            rtsp::video_launch("appsrc name=source", stream),
+            (config.frame_rate as u64 * keyframe_interval.as_secs().max(1)).max(1),
+        );
+        let pipeline = gst::parse_launch(&launch_str)
+            .with_context(|| format!("Failed to create encoder for {}", name))?
+            .downcast::<gst::Pipeline>()
+            .map_err(|_| anyhow::anyhow!("Encoder for {} is not a pipeline", name))?;
+
+        let appsrc = pipeline
+            .by_name("source")
+            .and_then(|source| source.downcast::<AppSrc>().ok())
+            .context("No appsrc in encoder pipeline")?;
+        let appsink = pipeline
+            .by_name("sink")
+            .and_then(|sink| sink.downcast::<AppSink>().ok())
+            .context("No appsink in encoder pipeline")?;
+
+        overlay::attach(pipeline.upcast_ref(), &stream.overlay, name);
+        watermark::attach(pipeline.upcast_ref(), &stream.watermark);
+
+        let subscribers: Arc<Mutex<Vec<Subscriber>>> = Arc::new(Mutex::new(Vec::new()));
+        let mut last_keyframe: Option<Duration> = None;
+        let mut keyframe_requested = false;
+        {
+            let subscribers = subscribers.clone();
+            appsink.set_callbacks(
+                AppSinkCallbacks::builder()
+                    .new_sample(move |appsink| {
+                        let sample = appsink.pull_sample().map_err(|_| gst::FlowError::Eos)?;
+                        let buffer = match sample.buffer_owned() {
+                            Some(buffer) => buffer,
+                            None => return Ok(gst::FlowSuccess::Ok),
+                        };
+                        let frame = EncodedFrame {
+                            pts: Duration::from_nanos(buffer.pts().map_or(0, |pts| pts.nseconds())),
+                            keyframe: !buffer.flags().contains(gst::BufferFlags::DELTA_UNIT),
+                            buffer,
+                        };
+
+                        // x264enc counts frames, which come slowly while the
+                        // screen is static, so ask for keyframes on time
+                        if frame.keyframe {
+                            last_keyframe = Some(frame.pts);
+                            keyframe_requested = false;
+                        } else if !keyframe_requested
+                            && last_keyframe.is_none_or(|last| frame.pts.saturating_sub(last) >= keyframe_interval)
+                        {
+                            keyframe_requested = request_keyframe(appsink);
+                        }
+
+                        subscribers.lock().unwrap().retain_mut(|subscriber| subscriber(&frame));
+                        Ok(gst::FlowSuccess::Ok)
+                    })
+                    .build(),
+            );
+        }
+
+        let failed = Arc::new(AtomicBool::new(false));
+        {
+            let failed = failed.clone();
+            watch_bus(&pipeline, &format!("Encoder for {}", name), move || {
+                failed.store(true, Ordering::SeqCst);
+            });
+        }
+
+        let source = MediaSource {
+            element: "source".to_string(),
+            name: name.to_string(),
+            width,
+            height,
+            colorimetry: stream.colorimetry(),
+        };
+        let mount = RtspMount::for_appsrc(
+            appsrc.clone(),
+            source,
+            config.frame_rate,
+            config.pixel_format(),
+            config.placeholder.clone(),
+            name,
+        );
+        // Frames are dropped until the pipeline runs
+        mount.disconnect();
+
+        let activity = Arc::new(Activity {
+            name: name.to_string(),
+            pipeline,
+            appsrc,
+            mount,
+            demand: Mutex::new(Demand {
+                always_on,
+                holds: 0,
+                last_wanted: Instant::now(),
+                playing: false,
+                started: Instant::now(),
+                retry_at: None,
+            }),
+            failed,
+        });
+        if always_on {
+            activity
+                .pipeline
+                .set_state(gst::State::Playing)
+                .with_context(|| format!("Failed to start encoder for {}", name))?;
+            activity.mount.connect(activity.appsrc.clone());
+            activity.demand.lock().unwrap().playing = true;
+            info!("Started encoder for {}", name);
+        }
+
+        let running = Arc::new(AtomicBool::new(true));
+        let supervisor_handle = {
+            let activity = activity.clone();
+            let running = running.clone();
+            thread::spawn(move || {
+                let mut backoff = Backoff::new();
+                while running.load(Ordering::SeqCst) {
+                    thread::sleep(Duration::from_millis(500));
+                    activity.restart_if_failed(&mut backoff, Instant::now());
+                    activity.stop_if_idle(Instant::now());
+                }
+            })
+        };
+
+        Ok(Self {
+            appsink,
+            activity,
+            subscribers,
+            running,
+            supervisor_handle: Some(supervisor_handle),
+        })
+    }
+
+    /// The input the capture thread pushes frames to
+    pub fn mount(&self) -> RtspMount {
+        self.activity.mount.clone()
+    }
+
+    /// Note that a viewer wants the output, starting the encoder if it is
+    /// idle; it keeps running for IDLE_TIMEOUT after the last call
+    pub fn wake(&self) {
+        self.activity.wake();
+    }
+
+    /// Keep the encoder running for as long as the returned hold lives
+    pub fn hold(&self) -> EncoderHold {
+        self.activity.demand.lock().unwrap().holds += 1;
+        self.activity.wake();
+        EncoderHold {
+            activity: self.activity.clone(),
+        }
+    }
+
+    /// Call `subscriber` with every access unit from now on, until it
+    /// returns false
+    pub fn subscribe(&self, subscriber: impl FnMut(&EncodedFrame) -> bool + Send + 'static) {
+        self.subscribers.lock().unwrap().push(Box::new(subscriber));
+    }
+
+    /// Ask for a keyframe as soon as possible, e.g. for a new viewer
+    pub fn force_keyframe(&self) {
+        request_keyframe(&self.appsink);
+    }
+}
+
+fn request_keyframe(appsink: &AppSink) -> bool {
+    let event = gstreamer_video::UpstreamForceKeyUnitEvent::builder()
+        .all_headers(true)
+        .build();
+    appsink.send_event(event)
+}
+
+/// Log errors and warnings of a pipeline that runs outside the RTSP server
+/// and call `on_error` when it fails. Messages are handled on the thread
+/// posting them, so this works without a GLib main loop.
+pub fn watch_bus(pipeline: &gst::Pipeline, name: &str, on_error: impl Fn() + Send + Sync + 'static) {
+    let bus = match pipeline.bus() {
+        Some(bus) => bus,
+        None => return,
+    };
+    let name = name.to_string();
+    bus.set_sync_handler(move |_, message| {
+        match message.view() {
+            gst::MessageView::Error(err) => {
+                error!("{} failed: {} ({:?})", name, err.error(), err.debug());
+                on_error();
+            }
+            gst::MessageView::Eos(_) => {
+                warn!("{} ended", name);
+                on_error();
+            }
+            gst::MessageView::Warning(warning) => {
+                warn!("{}: {}", name, warning.error());
+            }
+            _ => {}
+        }
+        gst::BusSyncReply::Drop
+    });
+}
+
+impl Drop for Encoder {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        if let Some(handle) = self.supervisor_handle.take() {
+            if let Err(e) = handle.join() {
+                error!("Error joining encoder supervisor thread: {:?}", e);
+            }
+        }
+        self.activity.mount.disconnect();
+        if let Err(e) = self.activity.pipeline.set_state(gst::State::Null) {
+            error!("Failed to stop encoder: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn demand(always_on: bool, holds: usize, last_wanted: Instant) -> Demand {
+        Demand {
+            always_on,
+            holds,
+            last_wanted,
+            playing: true,
+            started: last_wanted,
+            retry_at: None,
+        }
+    }
+
+    #[test]
+    fn on_demand_encoders_idle_after_the_timeout() {
+        let start = Instant::now();
+        assert!(!demand(false, 0, start).idle_since(start + IDLE_TIMEOUT - Duration::from_secs(1)));
+        assert!(demand(false, 0, start).idle_since(start + IDLE_TIMEOUT));
+    }
+
+    #[test]
+    fn holds_and_always_on_outputs_keep_encoders_running() {
+        let start = Instant::now();
+        let later = start + IDLE_TIMEOUT * 10;
+        assert!(!demand(false, 1, start).idle_since(later));
+        assert!(!demand(true, 0, start).idle_since(later));
+    }
+}
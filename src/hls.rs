@@ -0,0 +1,220 @@
+// HLS output for browser playback
+//
+// The display's encoder output is cut into MPEG-TS segments at keyframes and
+// kept in a small in-memory ring; /hls/display<N>/index.m3u8 lists the most
+// recent ones. Nothing is written to disk. The encoder runs while players
+// keep fetching, so the first request after a quiet spell waits for a
+// segment (503) and the ring restarts whenever the encoder does.
+
+use log::debug;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tiny_http::{Request, Response};
+
+use crate::config::HlsConfig;
+use crate::encoder::{EncodedFrame, Encoder};
+use crate::http::{self, HttpServer};
+use crate::ts::TsMuxer;
+
+/// HLS output of each display, by display slot
+pub type HlsStreams = Arc<Mutex<HashMap<usize, Arc<HlsStream>>>>;
+
+const PATH_PREFIX: &str = "/hls/";
+const PLAYLIST_NAME: &str = "index.m3u8";
+
+/// Segments kept beyond the playlist, for clients that fetch a playlist
+/// just before it moves on
+const EXTRA_SEGMENTS: usize = 2;
+
+struct Segment {
+    sequence: u64,
+    duration: Duration,
+    data: Arc<Vec<u8>>,
+}
+
+// The segment being written
+struct OpenSegment {
+    sequence: u64,
+    start: Duration,
+    data: Vec<u8>,
+}
+
+struct SegmentRing {
+    muxer: TsMuxer,
+    open: Option<OpenSegment>,
+    segments: VecDeque<Segment>,
+    next_sequence: u64,
+    // Presentation time of the last frame
+    last_pts: Option<Duration>,
+}
+
+pub struct HlsStream {
+    segment_duration: Duration,
+    playlist_length: usize,
+    ring: Mutex<SegmentRing>,
+    // Woken by requests; weak since the encoder's subscriber owns the stream
+    encoder: Weak<Encoder>,
+}
+
+impl HlsStream {
+    pub fn new(config: &HlsConfig, encoder: &Arc<Encoder>) -> Self {
+        Self {
+            encoder: Arc::downgrade(encoder),
+            segment_duration: Duration::from_secs(config.segment_duration.max(1) as u64),
+            playlist_length: config.playlist_length.max(1) as usize,
+            ring: Mutex::new(SegmentRing {
+                muxer: TsMuxer::new(),
+                open: None,
+                segments: VecDeque::new(),
+                next_sequence: 0,
+                last_pts: None,
+            }),
+        }
+    }
+
+    /// Add an encoded frame, starting a new segment at the first keyframe
+    /// once the current one is long enough
+    pub fn push(&self, frame: &EncodedFrame) {
+        let data = match frame.buffer.map_readable() {
+            Ok(data) => data,
+            Err(_) => return,
+        };
+
+        let mut ring = self.ring.lock().unwrap();
+        let ring = &mut *ring;
+
+        // The encoder was restarted and its timestamps with it; what is
+        // buffered is too old to play anyway
+        if ring.last_pts.is_some_and(|last| frame.pts < last) {
+            ring.open = None;
+            ring.segments.clear();
+        }
+        ring.last_pts = Some(frame.pts);
+
+        let cut = frame.keyframe
+            && ring
+                .open
+                .as_ref()
+                .is_none_or(|open| frame.pts.saturating_sub(open.start) >= self.segment_duration);
+        if cut {
+            if let Some(open) = ring.open.take() {
+                ring.segments.push_back(Segment {
+                    sequence: open.sequence,
+                    duration: frame.pts.saturating_sub(open.start),
+                    data: Arc::new(open.data),
+                });
+                while ring.segments.len() > self.playlist_length + EXTRA_SEGMENTS {
+                    ring.segments.pop_front();
+                }
+            }
+
+            let mut data = Vec::new();
+            ring.muxer.write_tables(&mut data);
+            ring.open = Some(OpenSegment {
+                sequence: ring.next_sequence,
+                start: frame.pts,
+                data,
+            });
+            ring.next_sequence += 1;
+        }
+
+        // Segments must start with a keyframe, so anything before the
+        // first one is dropped
+        if let Some(open) = &mut ring.open {
+            let time_90khz = frame.pts.as_nanos() as u64 * 9 / 100_000;
+            ring.muxer.write_frame(&mut open.data, &data, time_90khz, frame.keyframe);
+        }
+    }
+
+    // Live playlist of the most recent complete segments
+    fn playlist(&self) -> Option<String> {
+        let ring = self.ring.lock().unwrap();
+        let skip = ring.segments.len().saturating_sub(self.playlist_length);
+        let segments: Vec<&Segment> = ring.segments.iter().skip(skip).collect();
+        let first = segments.first()?;
+
+        let target_duration = segments
+            .iter()
+            .map(|segment| segment.duration.as_secs_f64().ceil() as u64)
+            .max()
+            .unwrap_or(0)
+            .max(self.segment_duration.as_secs());
+
+        let mut playlist = format!(
+            "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:{}\n#EXT-X-MEDIA-SEQUENCE:{}\n",
+            target_duration, first.sequence
+        );
+        for segment in segments {
+            playlist.push_str(&format!(
+                "#EXTINF:{:.3},\nsegment{}.ts\n",
+                segment.duration.as_secs_f64(),
+                segment.sequence
+            ));
+        }
+        Some(playlist)
+    }
+
+    fn segment(&self, sequence: u64) -> Option<Arc<Vec<u8>>> {
+        let ring = self.ring.lock().unwrap();
+        ring.segments
+            .iter()
+            .find(|segment| segment.sequence == sequence)
+            .map(|segment| segment.data.clone())
+    }
+}
+
+/// Serve /hls/display<N>/index.m3u8 and its segments on the HTTP server
+pub fn register(http_server: &HttpServer, streams: HlsStreams) {
+    http_server.route(PATH_PREFIX, move |request| handle(request, &streams));
+}
+
+fn handle(request: Request, streams: &HlsStreams) {
+    let path = request.url().split('?').next().unwrap_or("").to_string();
+
+    let (stream, file) = match path
+        .strip_prefix(PATH_PREFIX)
+        .and_then(|rest| rest.strip_prefix("display"))
+        .and_then(|rest| rest.split_once('/'))
+        .and_then(|(slot, file)| Some((slot.parse::<usize>().ok()?, file)))
+    {
+        Some((slot, file)) => (streams.lock().unwrap().get(&slot).cloned(), file),
+        None => (None, ""),
+    };
+    let stream = match stream {
+        Some(stream) => stream,
+        None => {
+            let _ = request.respond(Response::empty(404));
+            return;
+        }
+    };
+
+    debug!("HLS {} requested by {}", path, request.remote_addr().map(|a| a.to_string()).unwrap_or_default());
+    if let Some(encoder) = stream.encoder.upgrade() {
+        encoder.wake();
+    }
+
+    let response = if file == PLAYLIST_NAME {
+        match stream.playlist() {
+            Some(playlist) => Response::from_data(playlist.into_bytes())
+                .with_header(http::header("Content-Type", "application/vnd.apple.mpegurl"))
+                .with_header(http::header("Cache-Control", "no-cache, no-store")),
+            // The first segment isn't complete yet
+            None => Response::from_data(Vec::new()).with_status_code(503),
+        }
+    } else {
+        let segment = file
+            .strip_prefix("segment")
+            .and_then(|name| name.strip_suffix(".ts"))
+            .and_then(|sequence| sequence.parse::<u64>().ok())
+            .and_then(|sequence| stream.segment(sequence));
+        match segment {
+            Some(data) => Response::from_data(data.as_slice())
+                .with_header(http::header("Content-Type", "video/mp2t")),
+            None => Response::from_data(Vec::new()).with_status_code(404),
+        }
+    };
+
+    // Players are often served from a different origin
+    let _ = request.respond(response.with_header(http::header("Access-Control-Allow-Origin", "*")));
+}
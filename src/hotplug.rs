@@ -0,0 +1,522 @@
+use anyhow::{Context, Result};
+use log::{error, info};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::activity::ActivityDetector;
+use crate::capture::{self, CaptureSettings, CaptureTarget, DisplayMetadata, FrameOutputs, WINDOW_INITIAL_SIZE};
+use crate::clip::{ClipBuffer, ClipBuffers};
+use crate::config::{Config, StreamConfig};
+use crate::encoder::{Encoder, Encoders};
+use crate::hls::{HlsStream, HlsStreams};
+use crate::mosaic;
+use crate::native;
+use crate::onvif::PublishedStreams;
+use crate::placeholder;
+use crate::publish::Publisher;
+use crate::record;
+use crate::rtsp::{RtspMount, RtspServer};
+use crate::snapshot::{LatestFrame, Snapshots};
+
+const MOSAIC_PATH: &str = "/mosaic";
+const DESKTOP_PATH: &str = "/desktop";
+
+/// Longest time between keyframes of the shared encoders, so new viewers
+/// don't wait long for a picture
+const DEFAULT_KEYFRAME_INTERVAL: Duration = Duration::from_secs(2);
+
+// A display that is currently being captured and streamed
+struct ActiveStream {
+    slot: usize,
+    display: DisplayMetadata,
+    running: Arc<AtomicBool>,
+    // None when the media captures the display itself
+    handle: Option<thread::JoinHandle<()>>,
+    // Encoder feeding the outputs that aren't served by the RTSP server
+    encoder: Option<Arc<Encoder>>,
+    // Pushes to external servers and the recording, fed by the encoder
+    publishers: Vec<Publisher>,
+}
+
+// A window capture; windows aren't hotplug-tracked, they run until shutdown
+struct WindowStream {
+    path: String,
+    running: Arc<AtomicBool>,
+    handle: thread::JoinHandle<()>,
+}
+
+/// Keeps one RTSP mount per connected display as displays come and go.
+///
+/// Each display gets a stable slot (`/display<slot>`) remembered by its
+/// position on the desktop, so a monitor that is unplugged and plugged back
+/// in gets its old path back and other mounts never shuffle.
+pub struct StreamManager<'a> {
+    config: &'a Config,
+    // None when only publishing and HTTP outputs are wanted
+    rtsp_server: Option<&'a RtspServer>,
+    streams: Vec<ActiveStream>,
+    // Desktop position of the display each slot was last assigned to
+    slot_positions: Vec<(i32, i32)>,
+    // Composite stream tiles fed by each slot's capture thread
+    composite_inputs: HashMap<usize, Vec<RtspMount>>,
+    windows: Vec<WindowStream>,
+    // Display streams currently served, shared with the ONVIF service
+    published: PublishedStreams,
+    // Latest frame of each display, shared with the snapshot and MJPEG services
+    snapshots: Snapshots,
+    // HLS output of each display, shared with the HTTP server
+    hls_streams: HlsStreams,
+    // Encoder of each display, shared with the WHEP service
+    encoders: Encoders,
+    // Recent video of each display, shared with the clip trigger
+    clip_buffers: ClipBuffers,
+}
+
+impl<'a> StreamManager<'a> {
+    pub fn new(config: &'a Config, rtsp_server: Option<&'a RtspServer>) -> Self {
+        Self {
+            config,
+            rtsp_server,
+            streams: Vec::new(),
+            slot_positions: Vec::new(),
+            composite_inputs: HashMap::new(),
+            windows: Vec::new(),
+            published: PublishedStreams::default(),
+            snapshots: Snapshots::default(),
+            hls_streams: HlsStreams::default(),
+            encoders: Encoders::default(),
+            clip_buffers: ClipBuffers::default(),
+        }
+    }
+
+    /// The display streams being served, kept up to date as displays come and go
+    pub fn published_streams(&self) -> PublishedStreams {
+        self.published.clone()
+    }
+
+    /// The latest frame of each display being captured
+    pub fn snapshots(&self) -> Snapshots {
+        self.snapshots.clone()
+    }
+
+    /// The HLS output of each display being captured
+    pub fn hls_streams(&self) -> HlsStreams {
+        self.hls_streams.clone()
+    }
+
+    /// The encoder of each display that has outputs needing one
+    pub fn encoders(&self) -> Encoders {
+        self.encoders.clone()
+    }
+
+    /// The buffered recent video of each display, for saving clips
+    pub fn clip_buffers(&self) -> ClipBuffers {
+        self.clip_buffers.clone()
+    }
+
+    /// Serve the /mosaic stream for the given displays. Must be called
+    /// before the first `sync` so the capture threads feed the mosaic too.
+    ///
+    /// The mosaic keeps its tiles for the lifetime of the process: a display
+    /// that is unplugged shows the placeholder in its tile, and displays
+    /// connected later are not added.
+    pub fn add_mosaic(&mut self, displays: &[DisplayMetadata]) -> Result<()> {
+        let mosaic = &self.config.mosaic;
+
+        let mut inputs = Vec::new();
+        for display in displays {
+            let slot = self.slot_for(display);
+            if mosaic.displays.is_empty() || mosaic.displays.contains(&slot) {
+                inputs.push((slot, display.clone()));
+            }
+        }
+
+        if inputs.is_empty() {
+            anyhow::bail!("No displays selected for the mosaic stream");
+        }
+
+        let tiles = mosaic::layout(&inputs, mosaic);
+        self.add_composite(MOSAIC_PATH, &inputs, &tiles, mosaic.width, mosaic.height, 0)
+    }
+
+    /// Serve the /desktop stream: the bounding box of all displays at native
+    /// size, with the gaps between them filled with the background colour.
+    /// Must be called before the first `sync`, like `add_mosaic`.
+    pub fn add_desktop(&mut self, displays: &[DisplayMetadata]) -> Result<()> {
+        let inputs: Vec<_> = displays
+            .iter()
+            .map(|display| (self.slot_for(display), display.clone()))
+            .collect();
+
+        let (_, _, width, height) = mosaic::bounding_box(displays.iter());
+        let tiles = mosaic::desktop_layout(&inputs, width, height);
+        let background = placeholder::parse_color(&self.config.desktop.background);
+        self.add_composite(DESKTOP_PATH, &inputs, &tiles, width, height, background)
+    }
+
+    fn add_composite(
+        &mut self,
+        path: &str,
+        inputs: &[(usize, DisplayMetadata)],
+        tiles: &[mosaic::Tile],
+        width: u32,
+        height: u32,
+        background: u32,
+    ) -> Result<()> {
+        let rtsp_server = self.rtsp_server.context("Composite streams need the built-in RTSP server")?;
+        let frame_rate = self.config.frame_rate;
+
+        // Tiles receive the frames of their display's own stream, converted
+        // with that stream's colorimetry
+        let mut sources = mosaic::sources(tiles, inputs);
+        for (source, tile) in sources.iter_mut().zip(tiles) {
+            source.colorimetry = self.config.stream_config(tile.slot).colorimetry();
+        }
+
+        let mounts = rtsp_server.add_composite_stream(
+            path,
+            &mosaic::launch_string(tiles, width, height, frame_rate, background),
+            sources,
+            frame_rate,
+        )?;
+
+        for (tile, mount) in tiles.iter().zip(mounts) {
+            self.composite_inputs.entry(tile.slot).or_default().push(mount);
+        }
+
+        info!("Started {}x{} stream of {} displays at rtsp://{}:{}{}",
+              width, height, inputs.len(), self.config.server_address, self.config.rtsp_port, path);
+
+        Ok(())
+    }
+
+    /// Serve a /window/<name> stream for every configured window. A window
+    /// that can't be found yet shows the placeholder until it appears.
+    pub fn add_windows(&mut self) -> Result<()> {
+        for window in &self.config.windows {
+            if !cfg!(target_os = "linux") {
+                anyhow::bail!("Window capture is only supported on Linux");
+            }
+            let rtsp_server = self.rtsp_server.context("Window streams need the built-in RTSP server")?;
+
+            let path = format!("/window/{}", window.name);
+            let name = format!("Window {}", window.name);
+            let (width, height) = WINDOW_INITIAL_SIZE;
+            let stream_config = self.config.window_stream_config(&window.name);
+            let rtsp_mount = rtsp_server.add_stream(
+                &path,
+                &name,
+                width,
+                height,
+                self.config.frame_rate,
+                &stream_config,
+            )?;
+
+            let running = Arc::new(AtomicBool::new(true));
+            let handle = capture::start_capture_thread(
+                name,
+                CaptureTarget::Window(window.clone()),
+                FrameOutputs {
+                    rtsp_mounts: vec![rtsp_mount],
+                    latest_frame: None,
+                    activity: None,
+                },
+                CaptureSettings::from_config(self.config, &stream_config),
+                running.clone(),
+            )?;
+
+            info!("Started streaming window {} at rtsp://{}:{}{}",
+                  window.name, self.config.server_address, self.config.rtsp_port, path);
+
+            self.windows.push(WindowStream { path, running, handle });
+        }
+
+        Ok(())
+    }
+
+    /// Start streams for new displays and stop streams for disconnected ones.
+    /// A display that fails to start doesn't keep the others from starting;
+    /// it is retried on the next sync, and the error lists every such display.
+    pub fn sync(&mut self, displays: &[DisplayMetadata]) -> Result<()> {
+        // Stop streams whose display is gone
+        let (kept, removed): (Vec<_>, Vec<_>) = std::mem::take(&mut self.streams)
+            .into_iter()
+            .partition(|stream| displays.iter().any(|d| same_position(d, &stream.display)));
+        self.streams = kept;
+
+        for stream in removed {
+            info!("Display {} disconnected, stopping stream", stream.slot);
+            self.stop_stream(stream);
+        }
+
+        // Start streams for displays we aren't capturing yet
+        let mut failed = Vec::new();
+        for display in displays {
+            if self.streams.iter().any(|s| same_position(display, &s.display)) {
+                continue;
+            }
+
+            let slot = self.slot_for(display);
+            if !self.config.displays.is_empty() && !self.config.displays.contains(&slot) {
+                continue;
+            }
+
+            if let Err(e) = self.start_stream(slot, display) {
+                error!("Failed to start stream for display {}: {:#}", slot, e);
+                failed.push(slot.to_string());
+            }
+        }
+
+        if !failed.is_empty() {
+            anyhow::bail!("Failed to start display(s) {}", failed.join(", "));
+        }
+        Ok(())
+    }
+
+    /// Stop every stream and wait for the capture threads to finish
+    pub fn shutdown(mut self) {
+        for stream in std::mem::take(&mut self.streams) {
+            self.stop_stream(stream);
+        }
+
+        for window in std::mem::take(&mut self.windows) {
+            window.running.store(false, Ordering::SeqCst);
+            if let Err(e) = window.handle.join() {
+                error!("Error joining capture thread: {:?}", e);
+            }
+            if let Some(rtsp_server) = self.rtsp_server {
+                rtsp_server.remove_stream(&window.path);
+            }
+        }
+    }
+
+    fn start_stream(&mut self, slot: usize, display: &DisplayMetadata) -> Result<()> {
+        let stream_path = stream_path(slot);
+        let name = format!("Display {}", slot);
+        let stream_config = self.config.stream_config(slot);
+
+        let mut rtsp_mounts = Vec::new();
+        if let Some(rtsp_server) = self.rtsp_server {
+            match native::source_launch(self.config, slot, display) {
+                Some(source_launch) => {
+                    rtsp_server.add_native_stream(&stream_path, &name, &source_launch, &stream_config)?;
+                }
+                None => {
+                    rtsp_mounts.push(rtsp_server.add_stream(
+                        &stream_path,
+                        &name,
+                        display.width,
+                        display.height,
+                        self.config.frame_rate,
+                        &stream_config,
+                    )?);
+                }
+            }
+        }
+
+        if let Some(composite_inputs) = self.composite_inputs.get(&slot) {
+            rtsp_mounts.extend(composite_inputs.iter().cloned());
+        }
+
+        // Leave nothing of a display that failed to start behind, so the
+        // next sync starts it from scratch
+        let result = self.start_outputs(slot, display, rtsp_mounts, &stream_config);
+        if result.is_err() {
+            self.unregister(slot);
+            if let Some(rtsp_server) = self.rtsp_server {
+                rtsp_server.remove_stream(&stream_path);
+            }
+        }
+        result
+    }
+
+    // Start the encoder and the capture thread of a display whose RTSP
+    // stream is set up
+    fn start_outputs(
+        &mut self,
+        slot: usize,
+        display: &DisplayMetadata,
+        mut rtsp_mounts: Vec<RtspMount>,
+        stream_config: &StreamConfig,
+    ) -> Result<()> {
+        let latest_frame = if self.config.snapshot.enabled || self.config.mjpeg.enabled {
+            let latest_frame = Arc::new(LatestFrame::default());
+            self.snapshots.lock().unwrap().insert(slot, latest_frame.clone());
+            Some(latest_frame)
+        } else {
+            None
+        };
+        let encoder = self.start_encoder(slot, display, stream_config)?;
+        let mut publishers = Vec::new();
+        if let Some(encoder) = &encoder {
+            rtsp_mounts.push(encoder.mount());
+
+            // A bad target shouldn't take the display's other outputs down
+            for url in &stream_config.publish {
+                match Publisher::start(encoder, url, slot) {
+                    Ok(publisher) => publishers.push(publisher),
+                    Err(e) => error!("Failed to publish display {}: {:#}", slot, e),
+                }
+            }
+            if self.records(slot) {
+                match record::start(encoder, &self.config.record, slot) {
+                    Ok(recorder) => publishers.push(recorder),
+                    Err(e) => error!("Failed to record display {}: {:#}", slot, e),
+                }
+            }
+        }
+        let activity = if stream_config.activity.enabled {
+            Some(Arc::new(ActivityDetector::new(slot, &stream_config.activity)))
+        } else {
+            None
+        };
+        let outputs = FrameOutputs {
+            rtsp_mounts,
+            latest_frame,
+            activity,
+        };
+
+        // With a native source the capture thread only runs to feed the
+        // composite streams and HTTP outputs; its settings fall back to scrap
+        let running = Arc::new(AtomicBool::new(true));
+        let handle = if outputs.is_empty() {
+            None
+        } else {
+            Some(capture::start_capture_thread(
+                format!("display {}", slot),
+                CaptureTarget::Display(display.clone()),
+                outputs,
+                CaptureSettings::from_config(self.config, stream_config),
+                running.clone(),
+            )?)
+        };
+
+        if self.rtsp_server.is_some() {
+            info!("Started streaming display {} at rtsp://{}:{}{}",
+                  slot, self.config.server_address, self.config.rtsp_port, stream_path(slot));
+            self.published.lock().unwrap().push((slot, display.clone()));
+        } else {
+            info!("Started capturing display {}", slot);
+        }
+
+        self.streams.push(ActiveStream {
+            slot,
+            display: display.clone(),
+            running,
+            handle,
+            encoder,
+            publishers,
+        });
+
+        Ok(())
+    }
+
+    fn stop_stream(&self, stream: ActiveStream) {
+        self.unregister(stream.slot);
+        stream.running.store(false, Ordering::SeqCst);
+        if let Some(Err(e)) = stream.handle.map(|handle| handle.join()) {
+            error!("Error joining capture thread: {:?}", e);
+        }
+        drop(stream.publishers);
+        drop(stream.encoder);
+        if let Some(rtsp_server) = self.rtsp_server {
+            rtsp_server.remove_stream(&stream_path(stream.slot));
+        }
+    }
+
+    // Withdraw a display from the HTTP outputs and the ONVIF profiles
+    fn unregister(&self, slot: usize) {
+        self.published.lock().unwrap().retain(|(published, _)| *published != slot);
+        self.snapshots.lock().unwrap().remove(&slot);
+        self.hls_streams.lock().unwrap().remove(&slot);
+        self.encoders.lock().unwrap().remove(&slot);
+        self.clip_buffers.lock().unwrap().remove(&slot);
+    }
+
+    // Start the encoder of a display if any of its outputs need one
+    fn start_encoder(
+        &self,
+        slot: usize,
+        display: &DisplayMetadata,
+        stream_config: &StreamConfig,
+    ) -> Result<Option<Arc<Encoder>>> {
+        let hls = &self.config.hls;
+        // Viewers start and stop the encoder, the other outputs need it all the time
+        let always_on = !stream_config.publish.is_empty() || self.records(slot) || self.config.clips.enabled;
+        if !always_on && !hls.enabled && !self.config.whep.enabled {
+            return Ok(None);
+        }
+
+        // HLS can only cut segments at keyframes
+        let keyframe_interval = if hls.enabled {
+            Duration::from_secs(hls.segment_duration.max(1) as u64)
+        } else {
+            DEFAULT_KEYFRAME_INTERVAL
+        };
+        let encoder = Encoder::start(
+            &format!("Display {}", slot),
+            display.width,
+            display.height,
+            self.config,
+            stream_config,
+            keyframe_interval,
+            always_on,
+        )?;
+        let encoder = Arc::new(encoder);
+        self.encoders.lock().unwrap().insert(slot, encoder.clone());
+
+        if hls.enabled {
+            let hls_stream = Arc::new(HlsStream::new(hls, &encoder));
+            let subscriber = hls_stream.clone();
+            encoder.subscribe(move |frame| {
+                subscriber.push(frame);
+                true
+            });
+            self.hls_streams.lock().unwrap().insert(slot, hls_stream);
+        }
+
+        if self.config.clips.enabled {
+            let clip_buffer = Arc::new(ClipBuffer::new(slot, &self.config.clips));
+            let subscriber = clip_buffer.clone();
+            encoder.subscribe(move |frame| {
+                subscriber.push(frame);
+                true
+            });
+            self.clip_buffers.lock().unwrap().insert(slot, clip_buffer);
+        }
+
+        Ok(Some(encoder))
+    }
+
+    fn records(&self, slot: usize) -> bool {
+        let record = &self.config.record;
+        record.enabled && (record.displays.is_empty() || record.displays.contains(&slot))
+    }
+
+    // Reuse the slot a display had before it was unplugged, otherwise
+    // allocate a new one
+    fn slot_for(&mut self, display: &DisplayMetadata) -> usize {
+        let free_slot = self.slot_positions.iter().enumerate().position(|(slot, position)| {
+            *position == (display.x, display.y) && !self.streams.iter().any(|s| s.slot == slot)
+        });
+
+        match free_slot {
+            Some(slot) => slot,
+            None => {
+                self.slot_positions.push((display.x, display.y));
+                self.slot_positions.len() - 1
+            }
+        }
+    }
+}
+
+fn same_position(a: &DisplayMetadata, b: &DisplayMetadata) -> bool {
+    a.x == b.x && a.y == b.y
+}
+
+fn stream_path(slot: usize) -> String {
+    format!("/display{}", slot)
+}
@@ -0,0 +1,327 @@
+// Shared HTTP server for everything that isn't RTSP (ONVIF, snapshots, ...)
+//
+// Handlers are registered under a path prefix and get the whole request;
+// each request runs on its own thread so long-lived responses (streams)
+// don't hold up the others. When ONVIF users are configured, every route
+// but the ONVIF services (which check WS-UsernameToken themselves) needs
+// HTTP Basic credentials of one of them.
+
+use anyhow::{Context, Result};
+use base64::Engine;
+use log::{error, info, warn};
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+use tiny_http::{Header, Request, Response, Server};
+
+use crate::config::OnvifUser;
+
+/// Largest request body we read
+const MAX_BODY_SIZE: u64 = 1024 * 1024;
+
+const REALM: &str = "display_rtsp_streamer";
+
+type Handler = Arc<dyn Fn(Request) + Send + Sync>;
+
+struct Route {
+    prefix: String,
+    handler: Handler,
+    // Reachable without HTTP credentials
+    public: bool,
+}
+
+pub struct HttpServer {
+    server: Arc<Server>,
+    routes: Arc<RwLock<Vec<Route>>>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl HttpServer {
+    /// Serve on `port`; with `users`, routes need HTTP Basic credentials of
+    /// one of them
+    pub fn start(port: u16, users: Vec<OnvifUser>) -> Result<Self> {
+        let server = Server::http(("0.0.0.0", port))
+            .map_err(|e| anyhow::anyhow!("{}", e))
+            .with_context(|| format!("Failed to start HTTP server on port {}", port))?;
+        let server = Arc::new(server);
+        let routes: Arc<RwLock<Vec<Route>>> = Arc::new(RwLock::new(Vec::new()));
+
+        let handle = {
+            let server = server.clone();
+            let routes = routes.clone();
+            thread::spawn(move || {
+                for request in server.incoming_requests() {
+                    dispatch(&routes, &users, request);
+                }
+            })
+        };
+
+        info!("HTTP server started on port {}", port);
+
+        Ok(Self {
+            server,
+            routes,
+            handle: Some(handle),
+        })
+    }
+
+    /// Handle requests whose path starts with `prefix`; the longest matching
+    /// prefix wins. The handler must respond to the request.
+    pub fn route(&self, prefix: &str, handler: impl Fn(Request) + Send + Sync + 'static) {
+        self.add_route(prefix, Arc::new(handler), false);
+    }
+
+    /// Like `route`, but without HTTP authentication; the handler checks
+    /// credentials itself
+    pub fn route_public(&self, prefix: &str, handler: impl Fn(Request) + Send + Sync + 'static) {
+        self.add_route(prefix, Arc::new(handler), true);
+    }
+
+    fn add_route(&self, prefix: &str, handler: Handler, public: bool) {
+        let mut routes = self.routes.write().unwrap();
+        routes.push(Route {
+            prefix: prefix.to_string(),
+            handler,
+            public,
+        });
+        routes.sort_by_key(|route| std::cmp::Reverse(route.prefix.len()));
+    }
+}
+
+impl Drop for HttpServer {
+    fn drop(&mut self) {
+        self.server.unblock();
+        if let Some(handle) = self.handle.take() {
+            if let Err(e) = handle.join() {
+                error!("Error joining HTTP server thread: {:?}", e);
+            }
+        }
+    }
+}
+
+fn dispatch(routes: &RwLock<Vec<Route>>, users: &[OnvifUser], request: Request) {
+    let path = request.url().split('?').next().unwrap_or("").to_string();
+    let route = routes
+        .read()
+        .unwrap()
+        .iter()
+        .find(|route| path.starts_with(route.prefix.as_str()))
+        .map(|route| (route.handler.clone(), route.public));
+
+    match route {
+        Some((_, false)) if !users.is_empty() && !authorized(&request, users) => {
+            warn!(
+                "Unauthorized HTTP request for {} from {}",
+                path,
+                request.remote_addr().map(|a| a.to_string()).unwrap_or_default()
+            );
+            let response = Response::empty(401)
+                .with_header(header("WWW-Authenticate", &format!("Basic realm=\"{}\"", REALM)));
+            let _ = request.respond(response);
+        }
+        Some((handler, _)) => {
+            thread::spawn(move || handler(request));
+        }
+        None => {
+            let _ = request.respond(Response::empty(404));
+        }
+    }
+}
+
+fn authorized(request: &Request, users: &[OnvifUser]) -> bool {
+    request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv("Authorization"))
+        .is_some_and(|header| basic_credentials_valid(header.value.as_str(), users))
+}
+
+// Whether an Authorization header value holds Basic credentials of one of `users`
+fn basic_credentials_valid(value: &str, users: &[OnvifUser]) -> bool {
+    let credentials = value
+        .trim()
+        .split_once(' ')
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("Basic"))
+        .and_then(|(_, encoded)| base64::engine::general_purpose::STANDARD.decode(encoded.trim()).ok())
+        .and_then(|decoded| String::from_utf8(decoded).ok());
+    let (username, password) = match credentials.as_deref().and_then(|c| c.split_once(':')) {
+        Some(credentials) => credentials,
+        None => return false,
+    };
+
+    // Every user is compared so the time taken doesn't tell which ones exist
+    users.iter().fold(false, |found, user| {
+        let matches = constant_time_eq(user.username.as_bytes(), username.as_bytes())
+            & constant_time_eq(user.password.as_bytes(), password.as_bytes());
+        found | matches
+    })
+}
+
+/// Authorization header value with the Basic credentials of `user`
+pub fn basic_authorization(user: &OnvifUser) -> String {
+    let credentials = format!("{}:{}", user.username, user.password);
+    format!("Basic {}", base64::engine::general_purpose::STANDARD.encode(credentials))
+}
+
+/// Compare secrets in a time that only depends on their lengths
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Read the request body as text, up to MAX_BODY_SIZE
+pub fn read_body(request: &mut Request) -> Result<String> {
+    let mut body = String::new();
+    request
+        .as_reader()
+        .take(MAX_BODY_SIZE)
+        .read_to_string(&mut body)
+        .context("Failed to read request body")?;
+    Ok(body)
+}
+
+pub fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).expect("Invalid HTTP header")
+}
+
+/// Value of query parameter `name` in a request URL, undecoded
+pub fn query_param<'a>(url: &'a str, name: &str) -> Option<&'a str> {
+    let (_, query) = url.split_once('?')?;
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+/// POST `body` to a plain http:// URL; returns the status code and the
+/// response body
+pub fn post(url: &str, content_type: &str, body: &[u8], timeout: Duration) -> Result<(u16, String)> {
+    post_with_headers(url, content_type, &[], body, timeout)
+}
+
+/// Like `post`, sending extra `headers` with the request
+pub fn post_with_headers(
+    url: &str,
+    content_type: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+    timeout: Duration,
+) -> Result<(u16, String)> {
+    let rest = url
+        .strip_prefix("http://")
+        .with_context(|| format!("Only http:// URLs are supported, not {}", url))?;
+    let (host, path) = match rest.find('/') {
+        Some(index) => rest.split_at(index),
+        None => (rest, "/"),
+    };
+    // Default port, taking care of IPv6 literals like [::1]
+    let address = if host.rsplit_once(':').is_some_and(|(_, port)| !port.ends_with(']')) {
+        host.to_string()
+    } else {
+        format!("{}:80", host)
+    };
+    let address = address
+        .to_socket_addrs()
+        .with_context(|| format!("Failed to resolve {}", host))?
+        .next()
+        .with_context(|| format!("No address for {}", host))?;
+
+    let mut stream = TcpStream::connect_timeout(&address, timeout)
+        .with_context(|| format!("Failed to connect to {}", host))?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    let extra_headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{}: {}\r\n", name, value))
+        .collect();
+    write!(
+        stream,
+        "POST {} HTTP/1.0\r\nHost: {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n",
+        path,
+        host,
+        content_type,
+        body.len(),
+        extra_headers
+    )
+    .and_then(|_| stream.write_all(body))
+    .with_context(|| format!("Failed to send request to {}", url))?;
+
+    let mut response = Vec::new();
+    stream
+        .take(MAX_BODY_SIZE)
+        .read_to_end(&mut response)
+        .with_context(|| format!("Failed to read response from {}", url))?;
+    let response = String::from_utf8_lossy(&response);
+    let (head, body) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+    let status = head
+        .split(' ')
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .with_context(|| format!("Invalid HTTP response from {}", url))?;
+
+    Ok((status, body.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn users() -> Vec<OnvifUser> {
+        vec![
+            OnvifUser {
+                username: "admin".to_string(),
+                password: "secret".to_string(),
+            },
+            OnvifUser {
+                username: "viewer".to_string(),
+                password: "hunter2".to_string(),
+            },
+        ]
+    }
+
+    fn basic(credentials: &str) -> String {
+        format!("Basic {}", base64::engine::general_purpose::STANDARD.encode(credentials))
+    }
+
+    #[test]
+    fn basic_credentials_of_any_user_are_accepted() {
+        assert!(basic_credentials_valid(&basic("admin:secret"), &users()));
+        assert!(basic_credentials_valid(&basic("viewer:hunter2"), &users()));
+        assert!(basic_credentials_valid(&basic("admin:secret").replace("Basic", "basic"), &users()));
+    }
+
+    #[test]
+    fn wrong_or_malformed_credentials_are_rejected() {
+        assert!(!basic_credentials_valid(&basic("admin:hunter2"), &users()));
+        assert!(!basic_credentials_valid(&basic("nobody:secret"), &users()));
+        assert!(!basic_credentials_valid(&basic("admin"), &users()));
+        assert!(!basic_credentials_valid("Basic !!!", &users()));
+        assert!(!basic_credentials_valid("Bearer c2VjcmV0", &users()));
+        assert!(!basic_credentials_valid("", &users()));
+    }
+
+    #[test]
+    fn basic_authorization_round_trips() {
+        for user in users() {
+            assert!(basic_credentials_valid(&basic_authorization(&user), &users()));
+        }
+    }
+
+    #[test]
+    fn constant_time_eq_compares_contents_and_length() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+    #[test]
+    fn query_params_are_found() {
+        assert_eq!(query_param("/snapshot/display0.jpg?width=320&quality=50", "quality"), Some("50"));
+        assert_eq!(query_param("/snapshot/display0.jpg?width=320", "quality"), None);
+        assert_eq!(query_param("/snapshot/display0.jpg", "width"), None);
+    }
+}
@@ -5,10 +5,37 @@ use std::path::PathBuf;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
+mod activity;
 mod capture;
+mod clip;
 mod config;
+mod convert;
+mod damage;
+mod discovery;
+mod encoder;
+mod hls;
+mod hotplug;
+mod http;
+mod mjpeg;
+mod mosaic;
+mod native;
+mod onvif;
+mod overlay;
+mod pacing;
+mod placeholder;
+#[cfg(target_os = "linux")]
+mod portal;
+mod publish;
+mod record;
+mod rotation;
 mod rtsp;
 mod service;
+mod snapshot;
+mod ts;
+mod watermark;
+mod whep;
+#[cfg(target_os = "linux")]
+mod x11;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -37,6 +64,18 @@ enum Commands {
     Stop,
     /// Run in foreground (not as a service)
     Run,
+    /// List ONVIF devices on the network that answer WS-Discovery probes
+    Discover {
+        /// Seconds to wait for answers
+        #[arg(long, default_value_t = 3)]
+        timeout: u64,
+    },
+    /// Save a clip of a display from the running instance (needs [clips])
+    Clip {
+        /// Display index (the N in /displayN)
+        #[arg(long, default_value_t = 0)]
+        display: usize,
+    },
 }
 
 fn main() -> Result<()> {
@@ -106,6 +145,21 @@ fn main() -> Result<()> {
                 anyhow::bail!("Not running on Windows")
             }
         }
+        Some(Commands::Discover { timeout }) => {
+            let devices = discovery::probe(std::time::Duration::from_secs(timeout))?;
+            if devices.is_empty() {
+                println!("No devices found");
+            }
+            for (address, xaddrs) in devices {
+                println!("{}: {}", address, xaddrs);
+            }
+            Ok(())
+        }
+        Some(Commands::Clip { display }) => {
+            let path = clip::request(config.http_port, display, config.onvif.users.first())?;
+            println!("Saving clip to {}", path);
+            Ok(())
+        }
         Some(Commands::Run) | None => {
             info!("Starting in foreground mode");
             run_app(config)
@@ -116,6 +170,12 @@ fn main() -> Result<()> {
 fn run_app(config: config::Config) -> Result<()> {
     info!("Starting display RTSP streamer");
     
+    // Display enumeration, the X11 backends and ximagesrc all find the X
+    // server through DISPLAY
+    if !config.x11_display.is_empty() {
+        std::env::set_var("DISPLAY", &config.x11_display);
+    }
+    
     // Set up shutdown signal
     let running = Arc::new(AtomicBool::new(true));
     let r = running.clone();
@@ -136,49 +196,116 @@ fn run_app(config: config::Config) -> Result<()> {
     info!("Found {} displays", displays.len());
     for (i, display) in displays.iter().enumerate() {
         info!(
-            "Display {}: {}x{} at {},{}", 
-            i, display.width, display.height, display.x, display.y
+            "Display {}: {} {}x{} at {},{}{}",
+            i,
+            display.name,
+            display.width,
+            display.height,
+            display.x,
+            display.y,
+            if display.is_primary { " (primary)" } else { "" }
         );
     }
 
+    // The RTSP server and the overlay refreshes need a running main loop,
+    // with or without RTSP
+    let event_loop = rtsp::EventLoop::start();
+
     // Initialize RTSP server
-    let rtsp_server = rtsp::RtspServer::new(config.rtsp_port)?;
+    let rtsp_server = if config.rtsp_enabled {
+        Some(rtsp::RtspServer::new(config.rtsp_port, config.placeholder.clone(), config.pixel_format())?)
+    } else {
+        info!("Built-in RTSP server disabled");
+        None
+    };
     
     // Start capture and streaming for each display
-    let mut capture_handles = Vec::new();
-    
-    for (i, display) in displays.iter().enumerate() {
-        let stream_path = format!("/display{}", i);
-        let rtsp_mount = rtsp_server.add_stream(&stream_path, display.width, display.height)?;
-        
-        let running_clone = running.clone();
-        let capture_handle = capture::start_capture_thread(
-            i, 
-            display.clone(),
-            rtsp_mount,
-            config.frame_rate,
-            running_clone,
-        )?;
-        
-        capture_handles.push(capture_handle);
-        
-        info!("Started streaming display {} at rtsp://{}:{}{}", 
-              i, config.server_address, config.rtsp_port, stream_path);
+    let mut stream_manager = hotplug::StreamManager::new(&config, rtsp_server.as_ref());
+    if config.mosaic.enabled {
+        stream_manager.add_mosaic(&displays)?;
+    }
+    if config.desktop.enabled {
+        stream_manager.add_desktop(&displays)?;
+    }
+    stream_manager.add_windows()?;
+    // Displays that fail to start are retried on the next hotplug check, if any
+    if let Err(e) = stream_manager.sync(&displays) {
+        error!("{}", e);
     }
 
-    // Keep running until shutdown signal
+    // HTTP services
+    let http_server = if config.onvif.enabled
+        || config.snapshot.enabled
+        || config.mjpeg.enabled
+        || config.hls.enabled
+        || config.whep.enabled
+        || config.clips.enabled
+    {
+        Some(http::HttpServer::start(config.http_port, config.onvif.users.clone())?)
+    } else {
+        None
+    };
+    if let Some(http_server) = &http_server {
+        if config.onvif.enabled {
+            onvif::register(http_server, &config, stream_manager.published_streams());
+            info!("ONVIF services at {}", discovery::device_service_url(&config));
+        }
+        if config.snapshot.enabled {
+            snapshot::register(http_server, &config, stream_manager.snapshots());
+            info!("Snapshots at http://{}:{}/snapshot/", config.server_address, config.http_port);
+        }
+        if config.mjpeg.enabled {
+            mjpeg::register(http_server, &config, stream_manager.snapshots());
+            info!("MJPEG streams at http://{}:{}/mjpeg/", config.server_address, config.http_port);
+        }
+        if config.hls.enabled {
+            hls::register(http_server, stream_manager.hls_streams());
+            info!("HLS streams at http://{}:{}/hls/", config.server_address, config.http_port);
+        }
+        if config.whep.enabled {
+            whep::register(http_server, stream_manager.encoders());
+            info!("WHEP endpoints at http://{}:{}/whep/", config.server_address, config.http_port);
+        }
+        if config.clips.enabled {
+            clip::register(http_server, stream_manager.clip_buffers());
+            info!("Clip triggers at http://{}:{}/clip/", config.server_address, config.http_port);
+        }
+    }
+
+    // Announce ourselves to NVRs; dropping the responder says goodbye
+    let discovery = if config.onvif.discovery {
+        Some(discovery::DiscoveryResponder::start(&config)?)
+    } else {
+        None
+    };
+
+    // Keep running until shutdown signal, picking up display changes on the way
+    let hotplug_interval = std::time::Duration::from_secs(config.hotplug_interval as u64);
+    let mut last_hotplug_check = std::time::Instant::now();
+    
     while running.load(Ordering::SeqCst) {
         std::thread::sleep(std::time::Duration::from_millis(100));
+        
+        if config.hotplug_interval > 0 && last_hotplug_check.elapsed() >= hotplug_interval {
+            last_hotplug_check = std::time::Instant::now();
+            match capture::get_displays() {
+                Ok(displays) => {
+                    if let Err(e) = stream_manager.sync(&displays) {
+                        error!("Failed to update streams for display changes: {}", e);
+                    }
+                }
+                Err(e) => error!("Failed to enumerate displays: {}", e),
+            }
+        }
     }
 
     info!("Shutting down");
+    drop(discovery);
+    drop(http_server);
     
-    // Wait for all capture threads to finish
-    for handle in capture_handles {
-        if let Err(e) = handle.join() {
-            error!("Error joining capture thread: {:?}", e);
-        }
-    }
+    // Stop all streams and wait for the capture threads to finish
+    stream_manager.shutdown();
+    drop(event_loop);
 
     info!("All streams stopped");
     Ok(())
@@ -0,0 +1,134 @@
+// MJPEG over HTTP for viewers that can't play RTSP or H.264
+//
+// /mjpeg/display<N> answers with a multipart/x-mixed-replace stream of JPEG
+// images taken from the display's capture thread, at most `frame_rate`
+// pictures per second. A static screen only costs the keep-alive parts.
+
+use log::{debug, info, warn};
+use std::io::Write;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tiny_http::Request;
+
+use crate::config::{Config, MjpegConfig};
+use crate::http::{self, HttpServer};
+use crate::snapshot::{self, LatestFrame, Snapshots};
+
+const PATH_PREFIX: &str = "/mjpeg/";
+const BOUNDARY: &str = "frame";
+
+/// Interval at which the last picture is repeated while the screen is static,
+/// so viewers and proxies don't consider the stream dead
+const KEEP_ALIVE: Duration = Duration::from_secs(2);
+
+/// Serve /mjpeg/display<N> on the HTTP server
+pub fn register(http_server: &HttpServer, config: &Config, snapshots: Snapshots) {
+    let settings = config.mjpeg.clone();
+    http_server.route(PATH_PREFIX, move |request| handle(request, &snapshots, &settings));
+}
+
+fn handle(request: Request, snapshots: &Snapshots, settings: &MjpegConfig) {
+    let url = request.url().to_string();
+    let path = url.split('?').next().unwrap_or("").to_string();
+
+    let stream = path
+        .strip_prefix(PATH_PREFIX)
+        .and_then(|name| name.strip_prefix("display"))
+        .and_then(|slot| slot.parse::<usize>().ok())
+        .and_then(|slot| Some((slot, snapshots.lock().unwrap().get(&slot).cloned()?)));
+    let (slot, latest_frame) = match stream {
+        Some(stream) => stream,
+        None => {
+            let _ = request.respond(tiny_http::Response::empty(404));
+            return;
+        }
+    };
+
+    // Clients may ask for a smaller picture, never a larger one than configured
+    let requested_width = http::query_param(&url, "width").and_then(|width| width.parse::<u32>().ok());
+    let width = match (requested_width, settings.max_width) {
+        (Some(width), 0) => Some(width),
+        (Some(width), max_width) => Some(width.min(max_width)),
+        (None, 0) => None,
+        (None, max_width) => Some(max_width),
+    };
+    let quality = settings.quality.clamp(1, 100);
+    let frame_interval = Duration::from_micros(1_000_000 / settings.frame_rate.max(1) as u64);
+
+    let client = request.remote_addr().map(|a| a.to_string()).unwrap_or_default();
+    info!("MJPEG client {} connected to {}", client, path);
+
+    // The response never ends, so write it ourselves instead of going
+    // through tiny_http's length/chunking logic
+    let mut writer = request.into_writer();
+    let header = format!(
+        "HTTP/1.1 200 OK\r\n\
+         Content-Type: multipart/x-mixed-replace; boundary={}\r\n\
+         Cache-Control: no-cache, no-store\r\n\
+         Pragma: no-cache\r\n\
+         Connection: close\r\n\r\n",
+        BOUNDARY
+    );
+    if writer.write_all(header.as_bytes()).and_then(|_| writer.flush()).is_err() {
+        return;
+    }
+
+    let mut sequence = 0;
+    let mut jpeg: Vec<u8> = Vec::new();
+    let mut last_sent: Option<Instant> = None;
+
+    // Stream until the client goes away or the display is disconnected
+    while is_published(snapshots, slot, &latest_frame) {
+        // Honour the frame rate limit
+        if let Some(since_last) = last_sent.map(|sent| sent.elapsed()) {
+            if since_last < frame_interval {
+                std::thread::sleep(frame_interval - since_last);
+            }
+        }
+
+        match latest_frame.wait_newer(sequence, KEEP_ALIVE) {
+            Some(frame) => {
+                sequence = frame.sequence;
+                match snapshot::encode(&frame, width, quality) {
+                    Ok(encoded) => jpeg = encoded,
+                    Err(e) => {
+                        warn!("Failed to encode MJPEG frame for {}: {:#}", path, e);
+                        continue;
+                    }
+                }
+            }
+            // Nothing new: repeat the last picture, if there is one yet
+            None if jpeg.is_empty() => continue,
+            None => {}
+        }
+
+        if let Err(e) = write_part(&mut writer, &jpeg) {
+            debug!("MJPEG client {} of {} went away: {}", client, path, e);
+            break;
+        }
+        last_sent = Some(Instant::now());
+    }
+
+    info!("MJPEG client {} disconnected from {}", client, path);
+}
+
+fn write_part(writer: &mut impl Write, jpeg: &[u8]) -> std::io::Result<()> {
+    write!(
+        writer,
+        "--{}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+        BOUNDARY,
+        jpeg.len()
+    )?;
+    writer.write_all(jpeg)?;
+    writer.write_all(b"\r\n")?;
+    writer.flush()
+}
+
+// Whether the capture thread feeding `latest_frame` is still running
+fn is_published(snapshots: &Snapshots, slot: usize, latest_frame: &Arc<LatestFrame>) -> bool {
+    snapshots
+        .lock()
+        .unwrap()
+        .get(&slot)
+        .is_some_and(|current| Arc::ptr_eq(current, latest_frame))
+}
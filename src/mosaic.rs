@@ -0,0 +1,246 @@
+// Composite streams: several displays combined into one RTSP mount
+//
+// Each display gets its own appsrc in the media, scaled into its tile and
+// fed into a compositor on top of a solid background. The capture thread of
+// a display pushes the same frames to its own mount and to its tiles.
+//
+// Used for the /mosaic stream and for the /desktop stream, which is the
+// desktop layout at its native size.
+
+use crate::capture::DisplayMetadata;
+use crate::config::MosaicConfig;
+use crate::convert::Colorimetry;
+use crate::rtsp::{MediaSource, ENCODER_LAUNCH};
+
+/// Where a display is drawn in the output frame
+#[derive(Debug, Clone)]
+pub struct Tile {
+    pub slot: usize,
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Place the displays in an output frame of `width` x `height`
+pub fn layout(displays: &[(usize, DisplayMetadata)], config: &MosaicConfig) -> Vec<Tile> {
+    match config.layout.as_str() {
+        "desktop" => desktop_layout(displays, config.width, config.height),
+        _ => grid_layout(displays, config.width, config.height),
+    }
+}
+
+// Equal-sized tiles, filled row by row
+fn grid_layout(displays: &[(usize, DisplayMetadata)], width: u32, height: u32) -> Vec<Tile> {
+    let count = displays.len().max(1) as u32;
+    let columns = (count as f64).sqrt().ceil() as u32;
+    let rows = count.div_ceil(columns);
+    let tile_width = width / columns;
+    let tile_height = height / rows;
+
+    displays
+        .iter()
+        .enumerate()
+        .map(|(i, (slot, _))| Tile {
+            slot: *slot,
+            x: ((i as u32 % columns) * tile_width) as i32,
+            y: ((i as u32 / columns) * tile_height) as i32,
+            width: tile_width,
+            height: tile_height,
+        })
+        .collect()
+}
+
+/// The displays' real arrangement on the virtual desktop, scaled to fit
+pub fn desktop_layout(displays: &[(usize, DisplayMetadata)], width: u32, height: u32) -> Vec<Tile> {
+    let (min_x, min_y, desktop_width, desktop_height) = bounding_box(displays.iter().map(|(_, d)| d));
+    if desktop_width == 0 || desktop_height == 0 {
+        return Vec::new();
+    }
+
+    let scale = (width as f64 / desktop_width as f64).min(height as f64 / desktop_height as f64);
+
+    // Center the scaled desktop in the output frame
+    let offset_x = (width as f64 - desktop_width as f64 * scale) / 2.0;
+    let offset_y = (height as f64 - desktop_height as f64 * scale) / 2.0;
+
+    displays
+        .iter()
+        .map(|(slot, display)| Tile {
+            slot: *slot,
+            x: (offset_x + (display.x - min_x) as f64 * scale).round() as i32,
+            y: (offset_y + (display.y - min_y) as f64 * scale).round() as i32,
+            width: (display.width as f64 * scale).round() as u32,
+            height: (display.height as f64 * scale).round() as u32,
+        })
+        .collect()
+}
+
+/// Bounding box of the displays as (x, y, width, height)
+pub fn bounding_box<'a>(displays: impl Iterator<Item = &'a DisplayMetadata>) -> (i32, i32, u32, u32) {
+    let mut bounds: Option<(i32, i32, i32, i32)> = None;
+    for d in displays {
+        let (right, bottom) = (d.x + d.width as i32, d.y + d.height as i32);
+        bounds = Some(match bounds {
+            None => (d.x, d.y, right, bottom),
+            Some((l, t, r, b)) => (l.min(d.x), t.min(d.y), r.max(right), b.max(bottom)),
+        });
+    }
+
+    match bounds {
+        Some((left, top, right, bottom)) => (left, top, (right - left) as u32, (bottom - top) as u32),
+        None => (0, 0, 0, 0),
+    }
+}
+
+/// Launch string for a compositor media drawing `tiles` into a `width` x `height`
+/// frame filled with `background` (0xRRGGBB)
+pub fn launch_string(tiles: &[Tile], width: u32, height: u32, frame_rate: u32, background: u32) -> String {
+    // The background goes on the last pad, below every tile
+    let background_pad = tiles.len();
+    let pads: String = tiles
+        .iter()
+        .enumerate()
+        .map(|(i, tile)| {
+            format!(
+                " sink_{}::xpos={} sink_{}::ypos={} sink_{}::zorder={}",
+                i, tile.x, i, tile.y, i, i + 1
+            )
+        })
+        .collect();
+
+    let inputs: String = tiles
+        .iter()
+        .enumerate()
+        .map(|(i, tile)| {
+            format!(
+                " appsrc name=source{} is-live=true format=time ! videoconvert ! \
+                 videoscale add-borders=true ! \
+                 video/x-raw,width={},height={},pixel-aspect-ratio=1/1 ! mix.sink_{}",
+                i, tile.width, tile.height, i
+            )
+        })
+        .collect();
+
+    format!(
+        "( compositor name=mix sink_{}::zorder=0{} ! \
+         video/x-raw,width={},height={} ! videoconvert ! \
+         video/x-raw,format=I420,colorimetry={} ! {}{} \
+         videotestsrc is-live=true pattern=solid-color foreground-color={} ! \
+         video/x-raw,width={},height={},framerate={}/1 ! mix.sink_{} )",
+        background_pad, pads, width, height, Colorimetry::default().caps_name(), ENCODER_LAUNCH, inputs,
+        0xff00_0000 | background, width, height, frame_rate, background_pad
+    )
+}
+
+/// The appsrcs `launch_string` creates for `tiles`, in the same order
+pub fn sources(tiles: &[Tile], displays: &[(usize, DisplayMetadata)]) -> Vec<MediaSource> {
+    tiles
+        .iter()
+        .zip(displays)
+        .enumerate()
+        .map(|(i, (tile, (_, display)))| MediaSource {
+            element: format!("source{}", i),
+            name: format!("Display {}", tile.slot),
+            width: display.width,
+            height: display.height,
+            colorimetry: Colorimetry::default(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn display(x: i32, y: i32, width: u32, height: u32) -> DisplayMetadata {
+        DisplayMetadata {
+            index: 0,
+            x,
+            y,
+            width,
+            height,
+            is_primary: false,
+            name: String::new(),
+        }
+    }
+
+    fn rects(tiles: &[Tile]) -> Vec<(usize, i32, i32, u32, u32)> {
+        tiles.iter().map(|t| (t.slot, t.x, t.y, t.width, t.height)).collect()
+    }
+
+    #[test]
+    fn grid_fills_rows_first() {
+        let displays: Vec<_> = (0..3).map(|slot| (slot + 1, display(0, 0, 1920, 1080))).collect();
+        assert_eq!(
+            rects(&grid_layout(&displays, 1920, 1080)),
+            vec![(1, 0, 0, 960, 540), (2, 960, 0, 960, 540), (3, 0, 540, 960, 540)]
+        );
+    }
+
+    #[test]
+    fn grid_columns_follow_the_square_root() {
+        let columns_and_rows = |count: usize| {
+            let displays: Vec<_> = (0..count).map(|slot| (slot, display(0, 0, 100, 100))).collect();
+            let tiles = grid_layout(&displays, 1200, 1200);
+            (1200 / tiles[0].width, 1200 / tiles[0].height)
+        };
+        assert_eq!(columns_and_rows(1), (1, 1));
+        assert_eq!(columns_and_rows(2), (2, 1));
+        assert_eq!(columns_and_rows(4), (2, 2));
+        assert_eq!(columns_and_rows(5), (3, 2));
+        assert_eq!(columns_and_rows(10), (4, 3));
+    }
+
+    #[test]
+    fn desktop_keeps_the_arrangement() {
+        // Two 1920x1080 side by side, scaled into half the size
+        let displays = [(0, display(0, 0, 1920, 1080)), (1, display(1920, 0, 1920, 1080))];
+        assert_eq!(
+            rects(&desktop_layout(&displays, 1920, 540)),
+            vec![(0, 0, 0, 960, 540), (1, 960, 0, 960, 540)]
+        );
+    }
+
+    #[test]
+    fn desktop_is_centered_and_starts_at_its_origin() {
+        // A portrait display left of the origin and one below it: the desktop
+        // is 1000x1000 starting at (-500, 0), drawn 2000x2000 with a
+        // horizontal margin of 500
+        let displays = [(0, display(-500, 0, 500, 1000)), (1, display(0, 500, 500, 500))];
+        assert_eq!(
+            rects(&desktop_layout(&displays, 3000, 2000)),
+            vec![(0, 500, 0, 1000, 2000), (1, 1500, 1000, 1000, 1000)]
+        );
+    }
+
+    #[test]
+    fn desktop_without_displays_is_empty() {
+        assert!(desktop_layout(&[], 1920, 1080).is_empty());
+        assert!(desktop_layout(&[(0, display(0, 0, 0, 0))], 1920, 1080).is_empty());
+    }
+
+    #[test]
+    fn bounding_box_covers_every_display() {
+        let displays = [display(-1280, 200, 1280, 1024), display(0, 0, 1920, 1080), display(1920, -300, 1080, 1920)];
+        assert_eq!(bounding_box(displays.iter()), (-1280, -300, 4280, 1920));
+        assert_eq!(bounding_box([].iter()), (0, 0, 0, 0));
+    }
+
+    #[test]
+    fn layout_is_chosen_by_config() {
+        let displays = [(0, display(0, 0, 1920, 1080)), (1, display(0, 1080, 1920, 1080))];
+        let mut config = MosaicConfig {
+            width: 1920,
+            height: 1080,
+            ..MosaicConfig::default()
+        };
+
+        config.layout = "grid".to_string();
+        assert_eq!(rects(&layout(&displays, &config))[1], (1, 960, 0, 960, 1080));
+
+        // Stacked vertically: 960x540 each, centered horizontally
+        config.layout = "desktop".to_string();
+        assert_eq!(rects(&layout(&displays, &config))[1], (1, 480, 540, 960, 540));
+    }
+}
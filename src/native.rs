@@ -0,0 +1,57 @@
+// GStreamer-native capture: the media pipeline captures the display itself
+//
+// Instead of a capture thread pushing frames through an appsrc, the media
+// starts with a GStreamer source element. Selected with capture_backend =
+// "ximagesrc" (X11) or "pipewiresrc" (Wayland, with the PipeWire remote and
+// node of a monitor shared through xdg-desktop-portal, see portal.rs).
+// Useful to compare latency and CPU use against the Rust capture, and as a
+// fallback when scrap misbehaves.
+
+use std::env;
+
+use crate::capture::DisplayMetadata;
+use crate::config::Config;
+
+/// Launch fragment of the source element capturing `display`, or None when
+/// the configured backend captures in Rust
+pub fn source_launch(config: &Config, slot: usize, display: &DisplayMetadata) -> Option<String> {
+    let source = match config.capture_backend.as_str() {
+        // endx/endy are inclusive
+        "ximagesrc" => format!(
+            "ximagesrc display-name=\"{}\" startx={} starty={} endx={} endy={} show-pointer={} use-damage=false",
+            env::var("DISPLAY").unwrap_or_default(),
+            display.x,
+            display.y,
+            display.x + display.width as i32 - 1,
+            display.y + display.height as i32 - 1,
+            config.capture_cursor
+        ),
+        // A configured node is used directly, others come from the portal;
+        // with neither pipewiresrc picks the first video source it finds
+        "pipewiresrc" => match config.pipewire_nodes.get(slot) {
+            Some(node) if !node.is_empty() => format!("pipewiresrc path={} do-timestamp=true", node),
+            _ => match portal_properties(config, slot, display) {
+                Some(properties) => format!("pipewiresrc {} do-timestamp=true", properties),
+                None => "pipewiresrc do-timestamp=true".to_string(),
+            },
+        },
+        _ => return None,
+    };
+
+    // Both sources produce frames whenever the screen changes; videorate
+    // turns that into the configured constant rate
+    Some(format!(
+        "{} ! videorate ! video/x-raw,framerate={}/1",
+        source, config.frame_rate
+    ))
+}
+
+#[cfg(target_os = "linux")]
+fn portal_properties(config: &Config, slot: usize, display: &DisplayMetadata) -> Option<String> {
+    crate::portal::source_properties(slot, display, config.capture_cursor)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn portal_properties(_config: &Config, _slot: usize, _display: &DisplayMetadata) -> Option<String> {
+    None
+}
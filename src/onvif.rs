@@ -0,0 +1,518 @@
+// Minimal ONVIF Profile S device and media service
+//
+// Just enough SOAP for NVRs that only add cameras through ONVIF: device
+// information, capabilities, one media profile per display stream and the
+// RTSP and snapshot URIs of each profile. Requests are authenticated with
+// WS-UsernameToken against the configured users; digest nonces are
+// remembered for as long as their Created time is accepted, so a captured
+// header can't be replayed.
+
+use base64::Engine;
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tiny_http::{Request, Response};
+
+use crate::capture::DisplayMetadata;
+use crate::config::{Config, OnvifUser};
+use crate::discovery::{self, element, element_text, escape};
+use crate::http::{self, HttpServer};
+
+/// Streams currently served, by display slot
+pub type PublishedStreams = Arc<Mutex<Vec<(usize, DisplayMetadata)>>>;
+
+/// Largest clock difference accepted in a UsernameToken's Created time
+const MAX_CLOCK_SKEW_SECS: i64 = 300;
+
+/// How long a digest nonce is remembered: past this, its Created time is
+/// out of the accepted window anyway
+const NONCE_LIFETIME: Duration = Duration::from_secs(2 * MAX_CLOCK_SKEW_SECS as u64);
+
+/// Serve the device and media services on the HTTP server
+pub fn register(http_server: &HttpServer, config: &Config, streams: PublishedStreams) {
+    let service = Arc::new(OnvifService {
+        config: config.clone(),
+        streams,
+        nonces: Mutex::new(NonceCache::default()),
+    });
+
+    // Authenticated in the SOAP header instead of by the HTTP server
+    for path in ["/onvif/device_service", "/onvif/media_service"] {
+        let service = service.clone();
+        http_server.route_public(path, move |request| service.handle(request));
+    }
+}
+
+struct OnvifService {
+    config: Config,
+    streams: PublishedStreams,
+    nonces: Mutex<NonceCache>,
+}
+
+// Digest nonces seen recently, with when they were first used
+#[derive(Default)]
+struct NonceCache {
+    seen: HashMap<Vec<u8>, Instant>,
+}
+
+impl NonceCache {
+    // Remember a nonce; false if it was already used
+    fn insert(&mut self, nonce: &[u8], now: Instant) -> bool {
+        self.seen.retain(|_, used| now.saturating_duration_since(*used) < NONCE_LIFETIME);
+        if self.seen.contains_key(nonce) {
+            return false;
+        }
+        self.seen.insert(nonce.to_vec(), now);
+        true
+    }
+}
+
+impl OnvifService {
+    fn handle(&self, mut request: Request) {
+        let body = match http::read_body(&mut request) {
+            Ok(body) => body,
+            Err(e) => {
+                warn!("ONVIF request failed: {:#}", e);
+                let _ = request.respond(Response::empty(400));
+                return;
+            }
+        };
+
+        // The operation is the first element of the SOAP body
+        let operation = element_text(&body, "Body")
+            .and_then(|soap_body| soap_body.trim_start().strip_prefix('<'))
+            .and_then(|tag| tag.split(|c: char| c.is_whitespace() || c == '>' || c == '/').next())
+            .map(|name| name.rsplit(':').next().unwrap_or(name).to_string())
+            .unwrap_or_default();
+
+        debug!("ONVIF {} from {}", operation, request.remote_addr().map(|a| a.to_string()).unwrap_or_default());
+
+        // Clients read the clock before authenticating, so that one is open
+        if operation != "GetSystemDateAndTime" && !self.authenticate(&body) {
+            respond(request, 400, fault("s:Sender", "ter:NotAuthorized", "Sender not authorized"));
+            return;
+        }
+
+        let response = match operation.as_str() {
+            "GetSystemDateAndTime" => match self.get_system_date_and_time() {
+                Some(body) => Ok(body),
+                None => {
+                    respond(request, 500, fault("s:Receiver", "ter:Action", "Failed to read the system clock"));
+                    return;
+                }
+            },
+            "GetDeviceInformation" => Ok(self.get_device_information()),
+            "GetCapabilities" => Ok(self.get_capabilities()),
+            "GetProfiles" => Ok(self.get_profiles()),
+            "GetStreamUri" => self.get_stream_uri(&body),
+            "GetSnapshotUri" => self.get_snapshot_uri(&body),
+            _ => {
+                respond(request, 400, fault("s:Sender", "ter:ActionNotSupported", "Optional action not implemented"));
+                return;
+            }
+        };
+
+        match response {
+            Ok(body) => respond(request, 200, envelope(&body)),
+            Err(reason) => respond(request, 400, fault("s:Sender", "ter:InvalidArgVal", &reason)),
+        }
+    }
+
+    // Check the WS-UsernameToken of a request; anyone is allowed when no
+    // users are configured
+    fn authenticate(&self, body: &str) -> bool {
+        let now = match glib::DateTime::now_utc() {
+            Ok(now) => now,
+            Err(e) => {
+                warn!("Failed to read the system clock: {}", e);
+                return false;
+            }
+        };
+        self.authenticate_at(body, &now, Instant::now())
+    }
+
+    fn authenticate_at(&self, body: &str, now: &glib::DateTime, instant: Instant) -> bool {
+        if self.config.onvif.users.is_empty() {
+            return true;
+        }
+
+        let username = element_text(body, "Username").map(str::trim).unwrap_or("");
+        let user = match self
+            .config
+            .onvif
+            .users
+            .iter()
+            .find(|u| http::constant_time_eq(u.username.as_bytes(), username.as_bytes()))
+        {
+            Some(user) => user,
+            None => {
+                warn!("ONVIF request from unknown user '{}'", username);
+                return false;
+            }
+        };
+
+        let (password_tag, password) = match element(body, "Password") {
+            Some(password) => password,
+            None => return false,
+        };
+
+        let valid = if password_tag.contains("PasswordDigest") {
+            match check_digest(user, password.trim(), body, now) {
+                Some(nonce) if self.nonces.lock().unwrap().insert(&nonce, instant) => true,
+                Some(_) => {
+                    warn!("ONVIF UsernameToken for '{}' was replayed", username);
+                    false
+                }
+                None => false,
+            }
+        } else {
+            http::constant_time_eq(password.as_bytes(), user.password.as_bytes())
+        };
+
+        if !valid {
+            warn!("ONVIF authentication failed for user '{}'", username);
+        }
+        valid
+    }
+
+    fn get_system_date_and_time(&self) -> Option<String> {
+        let now = glib::DateTime::now_utc().ok()?;
+        Some(format!(
+            "<tds:GetSystemDateAndTimeResponse><tds:SystemDateAndTime>\
+             <tt:DateTimeType>NTP</tt:DateTimeType><tt:DaylightSavings>false</tt:DaylightSavings>\
+             <tt:UTCDateTime><tt:Time><tt:Hour>{}</tt:Hour><tt:Minute>{}</tt:Minute><tt:Second>{}</tt:Second></tt:Time>\
+             <tt:Date><tt:Year>{}</tt:Year><tt:Month>{}</tt:Month><tt:Day>{}</tt:Day></tt:Date></tt:UTCDateTime>\
+             </tds:SystemDateAndTime></tds:GetSystemDateAndTimeResponse>",
+            now.hour(), now.minute(), now.second(), now.year(), now.month(), now.day_of_month()
+        ))
+    }
+
+    fn get_device_information(&self) -> String {
+        format!(
+            "<tds:GetDeviceInformationResponse>\
+             <tds:Manufacturer>display_rtsp_streamer</tds:Manufacturer>\
+             <tds:Model>Display RTSP Streamer</tds:Model>\
+             <tds:FirmwareVersion>{}</tds:FirmwareVersion>\
+             <tds:SerialNumber>{}</tds:SerialNumber>\
+             <tds:HardwareId>{}</tds:HardwareId>\
+             </tds:GetDeviceInformationResponse>",
+            env!("CARGO_PKG_VERSION"),
+            escape(&discovery::endpoint_reference(&self.config)),
+            escape(&glib::host_name())
+        )
+    }
+
+    fn get_capabilities(&self) -> String {
+        let base = format!("http://{}:{}/onvif", self.config.server_address, self.config.http_port);
+        format!(
+            "<tds:GetCapabilitiesResponse><tds:Capabilities>\
+             <tt:Device><tt:XAddr>{}/device_service</tt:XAddr></tt:Device>\
+             <tt:Media><tt:XAddr>{}/media_service</tt:XAddr><tt:StreamingCapabilities>\
+             <tt:RTPMulticast>false</tt:RTPMulticast><tt:RTP_TCP>true</tt:RTP_TCP>\
+             <tt:RTP_RTSP_TCP>true</tt:RTP_RTSP_TCP></tt:StreamingCapabilities></tt:Media>\
+             </tds:Capabilities></tds:GetCapabilitiesResponse>",
+            base, base
+        )
+    }
+
+    fn get_profiles(&self) -> String {
+        let profiles: String = self
+            .streams
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(slot, display)| self.profile(*slot, display))
+            .collect();
+        format!("<trt:GetProfilesResponse>{}</trt:GetProfilesResponse>", profiles)
+    }
+
+    // One profile per display stream, token "displayN"
+    fn profile(&self, slot: usize, display: &DisplayMetadata) -> String {
+        format!(
+            "<trt:Profiles token=\"display{slot}\" fixed=\"true\"><tt:Name>Display {slot}</tt:Name>\
+             <tt:VideoSourceConfiguration token=\"source{slot}\"><tt:Name>Display {slot}</tt:Name>\
+             <tt:UseCount>1</tt:UseCount><tt:SourceToken>display{slot}</tt:SourceToken>\
+             <tt:Bounds x=\"0\" y=\"0\" width=\"{width}\" height=\"{height}\"/></tt:VideoSourceConfiguration>\
+             <tt:VideoEncoderConfiguration token=\"encoder{slot}\"><tt:Name>Display {slot}</tt:Name>\
+             <tt:UseCount>1</tt:UseCount><tt:Encoding>H264</tt:Encoding>\
+             <tt:Resolution><tt:Width>{width}</tt:Width><tt:Height>{height}</tt:Height></tt:Resolution>\
+             <tt:Quality>{quality}</tt:Quality>\
+             <tt:RateControl><tt:FrameRateLimit>{frame_rate}</tt:FrameRateLimit>\
+             <tt:EncodingInterval>1</tt:EncodingInterval><tt:BitrateLimit>0</tt:BitrateLimit></tt:RateControl>\
+             <tt:H264><tt:GovLength>30</tt:GovLength><tt:H264Profile>Baseline</tt:H264Profile></tt:H264>\
+             <tt:SessionTimeout>PT60S</tt:SessionTimeout></tt:VideoEncoderConfiguration>\
+             </trt:Profiles>",
+            slot = slot,
+            width = display.width,
+            height = display.height,
+            quality = self.config.quality,
+            frame_rate = self.config.frame_rate,
+        )
+    }
+
+    fn get_stream_uri(&self, body: &str) -> Result<String, String> {
+        let slot = self.profile_slot(body)?;
+        let uri = format!("rtsp://{}:{}/display{}", self.config.server_address, self.config.rtsp_port, slot);
+        Ok(format!(
+            "<trt:GetStreamUriResponse>{}</trt:GetStreamUriResponse>",
+            media_uri(&uri)
+        ))
+    }
+
+    fn get_snapshot_uri(&self, body: &str) -> Result<String, String> {
+        let slot = self.profile_slot(body)?;
+        if !self.config.snapshot.enabled {
+            return Err("Snapshots are disabled".to_string());
+        }
+        let uri = format!(
+            "http://{}:{}/snapshot/display{}.jpg",
+            self.config.server_address, self.config.http_port, slot
+        );
+        Ok(format!(
+            "<trt:GetSnapshotUriResponse>{}</trt:GetSnapshotUriResponse>",
+            media_uri(&uri)
+        ))
+    }
+
+    // Display slot of the ProfileToken in a request, if it is being served
+    fn profile_slot(&self, body: &str) -> Result<usize, String> {
+        let token = element_text(body, "ProfileToken").map(str::trim).unwrap_or("");
+        token
+            .strip_prefix("display")
+            .and_then(|slot| slot.parse::<usize>().ok())
+            .filter(|slot| self.streams.lock().unwrap().iter().any(|(s, _)| s == slot))
+            .ok_or_else(|| format!("No profile with token '{}'", token))
+    }
+}
+
+// Check a PasswordDigest against the time `now`; returns the nonce of a
+// valid digest, for the replay check
+fn check_digest(user: &OnvifUser, digest: &str, body: &str, now: &glib::DateTime) -> Option<Vec<u8>> {
+    let nonce = element_text(body, "Nonce")
+        .and_then(|nonce| base64::engine::general_purpose::STANDARD.decode(nonce.trim()).ok());
+    let created = element_text(body, "Created").map(str::trim);
+    let (nonce, created) = match (nonce, created) {
+        (Some(nonce), Some(created)) => (nonce, created),
+        _ => return None,
+    };
+
+    // Reject old headers; replays within the window hit the nonce cache
+    let fresh = glib::DateTime::from_iso8601(created, Some(&glib::TimeZone::utc()))
+        .map(|created| now.difference(&created).as_seconds().abs() <= MAX_CLOCK_SKEW_SECS)
+        .unwrap_or(false);
+    if !fresh {
+        warn!("ONVIF UsernameToken for '{}' is too old or has a bad timestamp", user.username);
+        return None;
+    }
+
+    let expected = password_digest(&nonce, created, &user.password);
+    http::constant_time_eq(expected.as_bytes(), digest.as_bytes()).then_some(nonce)
+}
+
+// PasswordDigest = Base64(SHA-1(nonce + created + password))
+fn password_digest(nonce: &[u8], created: &str, password: &str) -> String {
+    let mut sha1 = sha1_smol::Sha1::new();
+    sha1.update(nonce);
+    sha1.update(created.as_bytes());
+    sha1.update(password.as_bytes());
+    base64::engine::general_purpose::STANDARD.encode(sha1.digest().bytes())
+}
+
+fn media_uri(uri: &str) -> String {
+    format!(
+        "<trt:MediaUri><tt:Uri>{}</tt:Uri><tt:InvalidAfterConnect>false</tt:InvalidAfterConnect>\
+         <tt:InvalidAfterReboot>false</tt:InvalidAfterReboot><tt:Timeout>PT0S</tt:Timeout></trt:MediaUri>",
+        escape(uri)
+    )
+}
+
+fn envelope(body: &str) -> String {
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+         <s:Envelope xmlns:s=\"http://www.w3.org/2003/05/soap-envelope\" \
+         xmlns:tt=\"http://www.onvif.org/ver10/schema\" \
+         xmlns:tds=\"http://www.onvif.org/ver10/device/wsdl\" \
+         xmlns:trt=\"http://www.onvif.org/ver10/media/wsdl\" \
+         xmlns:ter=\"http://www.onvif.org/ver10/error\">\
+         <s:Body>{}</s:Body></s:Envelope>",
+        body
+    )
+}
+
+// `code` is s:Sender for bad requests, s:Receiver for our own failures
+fn fault(code: &str, subcode: &str, reason: &str) -> String {
+    envelope(&format!(
+        "<s:Fault><s:Code><s:Value>{}</s:Value><s:Subcode><s:Value>{}</s:Value></s:Subcode></s:Code>\
+         <s:Reason><s:Text xml:lang=\"en\">{}</s:Text></s:Reason></s:Fault>",
+        code,
+        subcode,
+        escape(reason)
+    ))
+}
+
+fn respond(request: Request, status: u16, body: String) {
+    let response = Response::from_string(body)
+        .with_status_code(status)
+        .with_header(http::header("Content-Type", "application/soap+xml; charset=utf-8"));
+    if let Err(e) = request.respond(response) {
+        warn!("Failed to send ONVIF response: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn service(snapshots: bool) -> OnvifService {
+        let mut config = Config {
+            server_address: "192.168.1.20".to_string(),
+            ..Config::default()
+        };
+        config.snapshot.enabled = snapshots;
+        let display = DisplayMetadata {
+            index: 0,
+            x: 0,
+            y: 0,
+            width: 1920,
+            height: 1080,
+            is_primary: true,
+            name: "primary".to_string(),
+        };
+        OnvifService {
+            config,
+            streams: Arc::new(Mutex::new(vec![(0, display)])),
+            nonces: Mutex::new(NonceCache::default()),
+        }
+    }
+
+    const SNAPSHOT_REQUEST: &str = "<s:Envelope><s:Body><trt:GetSnapshotUri>\
+        <trt:ProfileToken>display0</trt:ProfileToken></trt:GetSnapshotUri></s:Body></s:Envelope>";
+
+    #[test]
+    fn snapshot_uri_only_when_enabled() {
+        let response = service(true).get_snapshot_uri(SNAPSHOT_REQUEST).unwrap();
+        assert!(response.contains("/snapshot/display0.jpg"));
+
+        assert!(service(false).get_snapshot_uri(SNAPSHOT_REQUEST).is_err());
+    }
+
+    #[test]
+    fn unknown_profiles_are_rejected() {
+        let request = SNAPSHOT_REQUEST.replace("display0", "display7");
+        assert!(service(true).get_snapshot_uri(&request).is_err());
+    }
+
+    #[test]
+    fn nonces_are_used_once() {
+        let mut cache = NonceCache::default();
+        let start = Instant::now();
+
+        assert!(cache.insert(b"first", start));
+        assert!(cache.insert(b"second", start));
+        assert!(!cache.insert(b"first", start + Duration::from_secs(1)));
+        assert!(!cache.insert(b"first", start + NONCE_LIFETIME - Duration::from_secs(1)));
+    }
+
+    // Known answer: the nonce is the bytes 0..16, computed independently
+    const NONCE: &str = "AAECAwQFBgcICQoLDA0ODw==";
+    const CREATED: &str = "2026-10-18T12:00:00Z";
+    const DIGEST: &str = "jjcqH83wdNP/xNDcI8GxE+emtfc=";
+
+    fn user() -> OnvifUser {
+        OnvifUser {
+            username: "admin".to_string(),
+            password: "secret".to_string(),
+        }
+    }
+
+    fn token(username: &str, digest: &str, nonce: &str, created: &str) -> String {
+        format!(
+            "<s:Envelope><s:Header><wsse:Security><wsse:UsernameToken>\
+             <wsse:Username>{}</wsse:Username>\
+             <wsse:Password Type=\"...#PasswordDigest\">{}</wsse:Password>\
+             <wsse:Nonce>{}</wsse:Nonce><wsu:Created>{}</wsu:Created>\
+             </wsse:UsernameToken></wsse:Security></s:Header>\
+             <s:Body><tds:GetDeviceInformation/></s:Body></s:Envelope>",
+            username, digest, nonce, created
+        )
+    }
+
+    // `seconds` after CREATED
+    fn time(seconds: f64) -> glib::DateTime {
+        glib::DateTime::from_iso8601(CREATED, None).unwrap().add_seconds(seconds).unwrap()
+    }
+
+    #[test]
+    fn digest_matches_known_answer() {
+        let nonce = base64::engine::general_purpose::STANDARD.decode(NONCE).unwrap();
+        assert_eq!(password_digest(&nonce, CREATED, "secret"), DIGEST);
+
+        let body = token("admin", DIGEST, NONCE, CREATED);
+        assert_eq!(check_digest(&user(), DIGEST, &body, &time(10.0)), Some(nonce));
+    }
+
+    #[test]
+    fn wrong_passwords_are_rejected() {
+        let body = token("admin", DIGEST, NONCE, CREATED);
+        let other = OnvifUser {
+            password: "Secret".to_string(),
+            ..user()
+        };
+        assert_eq!(check_digest(&other, DIGEST, &body, &time(10.0)), None);
+
+        // The digest covers the nonce and the time too
+        let body = token("admin", DIGEST, "AAECAwQFBgcICQoLDA0OEA==", CREATED);
+        assert_eq!(check_digest(&user(), DIGEST, &body, &time(10.0)), None);
+        let body = token("admin", DIGEST, NONCE, "2026-10-18T12:00:01Z");
+        assert_eq!(check_digest(&user(), DIGEST, &body, &time(10.0)), None);
+    }
+
+    #[test]
+    fn stale_or_future_created_times_are_rejected() {
+        let body = token("admin", DIGEST, NONCE, CREATED);
+        let max = MAX_CLOCK_SKEW_SECS as f64;
+        assert!(check_digest(&user(), DIGEST, &body, &time(max)).is_some());
+        assert!(check_digest(&user(), DIGEST, &body, &time(-max)).is_some());
+        assert_eq!(check_digest(&user(), DIGEST, &body, &time(max + 1.0)), None);
+        assert_eq!(check_digest(&user(), DIGEST, &body, &time(-max - 1.0)), None);
+
+        let body = token("admin", DIGEST, NONCE, "yesterday");
+        assert_eq!(check_digest(&user(), DIGEST, &body, &time(0.0)), None);
+    }
+
+    #[test]
+    fn replayed_tokens_are_rejected() {
+        let mut service = service(true);
+        service.config.onvif.users = vec![user()];
+        let body = token("admin", DIGEST, NONCE, CREATED);
+        let start = Instant::now();
+
+        assert!(service.authenticate_at(&body, &time(1.0), start));
+        assert!(!service.authenticate_at(&body, &time(2.0), start + Duration::from_secs(1)));
+
+        // A new nonce is fine
+        let nonce = [7u8; 16];
+        let digest = password_digest(&nonce, CREATED, "secret");
+        let body = token("admin", &digest, &base64::engine::general_purpose::STANDARD.encode(nonce), CREATED);
+        assert!(service.authenticate_at(&body, &time(3.0), start + Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn unknown_users_are_rejected() {
+        let mut service = service(true);
+        service.config.onvif.users = vec![user()];
+        let body = token("root", DIGEST, NONCE, CREATED);
+        assert!(!service.authenticate_at(&body, &time(1.0), Instant::now()));
+    }
+
+    #[test]
+    fn nonces_are_forgotten_after_their_lifetime() {
+        let mut cache = NonceCache::default();
+        let start = Instant::now();
+
+        assert!(cache.insert(b"nonce", start));
+        assert!(cache.insert(b"nonce", start + NONCE_LIFETIME));
+        assert_eq!(cache.seen.len(), 1);
+    }
+}
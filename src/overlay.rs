@@ -0,0 +1,141 @@
+// Burned-in text (hostname, display name, wall-clock time) on streams
+//
+// A textoverlay element is added to the media pipeline and its text is
+// refreshed every second from the GLib main loop while the media exists.
+
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use log::warn;
+
+use crate::config::OverlayConfig;
+
+/// Name of the textoverlay element in the media pipeline
+const ELEMENT_NAME: &str = "overlay";
+
+/// Pipeline fragment to insert before the encoder (empty if disabled)
+pub fn launch_fragment(config: &OverlayConfig) -> String {
+    if config.enabled {
+        format!("textoverlay name={} shaded-background=true ! ", ELEMENT_NAME)
+    } else {
+        String::new()
+    }
+}
+
+/// Configure the overlay of a newly created media and keep its text current
+pub fn attach(bin: &gst::Bin, config: &OverlayConfig, display_name: &str) {
+    if !config.enabled {
+        return;
+    }
+
+    let overlay = match bin.by_name(ELEMENT_NAME) {
+        Some(overlay) => overlay,
+        None => {
+            warn!("No text overlay found in media for {}", display_name);
+            return;
+        }
+    };
+
+    let (valignment, halignment) = alignment(&config.position);
+    overlay.set_property_from_str("valignment", valignment);
+    overlay.set_property_from_str("halignment", halignment);
+    overlay.set_property("font-desc", format!("Sans {}", config.font_size));
+
+    let template = template(&config.format, &glib::host_name(), display_name);
+    let time_format = config.time_format.clone();
+
+    overlay.set_property("text", render(&template, &time_format));
+
+    let overlay = overlay.downgrade();
+    glib::timeout_add_seconds(1, move || match overlay.upgrade() {
+        Some(overlay) => {
+            overlay.set_property("text", render(&template, &time_format));
+            glib::Continue(true)
+        }
+        // The media was torn down
+        None => glib::Continue(false),
+    });
+}
+
+// Everything except the time is fixed for the lifetime of the media;
+// unknown placeholders are left as they are
+fn template(format: &str, hostname: &str, display_name: &str) -> String {
+    format.replace("{hostname}", hostname).replace("{display}", display_name)
+}
+
+fn render(template: &str, time_format: &str) -> String {
+    if !template.contains("{time}") {
+        return template.to_string();
+    }
+
+    let time = glib::DateTime::now_local()
+        .and_then(|now| now.format(time_format))
+        .map(|time| time.to_string())
+        .unwrap_or_default();
+
+    template.replace("{time}", &time)
+}
+
+/// Map a position name to (vertical, horizontal) alignment, as used by
+/// the textoverlay valignment and halignment properties
+pub fn alignment(position: &str) -> (&'static str, &'static str) {
+    match position {
+        "top-left" => ("top", "left"),
+        "top" => ("top", "center"),
+        "top-right" => ("top", "right"),
+        "left" => ("center", "left"),
+        "center" => ("center", "center"),
+        "right" => ("center", "right"),
+        "bottom-left" => ("bottom", "left"),
+        "bottom" => ("bottom", "center"),
+        "bottom-right" => ("bottom", "right"),
+        _ => {
+            warn!("Unknown position '{}', using top-left", position);
+            ("top", "left")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn template_fills_in_hostname_and_display() {
+        assert_eq!(
+            template("{hostname} - {display} - {time}", "nvr-host", "Display 1"),
+            "nvr-host - Display 1 - {time}"
+        );
+        assert_eq!(template("{display}/{display}", "host", "D"), "D/D");
+        assert_eq!(template("{unknown} {Display}", "host", "D"), "{unknown} {Display}");
+        assert_eq!(template("", "host", "D"), "");
+    }
+
+    #[test]
+    fn render_fills_in_the_time() {
+        assert_eq!(render("no time here", "%H"), "no time here");
+        assert_eq!(render("{unknown}", "%H"), "{unknown}");
+
+        let year = glib::DateTime::now_local().unwrap().year();
+        assert_eq!(render("Year {time}", "%Y"), format!("Year {}", year));
+        assert_eq!(render("{time}{time}", "%Y"), format!("{}{}", year, year));
+    }
+
+    #[test]
+    fn positions_map_to_alignments() {
+        let cases = [
+            ("top-left", ("top", "left")),
+            ("top", ("top", "center")),
+            ("top-right", ("top", "right")),
+            ("left", ("center", "left")),
+            ("center", ("center", "center")),
+            ("right", ("center", "right")),
+            ("bottom-left", ("bottom", "left")),
+            ("bottom", ("bottom", "center")),
+            ("bottom-right", ("bottom", "right")),
+            ("middle", ("top", "left")),
+        ];
+        for (position, expected) in cases {
+            assert_eq!(alignment(position), expected, "{}", position);
+        }
+    }
+}
@@ -0,0 +1,163 @@
+// Frame pacing for the capture loop
+//
+// In adaptive mode the capture rate follows screen activity: it jumps up as
+// soon as a large part of the screen changes and only ramps back down after
+// the screen has been quiet for a while, so short pauses in a video or
+// between keystrokes don't make the rate oscillate.
+
+use std::time::{Duration, Instant};
+
+/// Changed-area fraction at which the full frame rate is reached
+const FULL_ACTIVITY_FRACTION: f32 = 0.10;
+
+/// How long activity must stay low before the rate starts dropping
+const RAMP_DOWN_HOLD: Duration = Duration::from_secs(2);
+
+/// Multiplier applied to the rate for each quiet frame after the hold period
+const RAMP_DOWN_FACTOR: f32 = 0.9;
+
+pub struct FramePacer {
+    min_rate: f32,
+    max_rate: f32,
+    adaptive: bool,
+    rate: f32,
+    last_active: Instant,
+}
+
+impl FramePacer {
+    pub fn new(min_rate: u32, max_rate: u32, adaptive: bool) -> Self {
+        let max_rate = max_rate.max(1) as f32;
+        let min_rate = (min_rate.max(1) as f32).min(max_rate);
+        Self {
+            min_rate,
+            max_rate,
+            adaptive,
+            // Start at full rate so the first seconds of a stream look good
+            rate: max_rate,
+            last_active: Instant::now(),
+        }
+    }
+
+    /// Feed the changed-area fraction of the latest frame (0.0 - 1.0)
+    pub fn update(&mut self, changed_fraction: f32) {
+        self.update_at(changed_fraction, Instant::now());
+    }
+
+    // Same as update, for a frame captured at `now`
+    fn update_at(&mut self, changed_fraction: f32, now: Instant) {
+        if !self.adaptive {
+            return;
+        }
+
+        let activity = (changed_fraction / FULL_ACTIVITY_FRACTION).clamp(0.0, 1.0);
+        let target = self.min_rate + (self.max_rate - self.min_rate) * activity;
+
+        if target >= self.rate {
+            // Ramp up immediately
+            self.rate = target;
+            self.last_active = now;
+        } else if now.saturating_duration_since(self.last_active) >= RAMP_DOWN_HOLD {
+            // Ramp down gradually once the screen has calmed down
+            self.rate = (self.rate * RAMP_DOWN_FACTOR).max(target);
+        }
+    }
+
+    /// Time between frames at the current rate
+    pub fn frame_delay(&self) -> Duration {
+        Duration::from_micros((1_000_000.0 / self.rate) as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rate(pacer: &FramePacer) -> f32 {
+        1.0 / pacer.frame_delay().as_secs_f32()
+    }
+
+    fn assert_rate(pacer: &FramePacer, expected: f32) {
+        let actual = rate(pacer);
+        assert!((actual - expected).abs() < 0.01, "rate {} != {}", actual, expected);
+    }
+
+    #[test]
+    fn fixed_rate_ignores_activity() {
+        let mut pacer = FramePacer::new(5, 30, false);
+        assert_rate(&pacer, 30.0);
+        let start = Instant::now();
+        for second in 0..10 {
+            pacer.update_at(0.0, start + Duration::from_secs(second));
+        }
+        assert_rate(&pacer, 30.0);
+    }
+
+    #[test]
+    fn rates_are_sanitized() {
+        assert_rate(&FramePacer::new(0, 0, true), 1.0);
+
+        // A minimum above the maximum is capped to it
+        let mut pacer = FramePacer::new(60, 30, true);
+        pacer.update_at(0.0, Instant::now() + Duration::from_secs(10));
+        assert_rate(&pacer, 30.0);
+    }
+
+    #[test]
+    fn activity_maps_linearly_up_to_the_full_fraction() {
+        let start = Instant::now();
+        let mut pacer = FramePacer::new(10, 30, true);
+        for _ in 0..100 {
+            pacer.update_at(0.0, start + Duration::from_secs(60));
+        }
+        assert_rate(&pacer, 10.0);
+
+        // Half of FULL_ACTIVITY_FRACTION is half way between the rates
+        pacer.update_at(FULL_ACTIVITY_FRACTION / 2.0, start + Duration::from_secs(61));
+        assert_rate(&pacer, 20.0);
+
+        // Anything beyond it is the full rate
+        pacer.update_at(0.8, start + Duration::from_secs(62));
+        assert_rate(&pacer, 30.0);
+    }
+
+    #[test]
+    fn ramps_down_gradually_after_the_hold() {
+        let start = Instant::now();
+        let mut pacer = FramePacer::new(5, 30, true);
+        pacer.update_at(1.0, start);
+
+        // Quiet, but still within the hold period
+        pacer.update_at(0.0, start + RAMP_DOWN_HOLD - Duration::from_millis(1));
+        assert_rate(&pacer, 30.0);
+
+        // One step per quiet frame once the hold is over
+        let after_hold = start + RAMP_DOWN_HOLD;
+        pacer.update_at(0.0, after_hold);
+        assert_rate(&pacer, 30.0 * RAMP_DOWN_FACTOR);
+        pacer.update_at(0.0, after_hold);
+        assert_rate(&pacer, 30.0 * RAMP_DOWN_FACTOR * RAMP_DOWN_FACTOR);
+
+        // Never below the target for the current activity
+        for _ in 0..100 {
+            pacer.update_at(0.0, after_hold);
+        }
+        assert_rate(&pacer, 5.0);
+    }
+
+    #[test]
+    fn ramps_up_immediately_and_restarts_the_hold() {
+        let start = Instant::now();
+        let mut pacer = FramePacer::new(5, 30, true);
+        for _ in 0..100 {
+            pacer.update_at(0.0, start + Duration::from_secs(10));
+        }
+        assert_rate(&pacer, 5.0);
+
+        let active = start + Duration::from_secs(20);
+        pacer.update_at(1.0, active);
+        assert_rate(&pacer, 30.0);
+
+        pacer.update_at(0.0, active + Duration::from_secs(1));
+        assert_rate(&pacer, 30.0);
+    }
+}
@@ -0,0 +1,229 @@
+// "No signal" frames sent while a display can't be captured
+//
+// The frame is rendered once per resolution with a small GStreamer pipeline
+// (videotestsrc + textoverlay, or a decoded PNG) and cached by the caller.
+
+use anyhow::{Context, Result};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use gstreamer_app::AppSink;
+use log::warn;
+use std::time::Duration;
+
+use crate::config::PlaceholderConfig;
+use crate::convert::{self, Colorimetry, PixelFormat};
+
+/// Render a placeholder frame of the given size and format
+pub fn render(
+    config: &PlaceholderConfig,
+    display_name: &str,
+    width: u32,
+    height: u32,
+    format: PixelFormat,
+    colorimetry: &Colorimetry,
+) -> Vec<u8> {
+    let caps = raw_caps(format, colorimetry);
+    let result = if config.image.is_empty() {
+        render_text(config, display_name, width, height, &caps)
+    } else {
+        render_image(config, width, height, &caps)
+    };
+
+    match result {
+        Ok(frame) => frame,
+        Err(e) => {
+            // Missing plugins or a broken image shouldn't leave clients without video
+            warn!("Failed to render placeholder frame, using a solid colour: {:#}", e);
+            solid_frame(parse_color(&config.color), width, height, format, colorimetry)
+        }
+    }
+}
+
+fn render_text(
+    config: &PlaceholderConfig,
+    display_name: &str,
+    width: u32,
+    height: u32,
+    caps: &str,
+) -> Result<Vec<u8>> {
+    let pipeline = gst::parse_launch(&format!(
+        "videotestsrc name=source num-buffers=1 pattern=solid-color ! \
+         video/x-raw,width={},height={},pixel-aspect-ratio=1/1 ! \
+         textoverlay name=text valignment=center halignment=center ! \
+         videoconvert ! {} ! \
+         appsink name=sink",
+        width, height, caps
+    ))
+    .context("Failed to create placeholder pipeline")?;
+
+    let bin = pipeline.clone().dynamic_cast::<gst::Bin>()
+        .map_err(|_| anyhow::anyhow!("Failed to cast pipeline to Bin"))?;
+
+    let source = bin.by_name("source").context("Failed to find videotestsrc element")?;
+    source.set_property("foreground-color", 0xff00_0000 | parse_color(&config.color));
+
+    let text = bin.by_name("text").context("Failed to find textoverlay element")?;
+    text.set_property("text", config.text.replace("{display}", display_name));
+    text.set_property("font-desc", format!("Sans {}", config.font_size));
+
+    pull_frame(&pipeline, &bin, width, height)
+}
+
+fn render_image(config: &PlaceholderConfig, width: u32, height: u32, caps: &str) -> Result<Vec<u8>> {
+    let pipeline = gst::parse_launch(&format!(
+        "filesrc name=source ! decodebin ! imagefreeze num-buffers=1 ! \
+         videoconvert ! videoscale add-borders=true ! \
+         {},width={},height={},pixel-aspect-ratio=1/1 ! \
+         appsink name=sink",
+        caps, width, height
+    ))
+    .context("Failed to create placeholder pipeline")?;
+
+    let bin = pipeline.clone().dynamic_cast::<gst::Bin>()
+        .map_err(|_| anyhow::anyhow!("Failed to cast pipeline to Bin"))?;
+
+    let source = bin.by_name("source").context("Failed to find filesrc element")?;
+    source.set_property("location", &config.image);
+
+    pull_frame(&pipeline, &bin, width, height)
+}
+
+// Run a one-shot pipeline and take the frame that reaches its appsink
+fn pull_frame(
+    pipeline: &gst::Element,
+    bin: &gst::Bin,
+    width: u32,
+    height: u32,
+) -> Result<Vec<u8>> {
+    let appsink = bin.by_name("sink")
+        .context("Failed to find appsink element")?
+        .downcast::<AppSink>()
+        .map_err(|_| anyhow::anyhow!("Failed to downcast to AppSink"))?;
+
+    pipeline.set_state(gst::State::Playing).context("Failed to start placeholder pipeline")?;
+    let sample = appsink.try_pull_sample(gst::ClockTime::from_seconds(5));
+    let _ = pipeline.set_state(gst::State::Null);
+
+    let sample = sample.context("Placeholder pipeline produced no frame")?;
+    let buffer = sample.buffer().context("Placeholder sample has no buffer")?;
+    let map = buffer.map_readable().context("Failed to map placeholder buffer")?;
+
+    // YUV frames are pushed with GStreamer's default layout, as produced here
+    let is_bgr = sample.caps()
+        .and_then(|caps| caps.structure(0).and_then(|s| s.get::<&str>("format").ok()))
+        == Some("BGR");
+    if !is_bgr {
+        return Ok(map.to_vec());
+    }
+
+    // Strip any row padding so the frame matches what the capture threads push
+    let row_bytes = width as usize * 3;
+    let stride = map.len() / height as usize;
+    if stride < row_bytes {
+        anyhow::bail!("Placeholder frame is smaller than expected");
+    }
+
+    let mut frame = Vec::with_capacity(row_bytes * height as usize);
+    for row in map.chunks(stride).take(height as usize) {
+        frame.extend_from_slice(&row[..row_bytes]);
+    }
+
+    Ok(frame)
+}
+
+fn solid_frame(color: u32, width: u32, height: u32, format: PixelFormat, colorimetry: &Colorimetry) -> Vec<u8> {
+    let pixel = [color as u8, (color >> 8) as u8, (color >> 16) as u8, 0xff];
+    let bgra = pixel.repeat((width * height) as usize);
+    convert::convert(format, &bgra, width, height, colorimetry)
+}
+
+// Caps of the frames the mount expects, as in its appsrc caps
+fn raw_caps(format: PixelFormat, colorimetry: &Colorimetry) -> String {
+    match format {
+        PixelFormat::Bgr => "video/x-raw,format=BGR".to_string(),
+        _ => format!("video/x-raw,format={},colorimetry={}", format.caps_name(), colorimetry.caps_name()),
+    }
+}
+
+/// Parse a `#RRGGBB` colour into 0xRRGGBB, falling back to black
+pub fn parse_color(color: &str) -> u32 {
+    match u32::from_str_radix(color.trim_start_matches('#'), 16) {
+        Ok(value) if value <= 0xff_ffff => value,
+        _ => {
+            warn!("Invalid colour '{}', using black", color);
+            0
+        }
+    }
+}
+
+/// Time between placeholder frames
+pub fn frame_interval(config: &PlaceholderConfig) -> Duration {
+    Duration::from_micros((1_000_000.0 / config.frame_rate.max(1) as f64) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COLOR: u32 = 0x20_40_80;
+
+    fn config() -> PlaceholderConfig {
+        PlaceholderConfig {
+            color: "#204080".to_string(),
+            ..PlaceholderConfig::default()
+        }
+    }
+
+    #[test]
+    fn colors_are_parsed_with_a_black_fallback() {
+        assert_eq!(parse_color("#204080"), COLOR);
+        assert_eq!(parse_color("ffffff"), 0xff_ffff);
+        assert_eq!(parse_color("#1000000"), 0);
+        assert_eq!(parse_color("red"), 0);
+    }
+
+    #[test]
+    fn solid_frames_have_the_size_of_the_stream_format() {
+        let colorimetry = Colorimetry::default();
+        // Luma rows padded to 4 bytes and to an even count, then the chroma
+        let cases = [
+            (PixelFormat::Bgr, 64, 48, 64 * 48 * 3),
+            (PixelFormat::I420, 64, 48, 64 * 48 + 2 * 32 * 24),
+            (PixelFormat::Nv12, 64, 48, 64 * 48 + 64 * 24),
+            (PixelFormat::Bgr, 33, 17, 33 * 17 * 3),
+            (PixelFormat::I420, 33, 17, 36 * 18 + 2 * 20 * 9),
+            (PixelFormat::Nv12, 33, 17, 36 * 18 + 36 * 9),
+        ];
+        for (format, width, height, size) in cases {
+            let frame = solid_frame(COLOR, width, height, format, &colorimetry);
+            assert_eq!(frame.len(), size, "{:?} {}x{}", format, width, height);
+        }
+    }
+
+    #[test]
+    fn solid_frames_have_the_configured_color() {
+        let frame = solid_frame(COLOR, 8, 4, PixelFormat::Bgr, &Colorimetry::default());
+        for pixel in frame.chunks_exact(3) {
+            assert_eq!(pixel, [0x80, 0x40, 0x20]);
+        }
+
+        // Every luma sample is the same
+        let frame = solid_frame(COLOR, 8, 4, PixelFormat::I420, &Colorimetry::default());
+        assert!(frame[..8 * 4].iter().all(|&y| y == frame[0]));
+    }
+
+    #[test]
+    fn unreadable_image_falls_back_to_a_solid_frame() {
+        gst::init().unwrap();
+        let config = PlaceholderConfig {
+            image: "/nonexistent/placeholder.png".to_string(),
+            ..config()
+        };
+        let colorimetry = Colorimetry::default();
+
+        for format in [PixelFormat::Bgr, PixelFormat::I420, PixelFormat::Nv12] {
+            let frame = render(&config, "Display 0", 64, 48, format, &colorimetry);
+            assert_eq!(frame, solid_frame(COLOR, 64, 48, format, &colorimetry), "{:?}", format);
+        }
+    }
+}
@@ -0,0 +1,102 @@
+// Screen sharing through xdg-desktop-portal for the pipewiresrc backend
+//
+// Wayland compositors only hand out screen contents through the ScreenCast
+// portal: we create a session, select the monitors (the compositor usually
+// asks the user which ones to share), start it and open the PipeWire remote
+// whose file descriptor pipewiresrc connects with. One session serves every
+// display for the life of the process; the portal ends it when our D-Bus
+// connection closes.
+
+use anyhow::{Context, Result};
+use ashpd::desktop::screencast::{CursorMode, Screencast, SourceType, Stream};
+use ashpd::desktop::PersistMode;
+use log::{error, info, warn};
+use once_cell::sync::OnceCell;
+use std::os::fd::{AsRawFd, OwnedFd};
+
+use crate::capture::DisplayMetadata;
+
+struct Portal {
+    // pipewiresrc duplicates the descriptor for every pipeline, so it stays
+    // open for as long as streams may be created
+    fd: OwnedFd,
+    streams: Vec<Stream>,
+}
+
+// The screencast, or None when it couldn't be started; only asked for once
+static PORTAL: OnceCell<Option<Portal>> = OnceCell::new();
+
+/// pipewiresrc properties selecting the monitor shared for `display`,
+/// starting the screencast on first use. None without a portal session.
+pub fn source_properties(slot: usize, display: &DisplayMetadata, capture_cursor: bool) -> Option<String> {
+    let portal = PORTAL
+        .get_or_init(|| match pollster::block_on(start(capture_cursor)) {
+            Ok(portal) => Some(portal),
+            Err(e) => {
+                error!("Failed to share the screen through xdg-desktop-portal: {:#}", e);
+                None
+            }
+        })
+        .as_ref()?;
+
+    // Match the monitor by its place on the desktop, else by order
+    let stream = portal
+        .streams
+        .iter()
+        .find(|stream| stream.position() == Some((display.x, display.y)))
+        .or_else(|| portal.streams.get(slot));
+
+    // The portal hands out node ids, which pipewiresrc takes as the path
+    // (target-object wants a node name or serial)
+    match stream {
+        Some(stream) => Some(format!("fd={} path={}", portal.fd.as_raw_fd(), stream.pipe_wire_node_id())),
+        None => {
+            warn!("Display {} was not shared through the portal, using any shared monitor", slot);
+            Some(format!("fd={}", portal.fd.as_raw_fd()))
+        }
+    }
+}
+
+async fn start(capture_cursor: bool) -> Result<Portal> {
+    let screencast = Screencast::new()
+        .await
+        .context("Failed to connect to the ScreenCast portal")?;
+    let session = screencast
+        .create_session()
+        .await
+        .context("Failed to create screencast session")?;
+
+    let embed_cursor = capture_cursor
+        && screencast
+            .available_cursor_modes()
+            .await
+            .is_ok_and(|modes| modes.contains(CursorMode::Embedded));
+    let cursor_mode = if embed_cursor { CursorMode::Embedded } else { CursorMode::Hidden };
+
+    screencast
+        .select_sources(&session, cursor_mode, SourceType::Monitor.into(), true, None, PersistMode::DoNot)
+        .await
+        .and_then(|request| request.response())
+        .context("Failed to select monitors")?;
+
+    let streams = screencast
+        .start(&session, None)
+        .await
+        .and_then(|request| request.response())
+        .context("Screen sharing was not started")?;
+    let fd = screencast
+        .open_pipe_wire_remote(&session)
+        .await
+        .context("Failed to open the PipeWire remote")?;
+
+    info!(
+        "Sharing {} monitor(s) through xdg-desktop-portal: PipeWire nodes {:?}",
+        streams.streams().len(),
+        streams.streams().iter().map(|stream| stream.pipe_wire_node_id()).collect::<Vec<_>>()
+    );
+
+    Ok(Portal {
+        fd,
+        streams: streams.streams().to_vec(),
+    })
+}
@@ -0,0 +1,357 @@
+// Push mode: publish a display's stream to an external media server
+//
+// For machines behind NAT the media server can't pull from us, so each
+// configured target gets a pipeline that sends the display's shared H.264
+// encode to it: RTSP ANNOUNCE/RECORD, RTMP or SRT depending on the URL.
+// A target that is down or drops the connection is retried with backoff.
+// Local recording reuses the same machinery with a file sink.
+
+use anyhow::{Context, Result};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use gstreamer_app::AppSrc;
+use log::{error, info, warn};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::encoder::{self, Encoder};
+
+/// Longest wait for the sink to finish up (e.g. close a file) when stopping
+const EOS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Delay before the first reconnection, doubled after each failure
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// A connection that stays up this long resets the backoff
+const STABLE_CONNECTION: Duration = Duration::from_secs(30);
+
+// Shared between the publisher thread and the encoder subscription
+struct PublishState {
+    // Input of the current pipeline, None while disconnected
+    appsrc: Mutex<Option<AppSrc>>,
+    // A new pipeline must start with a keyframe
+    waiting_for_keyframe: AtomicBool,
+    running: AtomicBool,
+}
+
+// Prepares a freshly parsed pipeline, e.g. connects signals of its sink
+type PipelineSetup = Box<dyn Fn(&gst::Pipeline) -> Result<()> + Send>;
+
+/// Sends a display's stream to one target until dropped
+pub struct Publisher {
+    state: Arc<PublishState>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl Publisher {
+    /// Start publishing what `encoder` produces to `url`; `{display}` and
+    /// `{hostname}` in the URL are replaced
+    pub fn start(encoder: &Arc<Encoder>, url: &str, slot: usize) -> Result<Self> {
+        let url = url
+            .replace("{display}", &slot.to_string())
+            .replace("{hostname}", &glib::host_name());
+        let sink_launch = sink_launch(&url)?;
+        Ok(Self::with_sink(encoder, format!("publishing to {}", url), sink_launch, |_| Ok(())))
+    }
+
+    /// Feed what `encoder` produces, as H.264 from h264parse, into the
+    /// elements of `sink_launch`; `name` describes the target in log messages
+    pub fn with_sink(
+        encoder: &Arc<Encoder>,
+        name: String,
+        sink_launch: String,
+        setup: impl Fn(&gst::Pipeline) -> Result<()> + Send + 'static,
+    ) -> Self {
+        let state = Arc::new(PublishState {
+            appsrc: Mutex::new(None),
+            waiting_for_keyframe: AtomicBool::new(true),
+            running: AtomicBool::new(true),
+        });
+
+        {
+            let state = state.clone();
+            encoder.subscribe(move |frame| {
+                if !state.running.load(Ordering::SeqCst) {
+                    return false;
+                }
+                if let Some(appsrc) = state.appsrc.lock().unwrap().as_ref() {
+                    if frame.keyframe || !state.waiting_for_keyframe.load(Ordering::SeqCst) {
+                        state.waiting_for_keyframe.store(false, Ordering::SeqCst);
+                        // A failed push shows up as a bus error, handled by the thread
+                        let _ = appsrc.push_buffer(frame.unstamped_buffer());
+                    }
+                }
+                true
+            });
+        }
+
+        let handle = {
+            let state = state.clone();
+            let encoder = Arc::downgrade(encoder);
+            let setup: PipelineSetup = Box::new(setup);
+            thread::spawn(move || run_publisher(&name, &sink_launch, &setup, &state, &encoder))
+        };
+
+        Self {
+            state,
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for Publisher {
+    fn drop(&mut self) {
+        self.state.running.store(false, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            if let Err(e) = handle.join() {
+                error!("Error joining publisher thread: {:?}", e);
+            }
+        }
+    }
+}
+
+// Element(s) sending H.264 from h264parse to `url`
+fn sink_launch(url: &str) -> Result<String> {
+    let scheme = url.split("://").next().unwrap_or("").to_ascii_lowercase();
+    let launch = match scheme.as_str() {
+        "rtsp" | "rtsps" => format!("rtspclientsink location=\"{}\" latency=0", url),
+        // librtmp takes its options in the location
+        "rtmp" | "rtmps" => format!("flvmux streamable=true ! rtmpsink location=\"{} live=1\"", url),
+        "srt" => format!("mpegtsmux alignment=7 ! srtsink uri=\"{}\"", url),
+        _ => anyhow::bail!("Unsupported publish URL {} (expected rtsp://, rtmp:// or srt://)", url),
+    };
+    Ok(launch)
+}
+
+// Keep a pipeline to the target running, restarting it with backoff, until
+// the publisher is dropped
+fn run_publisher(
+    name: &str,
+    sink_launch: &str,
+    setup: &PipelineSetup,
+    state: &PublishState,
+    encoder: &Weak<Encoder>,
+) {
+    let mut backoff = Backoff::new();
+
+    while state.running.load(Ordering::SeqCst) {
+        let started = Instant::now();
+        match publish_session(name, sink_launch, setup, state, encoder) {
+            Ok(()) => break,
+            Err(e) => {
+                error!("{} failed: {:#}", capitalize(name), e);
+                let delay = backoff.after_failure(started.elapsed());
+                warn!("Retrying {} in {:?}", name, delay);
+
+                let deadline = Instant::now() + delay;
+                while state.running.load(Ordering::SeqCst) && Instant::now() < deadline {
+                    thread::sleep(Duration::from_millis(100));
+                }
+            }
+        }
+    }
+
+    info!("Stopped {}", name);
+}
+
+/// Reconnection delays: doubling after each failure, back to the start once
+/// a connection was up long enough to count as working
+pub(crate) struct Backoff {
+    next: Duration,
+}
+
+impl Backoff {
+    pub(crate) fn new() -> Self {
+        Self { next: INITIAL_BACKOFF }
+    }
+
+    /// Delay before reconnecting after a connection that lasted `uptime`
+    pub(crate) fn after_failure(&mut self, uptime: Duration) -> Duration {
+        if uptime >= STABLE_CONNECTION {
+            self.next = INITIAL_BACKOFF;
+        }
+        let delay = self.next;
+        self.next = (self.next * 2).min(MAX_BACKOFF);
+        delay
+    }
+}
+
+// Runs one connection until shutdown (Ok) or until the pipeline fails (Err)
+fn publish_session(
+    name: &str,
+    sink_launch: &str,
+    setup: &PipelineSetup,
+    state: &PublishState,
+    encoder: &Weak<Encoder>,
+) -> Result<()> {
+    let launch_str = format!(
+        "appsrc name=source is-live=true format=time do-timestamp=true \
+         caps=\"video/x-h264,stream-format=byte-stream,alignment=au\" ! h264parse ! {}",
+        sink_launch
+    );
+    let pipeline = gst::parse_launch(&launch_str)
+        .context("Failed to create publish pipeline")?
+        .downcast::<gst::Pipeline>()
+        .map_err(|_| anyhow::anyhow!("Publish pipeline is not a pipeline"))?;
+    let appsrc = pipeline
+        .by_name("source")
+        .and_then(|source| source.downcast::<AppSrc>().ok())
+        .context("No appsrc in publish pipeline")?;
+    setup(&pipeline)?;
+
+    // Errors and end-of-stream both end the session
+    let (ended_tx, ended_rx) = mpsc::channel();
+    let ended_tx = Mutex::new(ended_tx);
+    encoder::watch_bus(&pipeline, &capitalize(name), move || {
+        let _ = ended_tx.lock().unwrap().send(());
+    });
+
+    let result = (|| {
+        pipeline
+            .set_state(gst::State::Playing)
+            .context("Failed to start publish pipeline")?;
+
+        state.waiting_for_keyframe.store(true, Ordering::SeqCst);
+        *state.appsrc.lock().unwrap() = Some(appsrc);
+        if let Some(encoder) = encoder.upgrade() {
+            encoder.force_keyframe();
+        }
+        info!("Started {}", name);
+
+        while state.running.load(Ordering::SeqCst) {
+            match ended_rx.recv_timeout(Duration::from_millis(100)) {
+                Ok(()) => anyhow::bail!("Connection lost"),
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => anyhow::bail!("Pipeline bus closed"),
+            }
+        }
+        Ok(())
+    })();
+
+    // Let the sink finish cleanly when stopping, e.g. complete the last file
+    if let Some(appsrc) = state.appsrc.lock().unwrap().take() {
+        if result.is_ok() && appsrc.end_of_stream().is_ok() {
+            let _ = ended_rx.recv_timeout(EOS_TIMEOUT);
+        }
+    }
+    if let Err(e) = pipeline.set_state(gst::State::Null) {
+        error!("Failed to stop pipeline for {}: {}", name, e);
+    }
+
+    result
+}
+
+fn capitalize(text: &str) -> String {
+    let mut chars = text.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sinks_follow_the_url_scheme() {
+        assert_eq!(
+            sink_launch("rtsp://nvr:8554/display0").unwrap(),
+            "rtspclientsink location=\"rtsp://nvr:8554/display0\" latency=0"
+        );
+        assert!(sink_launch("RTSPS://nvr/display0").unwrap().starts_with("rtspclientsink "));
+        assert_eq!(
+            sink_launch("rtmp://live.example.com/app/key").unwrap(),
+            "flvmux streamable=true ! rtmpsink location=\"rtmp://live.example.com/app/key live=1\""
+        );
+        assert!(sink_launch("rtmps://live.example.com/app/key").unwrap().contains("rtmpsink"));
+        assert_eq!(
+            sink_launch("srt://nvr:9000?streamid=display0").unwrap(),
+            "mpegtsmux alignment=7 ! srtsink uri=\"srt://nvr:9000?streamid=display0\""
+        );
+    }
+
+    #[test]
+    fn unsupported_schemes_are_rejected() {
+        assert!(sink_launch("http://nvr/display0").is_err());
+        assert!(sink_launch("nvr:8554/display0").is_err());
+        assert!(sink_launch("").is_err());
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let mut backoff = Backoff::new();
+        let delays: Vec<u64> = (0..9).map(|_| backoff.after_failure(Duration::ZERO).as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 8, 16, 32, 60, 60, 60]);
+    }
+
+    #[test]
+    fn stable_connections_reset_the_backoff() {
+        let mut backoff = Backoff::new();
+        for _ in 0..5 {
+            backoff.after_failure(Duration::from_secs(1));
+        }
+        assert_eq!(backoff.after_failure(STABLE_CONNECTION - Duration::from_secs(1)), Duration::from_secs(32));
+        assert_eq!(backoff.after_failure(STABLE_CONNECTION), INITIAL_BACKOFF);
+        assert_eq!(backoff.after_failure(Duration::ZERO), Duration::from_secs(2));
+    }
+
+    // Publishes to a local media server and plays the stream back. Needs
+    // GStreamer with x264 and rtspclientsink, and mediamtx listening on
+    // MEDIAMTX_RTSP (default rtsp://127.0.0.1:8554):
+    // cargo test mediamtx -- --ignored
+    #[test]
+    #[ignore]
+    fn publishes_to_mediamtx() {
+        use crate::config::{Config, StreamConfig};
+        use gstreamer_app::AppSink;
+
+        gst::init().unwrap();
+        let server = std::env::var("MEDIAMTX_RTSP").unwrap_or_else(|_| "rtsp://127.0.0.1:8554".to_string());
+        let (width, height) = (320, 240);
+        let config = Config::default();
+        let stream = StreamConfig::default();
+        let encoder = Encoder::start("Publish test", width, height, &config, &stream, Duration::from_secs(1), true);
+        let encoder = Arc::new(encoder.unwrap());
+
+        let running = Arc::new(AtomicBool::new(true));
+        let feeder = {
+            let mount = encoder.mount();
+            let running = running.clone();
+            thread::spawn(move || {
+                let mut value = 0u8;
+                while running.load(Ordering::SeqCst) {
+                    let frame = vec![value; (width * height * 3) as usize];
+                    let _ = mount.push_frame(&frame, Duration::from_millis(33));
+                    value = value.wrapping_add(8);
+                    thread::sleep(Duration::from_millis(33));
+                }
+            })
+        };
+        let publisher = Publisher::start(&encoder, &format!("{}/publish-test{{display}}", server), 3).unwrap();
+
+        // Give the publisher time to connect before reading
+        thread::sleep(Duration::from_secs(2));
+        let player = gst::parse_launch(&format!(
+            "rtspsrc location={}/publish-test3 latency=0 ! rtph264depay ! appsink name=sink",
+            server
+        ))
+        .unwrap()
+        .downcast::<gst::Pipeline>()
+        .unwrap();
+        let appsink = player.by_name("sink").unwrap().downcast::<AppSink>().unwrap();
+        player.set_state(gst::State::Playing).unwrap();
+        let sample = appsink.try_pull_sample(gst::ClockTime::from_seconds(15));
+        player.set_state(gst::State::Null).unwrap();
+
+        drop(publisher);
+        running.store(false, Ordering::SeqCst);
+        feeder.join().unwrap();
+
+        assert!(sample.is_some(), "nothing came back from {}", server);
+    }
+}
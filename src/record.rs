@@ -0,0 +1,298 @@
+// Local recording of displays to time-segmented files
+//
+// Each recorded display feeds its shared H.264 encode into splitmuxsink,
+// which starts a new file at the first keyframe after segment_duration.
+// MP4 files are fragmented, so all but the last fragment stays playable
+// after a crash or power loss. Whenever a file is started the oldest
+// recordings are deleted to keep the directory within its quota; only files
+// in the layout written here count, so pointing `directory` at a folder that
+// holds other videos doesn't put them at risk.
+
+use anyhow::{Context, Result};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use log::{error, info, warn};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use crate::config::RecordConfig;
+use crate::encoder::Encoder;
+use crate::publish::Publisher;
+
+/// Length of the MP4 fragments in milliseconds, i.e. the most that is lost
+/// when the process dies
+const FRAGMENT_DURATION_MS: u32 = 1000;
+
+/// Extensions of the files the quota applies to
+const RECORDING_EXTENSIONS: &[&str] = &["mp4", "mkv"];
+
+/// Record what `encoder` produces until the returned publisher is dropped
+pub fn start(encoder: &Arc<Encoder>, config: &RecordConfig, slot: usize) -> Result<Publisher> {
+    let (muxer_name, extension) = match config.format.to_ascii_lowercase().as_str() {
+        "mp4" => ("mp4mux", "mp4"),
+        "mkv" => ("matroskamux", "mkv"),
+        other => anyhow::bail!("Unknown recording format '{}' (expected mp4 or mkv)", other),
+    };
+    let sink_launch = format!(
+        "splitmuxsink name=recorder max-size-time={}",
+        config.segment_duration.max(1) as u64 * 1_000_000_000
+    );
+    let directory = PathBuf::from(&config.directory);
+    let max_bytes = config.max_size_mb * 1024 * 1024;
+
+    let setup = move |pipeline: &gst::Pipeline| -> Result<()> {
+        let splitmuxsink = pipeline
+            .by_name("recorder")
+            .context("No splitmuxsink in recording pipeline")?;
+
+        let mut muxer = gst::ElementFactory::make(muxer_name);
+        if extension == "mp4" {
+            muxer = muxer.property("fragment-duration", FRAGMENT_DURATION_MS);
+        }
+        let muxer = muxer
+            .build()
+            .with_context(|| format!("Failed to create {}", muxer_name))?;
+        splitmuxsink.set_property("muxer", &muxer);
+
+        // Name each new file after the display and the time it starts
+        let directory = directory.clone();
+        splitmuxsink.connect("format-location", false, move |_| {
+            match next_file(&directory, slot, extension, max_bytes) {
+                Ok(path) => {
+                    info!("Recording display {} to {}", slot, path.display());
+                    Some(path.to_string_lossy().into_owned().to_value())
+                }
+                // Without a name splitmuxsink fails, which restarts the
+                // recording with backoff
+                Err(e) => {
+                    error!("Failed to start recording file for display {}: {:#}", slot, e);
+                    Some(None::<String>.to_value())
+                }
+            }
+        });
+
+        Ok(())
+    };
+
+    Ok(Publisher::with_sink(encoder, format!("recording display {}", slot), sink_launch, setup))
+}
+
+// Path of a new file, <directory>/display<N>/<date>/display<N>-<time>.<ext>,
+// after making room for it
+fn next_file(directory: &Path, slot: usize, extension: &str, max_bytes: u64) -> Result<PathBuf> {
+    let now = glib::DateTime::now_local().context("Failed to read the system clock")?;
+    let date = now.format("%Y-%m-%d").context("Failed to format date")?;
+    let time = now.format("%H-%M-%S").context("Failed to format time")?;
+
+    let day_directory = directory.join(format!("display{}", slot)).join(date.as_str());
+    fs::create_dir_all(&day_directory)
+        .with_context(|| format!("Failed to create {}", day_directory.display()))?;
+
+    if max_bytes > 0 {
+        enforce_quota(directory, max_bytes);
+    }
+
+    Ok(day_directory.join(format!("display{}-{}.{}", slot, time, extension)))
+}
+
+// Delete the oldest recordings until all of them fit in `max_bytes`
+fn enforce_quota(directory: &Path, max_bytes: u64) {
+    let mut recordings = find_recordings(directory);
+    recordings.sort_by_key(|(_, modified, _)| *modified);
+
+    let mut total: u64 = recordings.iter().map(|(_, _, size)| size).sum();
+    for (path, _, size) in recordings {
+        if total <= max_bytes {
+            break;
+        }
+
+        match fs::remove_file(&path) {
+            Ok(()) => {
+                info!("Deleted old recording {}", path.display());
+                total -= size;
+                // Only succeeds once the date directory is empty
+                if let Some(parent) = path.parent() {
+                    let _ = fs::remove_dir(parent);
+                }
+            }
+            Err(e) => warn!("Failed to delete old recording {}: {}", path.display(), e),
+        }
+    }
+}
+
+// Files of the display<N>/<YYYY-MM-DD>/display<N>-<HH-MM-SS>.<ext> layout
+// under `directory`, with their modification time and size
+fn find_recordings(directory: &Path) -> Vec<(PathBuf, SystemTime, u64)> {
+    let mut recordings = Vec::new();
+
+    for display_directory in subdirectories(directory) {
+        let display = match display_directory.file_name().and_then(|name| name.to_str()) {
+            Some(name) if name.strip_prefix("display").is_some_and(is_number) => name.to_string(),
+            _ => continue,
+        };
+
+        for day_directory in subdirectories(&display_directory) {
+            let is_date = day_directory
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| matches_pattern(name, "dddd-dd-dd"));
+            if !is_date {
+                continue;
+            }
+
+            let entries = match fs::read_dir(&day_directory) {
+                Ok(entries) => entries,
+                Err(_) => continue,
+            };
+            for entry in entries.flatten() {
+                let path = entry.path();
+                let metadata = match entry.metadata() {
+                    Ok(metadata) if metadata.is_file() => metadata,
+                    _ => continue,
+                };
+                if path.file_name().and_then(|name| name.to_str()).is_some_and(|name| is_recording(name, &display)) {
+                    let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                    recordings.push((path, modified, metadata.len()));
+                }
+            }
+        }
+    }
+
+    recordings
+}
+
+fn subdirectories(directory: &Path) -> Vec<PathBuf> {
+    match fs::read_dir(directory) {
+        Ok(entries) => entries
+            .flatten()
+            .filter(|entry| entry.file_type().is_ok_and(|file_type| file_type.is_dir()))
+            .map(|entry| entry.path())
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+// Whether `name` is display<N>-<HH-MM-SS>.<ext> for the display's directory
+fn is_recording(name: &str, display: &str) -> bool {
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some(parts) => parts,
+        None => return false,
+    };
+    RECORDING_EXTENSIONS.contains(&extension)
+        && stem
+            .strip_prefix(display)
+            .and_then(|rest| rest.strip_prefix('-'))
+            .is_some_and(|time| matches_pattern(time, "dd-dd-dd"))
+}
+
+fn is_number(text: &str) -> bool {
+    !text.is_empty() && text.bytes().all(|b| b.is_ascii_digit())
+}
+
+// Match `text` against a pattern where 'd' is a digit and everything else
+// is literal
+fn matches_pattern(text: &str, pattern: &str) -> bool {
+    text.len() == pattern.len()
+        && text
+            .bytes()
+            .zip(pattern.bytes())
+            .all(|(t, p)| if p == b'd' { t.is_ascii_digit() } else { t == p })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    // Empty directory of its own for each test
+    fn test_directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("record-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    fn write_file(path: &Path, size: usize, age_secs: u64) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let file = fs::File::create(path).unwrap();
+        file.set_len(size as u64).unwrap();
+        file.set_modified(SystemTime::now() - Duration::from_secs(age_secs)).unwrap();
+    }
+
+    #[test]
+    fn recording_names_follow_the_layout() {
+        assert!(is_recording("display0-13-05-59.mp4", "display0"));
+        assert!(is_recording("display12-00-00-00.mkv", "display12"));
+        assert!(!is_recording("display1-13-05-59.mp4", "display0"));
+        assert!(!is_recording("display0-13-05-59.mov", "display0"));
+        assert!(!is_recording("display0-holiday.mp4", "display0"));
+        assert!(!is_recording("display0-13-05-59", "display0"));
+        assert!(!is_recording("display0-13-05-599.mp4", "display0"));
+
+        assert!(matches_pattern("2024-02-29", "dddd-dd-dd"));
+        assert!(!matches_pattern("2024-2-29", "dddd-dd-dd"));
+        assert!(!matches_pattern("Holidays", "dddd-dd-dd"));
+        assert!(is_number("3") && !is_number("") && !is_number("3a"));
+    }
+
+    #[test]
+    fn only_files_of_the_layout_are_found() {
+        let directory = test_directory("layout");
+        let ours = [
+            directory.join("display0/2024-05-01/display0-08-00-00.mp4"),
+            directory.join("display1/2024-05-02/display1-09-30-00.mkv"),
+        ];
+        let theirs = [
+            directory.join("holiday.mp4"),
+            directory.join("display0/holiday.mp4"),
+            directory.join("display0/2024-05-01/holiday.mp4"),
+            directory.join("display0/2024-05-01/display1-08-00-00.mp4"),
+            directory.join("display0/Holidays/display0-08-00-00.mp4"),
+            directory.join("Videos/2024-05-01/display0-08-00-00.mp4"),
+            directory.join("displays/2024-05-01/displays-08-00-00.mp4"),
+        ];
+        for path in ours.iter().chain(&theirs) {
+            write_file(path, 10, 0);
+        }
+
+        let mut found: Vec<PathBuf> = find_recordings(&directory).into_iter().map(|(path, _, _)| path).collect();
+        found.sort();
+        assert_eq!(found, ours);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn quota_deletes_the_oldest_recordings_first() {
+        let directory = test_directory("quota");
+        let oldest = directory.join("display0/2024-05-01/display0-08-00-00.mp4");
+        let older = directory.join("display1/2024-05-01/display1-08-00-00.mp4");
+        let newer = directory.join("display0/2024-05-02/display0-08-00-00.mp4");
+        let newest = directory.join("display0/2024-05-02/display0-09-00-00.mp4");
+        let unrelated = directory.join("holiday.mp4");
+        write_file(&oldest, 100, 400);
+        write_file(&older, 100, 300);
+        write_file(&newer, 100, 200);
+        write_file(&newest, 100, 100);
+        write_file(&unrelated, 1000, 1000);
+
+        enforce_quota(&directory, 250);
+
+        assert!(!oldest.exists());
+        assert!(!older.exists());
+        assert!(newer.exists());
+        assert!(newest.exists());
+        assert!(unrelated.exists());
+        // Emptied date directories go too
+        assert!(!directory.join("display0/2024-05-01").exists());
+        assert!(!directory.join("display1/2024-05-01").exists());
+
+        // Within the quota nothing more is deleted
+        enforce_quota(&directory, 250);
+        assert!(newer.exists());
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
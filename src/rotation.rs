@@ -0,0 +1,136 @@
+// Per-stream rotation and flipping, e.g. for portrait-mounted monitors
+//
+// A videoflip element is added in front of the overlays so burned-in text
+// and watermarks stay upright. videoflip swaps width and height in its
+// output caps for quarter turns, so the encoder sees the rotated size.
+
+use log::warn;
+
+use crate::config::StreamConfig;
+
+/// Pipeline fragment to insert before the overlays (empty if not rotated)
+pub fn launch_fragment(config: &StreamConfig) -> String {
+    match method(config) {
+        "none" => String::new(),
+        method => format!("videoflip method={} ! ", method),
+    }
+}
+
+// Combine the flips and the rotation into the single videoflip method with
+// the same effect. Flips are applied first, then the clockwise rotation.
+fn method(config: &StreamConfig) -> &'static str {
+    let quarter_turns = match config.rotate {
+        0 | 90 | 180 | 270 => config.rotate / 90,
+        rotate => {
+            warn!("Unsupported rotation {}, must be 0, 90, 180 or 270", rotate);
+            0
+        }
+    };
+
+    // A vertical flip is a horizontal flip followed by half a turn
+    let (mirrored, flip_turns) = match (config.flip_horizontal, config.flip_vertical) {
+        (false, false) => (false, 0),
+        (true, false) => (true, 0),
+        (false, true) => (true, 2),
+        (true, true) => (false, 2),
+    };
+
+    match (mirrored, (flip_turns + quarter_turns) % 4) {
+        (false, 0) => "none",
+        (false, 1) => "clockwise",
+        (false, 2) => "rotate-180",
+        (false, _) => "counterclockwise",
+        (true, 0) => "horizontal-flip",
+        (true, 1) => "upper-right-diagonal",
+        (true, 2) => "vertical-flip",
+        (true, _) => "upper-left-diagonal",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WIDTH: i32 = 4;
+    const HEIGHT: i32 = 3;
+
+    // Where videoflip moves the pixel at (x, y) of a WIDTH x HEIGHT frame
+    fn videoflip(method: &str, (x, y): (i32, i32)) -> (i32, i32) {
+        match method {
+            "none" => (x, y),
+            "clockwise" => (HEIGHT - 1 - y, x),
+            "rotate-180" => (WIDTH - 1 - x, HEIGHT - 1 - y),
+            "counterclockwise" => (y, WIDTH - 1 - x),
+            "horizontal-flip" => (WIDTH - 1 - x, y),
+            "vertical-flip" => (x, HEIGHT - 1 - y),
+            "upper-left-diagonal" => (y, x),
+            "upper-right-diagonal" => (HEIGHT - 1 - y, WIDTH - 1 - x),
+            other => panic!("unexpected method {}", other),
+        }
+    }
+
+    // The documented behavior: flips first, then clockwise quarter turns
+    fn expected(config: &StreamConfig, (mut x, mut y): (i32, i32)) -> (i32, i32) {
+        if config.flip_horizontal {
+            x = WIDTH - 1 - x;
+        }
+        if config.flip_vertical {
+            y = HEIGHT - 1 - y;
+        }
+        let (mut width, mut height) = (WIDTH, HEIGHT);
+        for _ in 0..config.rotate / 90 {
+            (x, y) = (height - 1 - y, x);
+            (width, height) = (height, width);
+        }
+        (x, y)
+    }
+
+    fn config(rotate: u32, flip_horizontal: bool, flip_vertical: bool) -> StreamConfig {
+        StreamConfig {
+            rotate,
+            flip_horizontal,
+            flip_vertical,
+            ..StreamConfig::default()
+        }
+    }
+
+    #[test]
+    fn every_combination_matches_flipping_then_rotating() {
+        for rotate in [0, 90, 180, 270] {
+            for flip_horizontal in [false, true] {
+                for flip_vertical in [false, true] {
+                    let config = config(rotate, flip_horizontal, flip_vertical);
+                    let method = method(&config);
+                    for y in 0..HEIGHT {
+                        for x in 0..WIDTH {
+                            assert_eq!(
+                                videoflip(method, (x, y)),
+                                expected(&config, (x, y)),
+                                "rotate {} flip h {} v {} -> {} at ({}, {})",
+                                rotate,
+                                flip_horizontal,
+                                flip_vertical,
+                                method,
+                                x,
+                                y
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn unsupported_rotation_is_ignored() {
+        assert_eq!(method(&config(45, false, false)), "none");
+        assert_eq!(method(&config(45, true, false)), "horizontal-flip");
+    }
+
+    #[test]
+    fn no_fragment_without_rotation() {
+        assert_eq!(launch_fragment(&config(0, false, false)), "");
+        assert_eq!(launch_fragment(&config(90, false, false)), "videoflip method=clockwise ! ");
+        assert_eq!(launch_fragment(&config(0, true, true)), "videoflip method=rotate-180 ! ");
+    }
+}
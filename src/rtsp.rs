@@ -4,12 +4,19 @@ use gstreamer::prelude::*;
 use gstreamer_app::AppSrc;
 use gstreamer_rtsp_server::prelude::*;
 use gstreamer_rtsp_server::{RTSPMediaFactory, RTSPServer};
-use log::info;
-use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use log::{error, info, warn};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
 
 use once_cell::sync::OnceCell;
 
+use crate::config::{PlaceholderConfig, StreamConfig};
+use crate::convert::{Colorimetry, PixelFormat};
+use crate::overlay;
+use crate::placeholder;
+use crate::rotation;
+use crate::watermark;
+
 // Initialize GStreamer once
 static GST_INIT: OnceCell<()> = OnceCell::new();
 
@@ -21,14 +28,65 @@ pub fn init() -> Result<()> {
     Ok(())
 }
 
+/// Runs the default GLib main context on its own thread until dropped. The
+/// RTSP server and the once-a-second overlay and watermark refreshes are
+/// dispatched there, whichever outputs are enabled.
+pub struct EventLoop {
+    main_loop: glib::MainLoop,
+    handle: Option<std::thread::JoinHandle<()>>,
+}
+
+impl EventLoop {
+    pub fn start() -> Self {
+        let main_loop = glib::MainLoop::new(None, false);
+        let handle = {
+            let main_loop = main_loop.clone();
+            std::thread::spawn(move || main_loop.run())
+        };
+        Self {
+            main_loop,
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for EventLoop {
+    fn drop(&mut self) {
+        // Quit from inside the loop, so it also stops if it wasn't running yet
+        let main_loop = self.main_loop.clone();
+        self.main_loop.context().invoke(move || main_loop.quit());
+        if let Some(Err(e)) = self.handle.take().map(|handle| handle.join()) {
+            error!("Error joining GLib main loop thread: {:?}", e);
+        }
+    }
+}
+
+/// Encoding tail shared by every media pipeline, from raw video to RTP
+pub const ENCODER_LAUNCH: &str =
+    "x264enc tune=zerolatency speed-preset=ultrafast key-int-max=30 ! rtph264pay name=pay0 pt=96";
+
+/// An appsrc in a media pipeline, fed by one capture thread
+pub struct MediaSource {
+    /// Name of the appsrc element in the launch string
+    pub element: String,
+    /// Display name, shown on the placeholder
+    pub name: String,
+    pub width: u32,
+    pub height: u32,
+    /// Colorimetry of the YUV frames pushed into the appsrc
+    pub colorimetry: Colorimetry,
+}
+
 pub struct RtspServer {
-    server: RTSPServer,
+    // Only held so the server lives as long as its mounts are served
+    _server: RTSPServer,
     mounts: gstreamer_rtsp_server::RTSPMountPoints,
-    main_loop: glib::MainLoop,
+    placeholder: PlaceholderConfig,
+    pixel_format: PixelFormat,
 }
 
 impl RtspServer {
-    pub fn new(port: u16) -> Result<Self> {
+    pub fn new(port: u16, placeholder: PlaceholderConfig, pixel_format: PixelFormat) -> Result<Self> {
         // Always try to initialize GStreamer
         init()?;
         
@@ -36,109 +94,244 @@ impl RtspServer {
         server.set_service(&port.to_string());
         
         let mounts = server.mount_points().context("Failed to get mount points")?;
-        let main_loop = glib::MainLoop::new(None, false);
         
-        // Start the server
+        // Start the server; clients are served by the EventLoop
         let _ = server.attach(None);
         
         info!("RTSP server started on port {}", port);
         
-        // Start the GLib main loop in a separate thread
-        let main_loop_clone = main_loop.clone();
-        std::thread::spawn(move || {
-            main_loop_clone.run();
-        });
-        
         Ok(Self {
-            server,
+            _server: server,
             mounts,
-            main_loop,
+            placeholder,
+            pixel_format,
         })
     }
     
-    pub fn add_stream(&self, path: &str, width: u32, height: u32) -> Result<RtspMount> {
+    pub fn add_stream(
+        &self,
+        path: &str,
+        name: &str,
+        width: u32,
+        height: u32,
+        frame_rate: u32,
+        stream: &StreamConfig,
+    ) -> Result<RtspMount> {
+        // Create an AppSrc-based pipeline that will receive frames from our capture thread
+        // The raw caps come from the appsrc so the resolution can change
+        // when the capture is restarted after a display mode change
+        let launch_str = stream_launch("appsrc name=source is-live=true format=time", stream);
+        
+        let source = MediaSource {
+            element: "source".to_string(),
+            name: name.to_string(),
+            width,
+            height,
+            colorimetry: stream.colorimetry(),
+        };
+        
+        let mut mounts = self.add_media(path, &launch_str, vec![source], frame_rate, Some((name, stream)))?;
+        Ok(mounts.remove(0))
+    }
+    
+    /// Add a stream captured by a GStreamer source element instead of a
+    /// capture thread. `source_launch` produces raw video; everything after
+    /// it is the same as for `add_stream`.
+    pub fn add_native_stream(
+        &self,
+        path: &str,
+        name: &str,
+        source_launch: &str,
+        stream: &StreamConfig,
+    ) -> Result<()> {
+        let launch_str = stream_launch(source_launch, stream);
+        self.add_media(path, &launch_str, Vec::new(), 0, Some((name, stream)))?;
+        Ok(())
+    }
+    
+    /// Add a stream combining several capture threads. `launch_str` must
+    /// contain an appsrc named after each source's element and end in
+    /// `ENCODER_LAUNCH`; one mount per source is returned, in order.
+    pub fn add_composite_stream(
+        &self,
+        path: &str,
+        launch_str: &str,
+        sources: Vec<MediaSource>,
+        frame_rate: u32,
+    ) -> Result<Vec<RtspMount>> {
+        self.add_media(path, launch_str, sources, frame_rate, None)
+    }
+    
+    fn add_media(
+        &self,
+        path: &str,
+        launch_str: &str,
+        sources: Vec<MediaSource>,
+        frame_rate: u32,
+        stream: Option<(&str, &StreamConfig)>,
+    ) -> Result<Vec<RtspMount>> {
         // Create a factory for this path
         let factory = RTSPMediaFactory::new();
         factory.set_shared(true);
+        factory.set_launch(launch_str);
         
-        // Pipeline name isn't used with our current implementation
-        
-        // Create an AppSrc-based pipeline that will receive frames from our capture thread
-        let launch_str = format!(
-            "( appsrc name=source is-live=true format=time ! \
-             video/x-raw,format=BGR,width={},height={},framerate=30/1 ! \
-             videoconvert ! video/x-raw,format=I420 ! \
-             x264enc tune=zerolatency speed-preset=ultrafast key-int-max=30 ! \
-             rtph264pay name=pay0 pt=96 )",
-            width, height
-        );
+        let states: Vec<Arc<MountState>> = sources
+            .iter()
+            .map(|source| Arc::new(MountState::new(source, frame_rate, self.pixel_format)))
+            .collect();
         
-        factory.set_launch(&launch_str);
+        // The media (and its appsrcs) only exists while clients are connected,
+        // so the mounts pick up their appsrc whenever the factory configures one
+        let weak_states: Vec<(String, Weak<MountState>)> = sources
+            .iter()
+            .zip(&states)
+            .map(|(source, state)| (source.element.clone(), Arc::downgrade(state)))
+            .collect();
+        let stream_path = path.to_string();
+        let stream_config = stream.map(|(name, config)| (name.to_string(), config.clone()));
+        factory.connect_media_configure(move |_, media| {
+            let element = media.element();
+            let bin = match element.dynamic_cast_ref::<gst::Bin>() {
+                Some(bin) => bin,
+                None => {
+                    error!("Media for {} is not a bin", stream_path);
+                    return;
+                }
+            };
+            
+            for (element_name, weak_state) in &weak_states {
+                let state = match weak_state.upgrade() {
+                    Some(state) => state,
+                    None => continue,
+                };
+                
+                let appsrc = match bin
+                    .by_name_recurse_up(element_name)
+                    .and_then(|source| source.downcast::<AppSrc>().ok())
+                {
+                    Some(appsrc) => appsrc,
+                    None => {
+                        error!("No appsrc {} found in media for {}", element_name, stream_path);
+                        continue;
+                    }
+                };
+                
+                state.attach(appsrc);
+                
+                let weak_state = weak_state.clone();
+                media.connect_unprepared(move |_| {
+                    if let Some(state) = weak_state.upgrade() {
+                        *state.appsrc.lock().unwrap() = None;
+                    }
+                });
+            }
+            
+            if let Some((stream_name, stream_config)) = &stream_config {
+                overlay::attach(bin, &stream_config.overlay, stream_name);
+                watermark::attach(bin, &stream_config.watermark);
+            }
+            
+            info!("Client media configured for {}", stream_path);
+        });
         
         // Add factory to mount points
         self.mounts.add_factory(path, factory);
         
-        info!("Added RTSP stream at path: {}", path);
-        
-        // Create a pipeline for sending frames
-        let pipeline_str = format!(
-            "appsrc name=source is-live=true format=time ! \
-             video/x-raw,format=BGR,width={},height={},framerate=30/1 ! \
-             fakesink",
-            width, height
-        );
-        
-        let pipeline = gst::parse_launch(&pipeline_str)
-            .context("Failed to create pipeline")?;
-        
-        // Convert to a bin to use by_name
-        let pipeline_bin = pipeline.clone().dynamic_cast::<gst::Bin>()
-            .map_err(|_| anyhow::anyhow!("Failed to cast pipeline to Bin"))?;
-        
-        let appsrc = pipeline_bin.by_name("source")
-            .context("Failed to find appsrc element")?
-            .downcast::<AppSrc>()
-            .map_err(|_| anyhow::anyhow!("Failed to downcast to AppSrc"))?;
-        
-        // Configure the appsrc
-        appsrc.set_format(gst::Format::Time);
-        appsrc.set_is_live(true);
-        appsrc.set_max_bytes(0);
-        
-        // Create the caps for the video format
-        let caps = gst::Caps::builder("video/x-raw")
-            .field("format", &"BGR")
-            .field("width", &(width as i32))
-            .field("height", &(height as i32))
-            .field("framerate", &gst::Fraction::new(30, 1))
-            .build();
-        
-        appsrc.set_caps(Some(&caps));
+        // Keep clients fed with a placeholder whenever captured frames stop
+        for (source, state) in sources.into_iter().zip(&states) {
+            start_placeholder_watchdog(state, self.placeholder.clone(), self.pixel_format, path, source);
+        }
         
-        // Set to playing state
-        pipeline.set_state(gst::State::Playing).context("Failed to set pipeline to playing state")?;
+        info!("Added RTSP stream at path: {}", path);
         
-        Ok(RtspMount {
-            appsrc: Arc::new(Mutex::new(appsrc)),
-        })
+        Ok(states.into_iter().map(|state| RtspMount { state }).collect())
+    }
+    
+    pub fn remove_stream(&self, path: &str) {
+        self.mounts.remove_factory(path);
+        info!("Removed RTSP stream at path: {}", path);
     }
 }
 
-impl Drop for RtspServer {
-    fn drop(&mut self) {
-        self.main_loop.quit();
-    }
+// Launch string of a single-display media
+fn stream_launch(source: &str, stream: &StreamConfig) -> String {
+    format!("( {}{} )", video_launch(source, stream), ENCODER_LAUNCH)
 }
 
-#[derive(Clone)]
-pub struct RtspMount {
-    appsrc: Arc<Mutex<AppSrc>>,
+/// Raw video part of a single-display pipeline: `source` produces raw video,
+/// which is converted with the stream's colorimetry (which the encoder writes
+/// into the VUI), rotated and goes through the stream's overlays. Ends in
+/// `! ` so the encoder can follow directly.
+pub fn video_launch(source: &str, stream: &StreamConfig) -> String {
+    format!(
+        "{} ! videoconvert ! video/x-raw,format=I420,colorimetry={} ! {}{}{}",
+        source,
+        stream.colorimetry().caps_name(),
+        rotation::launch_fragment(stream),
+        overlay::launch_fragment(&stream.overlay),
+        watermark::launch_fragment(&stream.watermark),
+    )
 }
 
-impl RtspMount {
-    pub fn push_frame(&self, frame_data: &[u8]) -> Result<()> {
+// State shared between a mount, its media factory and its placeholder watchdog
+struct MountState {
+    appsrc: Mutex<Option<AppSrc>>,
+    caps: Mutex<gst::Caps>,
+    // When the capture feeding the mount stopped working, None while it works
+    unavailable_since: Mutex<Option<Instant>>,
+}
+
+impl MountState {
+    fn new(source: &MediaSource, frame_rate: u32, pixel_format: PixelFormat) -> Self {
+        // Create the caps for the video format
+        let mut caps = gst::Caps::builder("video/x-raw")
+            .field("format", pixel_format.caps_name())
+            .field("width", source.width as i32)
+            .field("height", source.height as i32)
+            .field("framerate", gst::Fraction::new(frame_rate as i32, 1))
+            .build();
+        if pixel_format != PixelFormat::Bgr {
+            caps.make_mut().set("colorimetry", source.colorimetry.caps_name());
+        }
+        
+        Self {
+            appsrc: Mutex::new(None),
+            caps: Mutex::new(caps),
+            // Unavailable until a capture thread reports in
+            unavailable_since: Mutex::new(Some(Instant::now())),
+        }
+    }
+    
+    // Configure the appsrc and start feeding it; timestamps follow the
+    // pipeline's running time so they stay consistent however irregularly
+    // frames arrive
+    fn attach(&self, appsrc: AppSrc) {
+        appsrc.set_format(gst::Format::Time);
+        appsrc.set_is_live(true);
+        appsrc.set_do_timestamp(true);
+        appsrc.set_max_bytes(0);
+        appsrc.set_caps(Some(&*self.caps.lock().unwrap()));
+        
+        *self.appsrc.lock().unwrap() = Some(appsrc);
+    }
+    
+    fn frame_size(&self) -> (u32, u32) {
+        let caps = self.caps.lock().unwrap();
+        let structure = caps.structure(0).expect("Stream caps have no structure");
+        let width = structure.get::<i32>("width").unwrap_or(0);
+        let height = structure.get::<i32>("height").unwrap_or(0);
+        (width as u32, height as u32)
+    }
+    
+    fn push(&self, frame_data: &[u8], duration: Duration) -> Result<()> {
         let appsrc = self.appsrc.lock().unwrap();
         
+        // No clients connected, nothing to feed
+        let appsrc = match appsrc.as_ref() {
+            Some(appsrc) => appsrc,
+            None => return Ok(()),
+        };
+        
         // Create a buffer from the frame data
         let mut buffer = gst::Buffer::with_size(frame_data.len())
             .context("Failed to allocate buffer")?;
@@ -147,21 +340,178 @@ impl RtspMount {
             let buffer_ref = buffer.get_mut().unwrap();
             buffer_ref.copy_from_slice(0, frame_data)
                 .expect("Failed to copy data into buffer");
+            
+            // The PTS is stamped by the appsrc (do-timestamp), we only
+            // need to tell downstream how long the frame is valid for
+            buffer_ref.set_duration(gst::ClockTime::from_nseconds(duration.as_nanos() as u64));
         }
         
-        // Set buffer timestamp based on current time
-        let pts = gst::ClockTime::from_nseconds(
-            std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap_or(Duration::from_secs(0))
-                .as_nanos() as u64
-        );
-        buffer.get_mut().unwrap().set_pts(pts);
-        
         // Push the buffer to the appsrc
         appsrc.push_buffer(buffer)
             .map_err(|e| anyhow::anyhow!("Failed to push buffer: {:?}", e))?;
         
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+fn start_placeholder_watchdog(
+    state: &Arc<MountState>,
+    config: PlaceholderConfig,
+    pixel_format: PixelFormat,
+    path: &str,
+    source: MediaSource,
+) {
+    if !config.enabled {
+        return;
+    }
+    
+    let weak_state = Arc::downgrade(state);
+    let path = path.to_string();
+    std::thread::spawn(move || {
+        run_placeholder_watchdog(weak_state, config, pixel_format, path, source);
+    });
+}
+
+// Sends placeholder frames while the capture feeding the mount is failing
+// or has nothing to capture. A static screen is healthy even though few
+// frames are pushed. Exits once the mount has been dropped.
+fn run_placeholder_watchdog(
+    state: Weak<MountState>,
+    config: PlaceholderConfig,
+    pixel_format: PixelFormat,
+    path: String,
+    source: MediaSource,
+) {
+    let interval = placeholder::frame_interval(&config);
+    let timeout = Duration::from_secs(config.timeout as u64);
+    let mut cached_frame: Option<((u32, u32), Vec<u8>)> = None;
+    let mut showing = false;
+    
+    loop {
+        std::thread::sleep(interval);
+        
+        let state = match state.upgrade() {
+            Some(state) => state,
+            None => break,
+        };
+        
+        let unavailable_for = state.unavailable_since.lock().unwrap().map(|since| since.elapsed());
+        if unavailable_for.is_none_or(|unavailable_for| unavailable_for < timeout) {
+            if showing {
+                info!("Capture for {} resumed", path);
+                showing = false;
+            }
+            continue;
+        }
+        
+        if !showing {
+            warn!("Capture for {} unavailable for {:?}, sending placeholder", path, timeout);
+            showing = true;
+        }
+        
+        // Only render once per resolution
+        let frame_size = state.frame_size();
+        let frame = match &cached_frame {
+            Some((size, frame)) if *size == frame_size => frame,
+            _ => {
+                let frame = placeholder::render(
+                    &config,
+                    &source.name,
+                    frame_size.0,
+                    frame_size.1,
+                    pixel_format,
+                    &source.colorimetry,
+                );
+                &cached_frame.insert((frame_size, frame)).1
+            }
+        };
+        
+        if let Err(e) = state.push(frame, interval) {
+            error!("Failed to push placeholder frame to {}: {}", path, e);
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RtspMount {
+    state: Arc<MountState>,
+}
+
+impl RtspMount {
+    /// Feed the appsrc of a pipeline outside the RTSP server, with the same
+    /// caps handling and placeholder frames as a mount of the server
+    pub fn for_appsrc(
+        appsrc: AppSrc,
+        source: MediaSource,
+        frame_rate: u32,
+        pixel_format: PixelFormat,
+        placeholder: PlaceholderConfig,
+        path: &str,
+    ) -> Self {
+        let state = Arc::new(MountState::new(&source, frame_rate, pixel_format));
+        state.attach(appsrc);
+        start_placeholder_watchdog(&state, placeholder, pixel_format, path, source);
+        Self { state }
+    }
+    
+    /// Renegotiate the stream for a new frame size
+    pub fn set_resolution(&self, width: u32, height: u32) {
+        let mut caps = self.state.caps.lock().unwrap();
+        {
+            let caps = caps.make_mut();
+            caps.set("width", width as i32);
+            caps.set("height", height as i32);
+        }
+        
+        if let Some(appsrc) = self.state.appsrc.lock().unwrap().as_ref() {
+            appsrc.set_caps(Some(&*caps));
+        }
+        
+        info!("Stream resolution changed to {}x{}", width, height);
+    }
+    
+    /// Feed pushed frames to `appsrc`, e.g. again after `disconnect`
+    pub fn connect(&self, appsrc: AppSrc) {
+        self.state.attach(appsrc);
+    }
+    
+    /// Drop pushed frames until connected again
+    pub fn disconnect(&self) {
+        *self.state.appsrc.lock().unwrap() = None;
+    }
+    
+    /// Report whether the capture feeding the mount works; the placeholder
+    /// is shown once it has been unavailable for the configured timeout
+    pub fn set_available(&self, available: bool) {
+        let mut unavailable_since = self.state.unavailable_since.lock().unwrap();
+        if available {
+            *unavailable_since = None;
+        } else if unavailable_since.is_none() {
+            *unavailable_since = Some(Instant::now());
+        }
+    }
+    
+    /// Push a frame in the server's pixel format, expected to be displayed for `duration`
+    pub fn push_frame(&self, frame_data: &[u8], duration: Duration) -> Result<()> {
+        self.state.push(frame_data, duration)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn event_loop_dispatches_default_context_sources() {
+        let event_loop = EventLoop::start();
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        glib::timeout_add(Duration::from_millis(10), move || {
+            let _ = tx.lock().unwrap().send(());
+            glib::Continue(false)
+        });
+        assert!(rx.recv_timeout(Duration::from_secs(5)).is_ok());
+        drop(event_loop);
+    }
+}
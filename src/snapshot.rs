@@ -0,0 +1,379 @@
+// JPEG snapshots over HTTP
+//
+// Every display's capture thread keeps a copy of the most recent frame;
+// /snapshot/display<N>.jpg encodes it on request, so NVRs and dashboards
+// polling still images don't need an RTSP session. Frames are only copied
+// while someone is asking for them: the first request after a quiet spell
+// waits for the next captured frame.
+
+use anyhow::{Context, Result};
+use jpeg_encoder::{ColorType, Encoder};
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+use tiny_http::{Request, Response};
+
+use crate::config::Config;
+use crate::http::{self, HttpServer};
+
+/// Latest frame of each display, by display slot
+pub type Snapshots = Arc<Mutex<HashMap<usize, Arc<LatestFrame>>>>;
+
+const PATH_PREFIX: &str = "/snapshot/";
+
+// Keep copying frames for this long after the last request, so pollers and
+// MJPEG clients get a current frame without waiting
+const KEEP_STORING: Duration = Duration::from_secs(10);
+
+// How long a request waits for the capture thread to store a current frame
+const STORE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The most recent frame of a capture thread, tightly packed BGRA
+#[derive(Default)]
+pub struct LatestFrame {
+    state: Mutex<State>,
+    stored: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    frame: Option<Frame>,
+    /// When a frame was last asked for
+    requested: Option<Instant>,
+    /// Frames were skipped since `frame` was stored
+    outdated: bool,
+}
+
+impl State {
+    fn wanted(&self, now: Instant) -> bool {
+        self.requested.is_some_and(|requested| now.duration_since(requested) < KEEP_STORING)
+    }
+
+    fn current(&self) -> Option<&Frame> {
+        self.frame.as_ref().filter(|_| !self.outdated)
+    }
+}
+
+#[derive(Clone)]
+pub struct Frame {
+    /// Increases with every stored frame
+    pub sequence: u64,
+    width: u32,
+    height: u32,
+    bgra: Arc<Vec<u8>>,
+}
+
+impl LatestFrame {
+    /// Replace the stored frame with a copy of `bgra`, whose rows may be
+    /// padded. Does nothing unless a frame was asked for recently.
+    pub fn store(&self, bgra: &[u8], width: u32, height: u32) {
+        self.store_at(bgra, width, height, Instant::now())
+    }
+
+    /// Whether the stored frame is behind the screen and a request is
+    /// waiting for a current one, so the next frame should be stored even
+    /// if it is unchanged
+    pub fn needs_frame(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.outdated && state.wanted(Instant::now())
+    }
+
+    fn store_at(&self, bgra: &[u8], width: u32, height: u32, now: Instant) {
+        let mut state = self.state.lock().unwrap();
+        if !state.wanted(now) {
+            state.outdated = true;
+            return;
+        }
+
+        let row_bytes = width as usize * 4;
+        let stride = if height == 0 { row_bytes } else { (bgra.len() / height as usize).max(row_bytes) };
+        if bgra.len() < stride * (height as usize).saturating_sub(1) + row_bytes {
+            return;
+        }
+
+        let sequence = state.frame.as_ref().map_or(0, |f| f.sequence) + 1;
+
+        // Reuse the previous buffer unless a request is still encoding it
+        let mut buffer = match state.frame.take().map(|f| Arc::try_unwrap(f.bgra)) {
+            Some(Ok(buffer)) => buffer,
+            _ => Vec::new(),
+        };
+        buffer.clear();
+        for row in bgra.chunks(stride).take(height as usize) {
+            buffer.extend_from_slice(&row[..row_bytes]);
+        }
+
+        state.frame = Some(Frame {
+            sequence,
+            width,
+            height,
+            bgra: Arc::new(buffer),
+        });
+        state.outdated = false;
+        self.stored.notify_all();
+    }
+
+    /// The current frame, waiting up to `timeout` for the capture thread if
+    /// frames weren't being stored. Falls back to an outdated frame, e.g.
+    /// when the backend only reports changes and the screen is static.
+    pub fn get(&self, timeout: Duration) -> Option<Frame> {
+        let mut state = self.state.lock().unwrap();
+        state.requested = Some(Instant::now());
+        let (state, _) = self
+            .stored
+            .wait_timeout_while(state, timeout, |state| state.current().is_none())
+            .unwrap();
+        state.frame.clone()
+    }
+
+    /// Wait up to `timeout` for a frame newer than `sequence`
+    pub fn wait_newer(&self, sequence: u64, timeout: Duration) -> Option<Frame> {
+        let mut state = self.state.lock().unwrap();
+        state.requested = Some(Instant::now());
+        let (state, _) = self
+            .stored
+            .wait_timeout_while(state, timeout, |state| {
+                state.current().is_none_or(|f| f.sequence <= sequence)
+            })
+            .unwrap();
+        state.current().filter(|f| f.sequence > sequence).cloned()
+    }
+}
+
+/// Serve /snapshot/display<N>.jpg on the HTTP server
+pub fn register(http_server: &HttpServer, config: &Config, snapshots: Snapshots) {
+    let default_quality = config.snapshot.quality.clamp(1, 100);
+    http_server.route(PATH_PREFIX, move |request| handle(request, &snapshots, default_quality));
+}
+
+fn handle(request: Request, snapshots: &Snapshots, default_quality: u8) {
+    let url = request.url().to_string();
+    let path = url.split('?').next().unwrap_or("");
+
+    let slot = path
+        .strip_prefix(PATH_PREFIX)
+        .and_then(|name| name.strip_prefix("display"))
+        .and_then(|name| name.strip_suffix(".jpg"))
+        .and_then(|slot| slot.parse::<usize>().ok());
+    let latest_frame = slot.and_then(|slot| snapshots.lock().unwrap().get(&slot).cloned());
+    let frame = match latest_frame {
+        Some(latest_frame) => latest_frame.get(STORE_TIMEOUT),
+        None => {
+            let _ = request.respond(Response::empty(404));
+            return;
+        }
+    };
+
+    // Nothing captured yet, e.g. right after the display was connected
+    let frame = match frame {
+        Some(frame) => frame,
+        None => {
+            let _ = request.respond(Response::empty(503));
+            return;
+        }
+    };
+
+    let quality = http::query_param(&url, "quality")
+        .and_then(|quality| quality.parse::<u8>().ok())
+        .map(|quality| quality.clamp(1, 100))
+        .unwrap_or(default_quality);
+    let width = http::query_param(&url, "width").and_then(|width| width.parse::<u32>().ok());
+
+    debug!("Snapshot of {} requested by {}", path, request.remote_addr().map(|a| a.to_string()).unwrap_or_default());
+
+    match encode(&frame, width, quality) {
+        Ok(jpeg) => {
+            let response = Response::from_data(jpeg)
+                .with_header(http::header("Content-Type", "image/jpeg"))
+                .with_header(http::header("Cache-Control", "no-cache, no-store"));
+            let _ = request.respond(response);
+        }
+        Err(e) => {
+            warn!("Failed to encode snapshot {}: {:#}", path, e);
+            let _ = request.respond(Response::empty(500));
+        }
+    }
+}
+
+/// Encode the frame as JPEG, scaled down to `width` if it is narrower
+pub fn encode(frame: &Frame, width: Option<u32>, quality: u8) -> Result<Vec<u8>> {
+    let scaled;
+    let (bgra, width, height) = match width {
+        Some(width) if width > 0 && width < frame.width => {
+            let height = ((frame.height as u64 * width as u64 + frame.width as u64 / 2) / frame.width as u64).max(1) as u32;
+            scaled = downscale(&frame.bgra, frame.width, frame.height, width, height);
+            (scaled.as_slice(), width, height)
+        }
+        _ => (frame.bgra.as_slice(), frame.width, frame.height),
+    };
+
+    let width = u16::try_from(width).context("Snapshot is too wide for JPEG")?;
+    let height = u16::try_from(height).context("Snapshot is too tall for JPEG")?;
+
+    let mut jpeg = Vec::new();
+    Encoder::new(&mut jpeg, quality)
+        .encode(bgra, width, height, ColorType::Bgra)
+        .context("JPEG encoding failed")?;
+    Ok(jpeg)
+}
+
+// Box filter: every output pixel is the average of the source pixels it covers
+fn downscale(bgra: &[u8], width: u32, height: u32, out_width: u32, out_height: u32) -> Vec<u8> {
+    let (width, height) = (width as usize, height as usize);
+    let (out_width, out_height) = (out_width as usize, out_height as usize);
+    let mut out = vec![0u8; out_width * out_height * 4];
+
+    for oy in 0..out_height {
+        let y0 = oy * height / out_height;
+        let y1 = ((oy + 1) * height / out_height).max(y0 + 1);
+        for ox in 0..out_width {
+            let x0 = ox * width / out_width;
+            let x1 = ((ox + 1) * width / out_width).max(x0 + 1);
+
+            let mut sum = [0u32; 4];
+            for y in y0..y1 {
+                let row = &bgra[(y * width + x0) * 4..(y * width + x1) * 4];
+                for pixel in row.chunks_exact(4) {
+                    for (total, value) in sum.iter_mut().zip(pixel) {
+                        *total += *value as u32;
+                    }
+                }
+            }
+
+            let count = ((y1 - y0) * (x1 - x0)) as u32;
+            let dst = &mut out[(oy * out_width + ox) * 4..][..4];
+            for (value, total) in dst.iter_mut().zip(sum) {
+                *value = ((total + count / 2) / count) as u8;
+            }
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // BGRA frame whose pixels come from `pixel(x, y)`
+    fn frame(width: u32, height: u32, pixel: impl Fn(u32, u32) -> [u8; 4]) -> Vec<u8> {
+        (0..height).flat_map(|y| (0..width).map(move |x| (x, y))).flat_map(|(x, y)| pixel(x, y)).collect()
+    }
+
+    #[test]
+    fn same_size_is_a_copy() {
+        let bgra = frame(7, 5, |x, y| [x as u8, y as u8, (x * y) as u8, 255]);
+        assert_eq!(downscale(&bgra, 7, 5, 7, 5), bgra);
+    }
+
+    #[test]
+    fn halving_averages_blocks_of_four() {
+        let bgra = frame(4, 2, |x, y| [(x * 10 + y * 100) as u8, 0, 255, 255]);
+        let out = downscale(&bgra, 4, 2, 2, 1);
+        // (0 + 10 + 100 + 110) / 4 and (20 + 30 + 120 + 130) / 4
+        assert_eq!(out, vec![55, 0, 255, 255, 75, 0, 255, 255]);
+    }
+
+    #[test]
+    fn averages_are_rounded() {
+        // 0 and 1 average to 0.5, rounded up
+        let bgra = frame(2, 1, |x, _| [x as u8, 0, 0, 0]);
+        assert_eq!(downscale(&bgra, 2, 1, 1, 1), vec![1, 0, 0, 0]);
+    }
+
+    #[test]
+    fn uneven_ratios_cover_every_source_pixel() {
+        // A single bright column at the right edge must still show up
+        let bgra = frame(10, 3, |x, _| if x == 9 { [255; 4] } else { [0; 4] });
+        let out = downscale(&bgra, 10, 3, 3, 1);
+        assert_eq!(out.len(), 3 * 4);
+        assert_eq!(&out[..8], &[0; 8]);
+        // The last output pixel covers columns 6-9
+        assert_eq!(&out[8..], &[64; 4]);
+    }
+
+    #[test]
+    fn uniform_frame_stays_uniform() {
+        let bgra = frame(1920, 1080, |_, _| [12, 34, 56, 255]);
+        let out = downscale(&bgra, 1920, 1080, 333, 187);
+        assert_eq!(out.len(), 333 * 187 * 4);
+        assert!(out.chunks_exact(4).all(|pixel| pixel == [12, 34, 56, 255]));
+    }
+
+    #[test]
+    fn frames_are_only_stored_after_a_request() {
+        let latest_frame = LatestFrame::default();
+        let bgra = frame(4, 2, |_, _| [1, 2, 3, 255]);
+        let start = Instant::now();
+
+        latest_frame.store_at(&bgra, 4, 2, start);
+        assert!(!latest_frame.needs_frame());
+        assert!(latest_frame.get(Duration::ZERO).is_none());
+
+        // The request above asks the capture thread for a frame
+        assert!(latest_frame.needs_frame());
+        latest_frame.state.lock().unwrap().requested = Some(start);
+        latest_frame.store_at(&bgra, 4, 2, start);
+        let stored = latest_frame.get(Duration::ZERO).unwrap();
+        assert_eq!((stored.sequence, stored.width, stored.height), (1, 4, 2));
+        assert_eq!(*stored.bgra, bgra);
+
+        // Nobody asked for a while
+        latest_frame.state.lock().unwrap().requested = Some(start);
+        latest_frame.store_at(&bgra, 4, 2, start + KEEP_STORING);
+        assert!(latest_frame.state.lock().unwrap().outdated);
+        assert!(latest_frame.wait_newer(0, Duration::ZERO).is_none());
+
+        // An outdated frame is still better than nothing
+        assert_eq!(latest_frame.get(Duration::ZERO).unwrap().sequence, 1);
+        assert!(latest_frame.needs_frame());
+    }
+
+    #[test]
+    fn requests_wait_for_the_capture_thread() {
+        let latest_frame = Arc::new(LatestFrame::default());
+        let bgra = frame(4, 2, |x, y| [x as u8, y as u8, 0, 255]);
+
+        let capture = {
+            let latest_frame = latest_frame.clone();
+            let bgra = bgra.clone();
+            std::thread::spawn(move || {
+                while !latest_frame.state.lock().unwrap().wanted(Instant::now()) {
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+                latest_frame.store(&bgra, 4, 2);
+            })
+        };
+
+        let stored = latest_frame.get(Duration::from_secs(10)).unwrap();
+        assert_eq!(*stored.bgra, bgra);
+        capture.join().unwrap();
+    }
+
+    #[test]
+    fn padded_rows_are_packed() {
+        let latest_frame = LatestFrame::default();
+        latest_frame.state.lock().unwrap().requested = Some(Instant::now());
+        // 2x2 with 4 bytes of padding per row
+        let bgra = [1, 1, 1, 1, 2, 2, 2, 2, 0, 0, 0, 0, 3, 3, 3, 3, 4, 4, 4, 4, 0, 0, 0, 0];
+        latest_frame.store(&bgra, 2, 2);
+        let stored = latest_frame.get(Duration::ZERO).unwrap();
+        assert_eq!(*stored.bgra, [1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4]);
+    }
+
+    #[test]
+    fn encode_scales_only_down() {
+        let bgra = frame(64, 32, |x, y| [x as u8 * 4, y as u8 * 8, 0, 255]);
+        let frame = Frame {
+            sequence: 1,
+            bgra: Arc::new(bgra),
+            width: 64,
+            height: 32,
+        };
+        let full = encode(&frame, None, 80).unwrap();
+        assert_eq!(&full[..2], &[0xff, 0xd8]);
+        assert_eq!(encode(&frame, Some(640), 80).unwrap(), full);
+        assert!(encode(&frame, Some(16), 80).unwrap().len() < full.len());
+    }
+}
@@ -0,0 +1,345 @@
+// Minimal MPEG transport stream muxer: one program with a single H.264
+// stream, which is all HLS segments need
+
+const PACKET_SIZE: usize = 188;
+const PAYLOAD_SIZE: usize = PACKET_SIZE - 4;
+
+const PAT_PID: u16 = 0x0000;
+const PMT_PID: u16 = 0x1000;
+const VIDEO_PID: u16 = 0x0100;
+const PROGRAM_NUMBER: u16 = 1;
+const STREAM_TYPE_H264: u8 = 0x1b;
+
+/// How far presentation times lead the PCR, in 90 kHz ticks (700 ms), so
+/// decoders have time to buffer before showing a frame
+const PTS_DELAY: u64 = 63_000;
+
+// Access unit delimiter, which some players expect at the start of every frame
+const ACCESS_UNIT_DELIMITER: [u8; 6] = [0x00, 0x00, 0x00, 0x01, 0x09, 0xf0];
+
+pub struct TsMuxer {
+    pat_continuity: u8,
+    pmt_continuity: u8,
+    video_continuity: u8,
+}
+
+impl TsMuxer {
+    pub fn new() -> Self {
+        Self {
+            pat_continuity: 0,
+            pmt_continuity: 0,
+            video_continuity: 0,
+        }
+    }
+
+    /// Write the program tables; every segment must start with them
+    pub fn write_tables(&mut self, out: &mut Vec<u8>) {
+        let mut pat = Vec::with_capacity(16);
+        pat.extend_from_slice(&PROGRAM_NUMBER.to_be_bytes());
+        pat.extend_from_slice(&(0xe000 | PMT_PID).to_be_bytes());
+        write_section(out, PAT_PID, &mut self.pat_continuity, 0x00, 1, &pat);
+
+        let mut pmt = Vec::with_capacity(16);
+        pmt.extend_from_slice(&(0xe000 | VIDEO_PID).to_be_bytes()); // PCR PID
+        pmt.extend_from_slice(&0xf000u16.to_be_bytes()); // No program descriptors
+        pmt.push(STREAM_TYPE_H264);
+        pmt.extend_from_slice(&(0xe000 | VIDEO_PID).to_be_bytes());
+        pmt.extend_from_slice(&0xf000u16.to_be_bytes()); // No stream descriptors
+        write_section(out, PMT_PID, &mut self.pmt_continuity, 0x02, PROGRAM_NUMBER, &pmt);
+    }
+
+    /// Write one H.264 access unit (Annex B byte stream) presented at
+    /// `time_90khz`; there are no B-frames, so decode time is the same
+    pub fn write_frame(&mut self, out: &mut Vec<u8>, frame: &[u8], time_90khz: u64, keyframe: bool) {
+        let pts = (time_90khz + PTS_DELAY) & 0x1_ffff_ffff;
+
+        // PES header: video stream, unbounded length, data aligned, PTS only
+        let mut pes = Vec::with_capacity(14 + ACCESS_UNIT_DELIMITER.len() + frame.len());
+        pes.extend_from_slice(&[0x00, 0x00, 0x01, 0xe0, 0x00, 0x00, 0x84, 0x80, 0x05]);
+        pes.push(0x21 | ((pts >> 29) & 0x0e) as u8);
+        pes.push((pts >> 22) as u8);
+        pes.push(0x01 | ((pts >> 14) & 0xfe) as u8);
+        pes.push((pts >> 7) as u8);
+        pes.push(0x01 | ((pts << 1) & 0xfe) as u8);
+        if !starts_with_delimiter(frame) {
+            pes.extend_from_slice(&ACCESS_UNIT_DELIMITER);
+        }
+        pes.extend_from_slice(frame);
+
+        // The first packet carries the clock reference and marks keyframes
+        // as places to start decoding
+        let pcr_base = time_90khz & 0x1_ffff_ffff;
+        let mut first_fields = vec![if keyframe { 0x50 } else { 0x10 }];
+        first_fields.extend_from_slice(&[
+            (pcr_base >> 25) as u8,
+            (pcr_base >> 17) as u8,
+            (pcr_base >> 9) as u8,
+            (pcr_base >> 1) as u8,
+            ((pcr_base & 1) << 7) as u8 | 0x7e,
+            0x00,
+        ]);
+
+        let mut remaining = pes.as_slice();
+        let mut first = true;
+        while !remaining.is_empty() {
+            let mut adaptation = if first { std::mem::take(&mut first_fields) } else { Vec::new() };
+            let adaptation_size = if adaptation.is_empty() { 0 } else { 1 + adaptation.len() };
+            let size = remaining.len().min(PAYLOAD_SIZE - adaptation_size);
+
+            // Pad the last packet with adaptation field stuffing
+            let stuffing = PAYLOAD_SIZE - adaptation_size - size;
+            if stuffing > 0 {
+                if adaptation_size == 0 {
+                    // Either just the length byte, or length, flags and stuffing
+                    if stuffing > 1 {
+                        adaptation.push(0x00);
+                    }
+                    adaptation.resize(stuffing - 1, 0xff);
+                } else {
+                    adaptation.resize(adaptation.len() + stuffing, 0xff);
+                }
+            }
+            let has_adaptation = adaptation_size > 0 || stuffing > 0;
+
+            write_header(out, VIDEO_PID, first, has_adaptation, &mut self.video_continuity);
+            if has_adaptation {
+                out.push(adaptation.len() as u8);
+                out.extend_from_slice(&adaptation);
+            }
+            out.extend_from_slice(&remaining[..size]);
+
+            remaining = &remaining[size..];
+            first = false;
+        }
+    }
+}
+
+fn starts_with_delimiter(frame: &[u8]) -> bool {
+    let nal = match frame {
+        [0, 0, 0, 1, nal, ..] | [0, 0, 1, nal, ..] => *nal,
+        _ => return false,
+    };
+    nal & 0x1f == 9
+}
+
+fn write_header(out: &mut Vec<u8>, pid: u16, unit_start: bool, adaptation: bool, continuity: &mut u8) {
+    out.push(0x47);
+    out.push(if unit_start { 0x40 } else { 0x00 } | (pid >> 8) as u8 & 0x1f);
+    out.push(pid as u8);
+    out.push(if adaptation { 0x30 } else { 0x10 } | *continuity);
+    *continuity = (*continuity + 1) & 0x0f;
+}
+
+// Write a PSI table section, which always fits in one packet here
+fn write_section(out: &mut Vec<u8>, pid: u16, continuity: &mut u8, table_id: u8, table_id_extension: u16, data: &[u8]) {
+    let start = out.len();
+    write_header(out, pid, true, false, continuity);
+    out.push(0x00); // Pointer field
+
+    let section_start = out.len();
+    let section_length = 5 + data.len() + 4;
+    out.push(table_id);
+    out.extend_from_slice(&(0xb000 | section_length as u16).to_be_bytes());
+    out.extend_from_slice(&table_id_extension.to_be_bytes());
+    out.extend_from_slice(&[0xc1, 0x00, 0x00]); // Version 0, current, single section
+    out.extend_from_slice(data);
+    let crc = crc32(&out[section_start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+
+    out.resize(start + PACKET_SIZE, 0xff);
+}
+
+// CRC-32/MPEG-2 of PSI sections
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for byte in data {
+        crc ^= (*byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 { (crc << 1) ^ 0x04c1_1db7 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A parsed transport stream packet
+    struct Packet<'a> {
+        pid: u16,
+        unit_start: bool,
+        continuity: u8,
+        adaptation: Option<&'a [u8]>,
+        payload: &'a [u8],
+    }
+
+    fn packets(data: &[u8]) -> Vec<Packet<'_>> {
+        assert_eq!(data.len() % PACKET_SIZE, 0, "stream is not made of whole packets");
+        data.chunks(PACKET_SIZE)
+            .map(|packet| {
+                assert_eq!(packet[0], 0x47, "lost sync");
+                let control = packet[3] >> 4;
+                let (adaptation, payload_start) = if control & 0x2 != 0 {
+                    let length = packet[4] as usize;
+                    (Some(&packet[5..5 + length]), 5 + length)
+                } else {
+                    (None, 4)
+                };
+                assert_eq!(control & 0x1, 1, "packet without payload");
+                Packet {
+                    pid: u16::from_be_bytes([packet[1] & 0x1f, packet[2]]),
+                    unit_start: packet[1] & 0x40 != 0,
+                    continuity: packet[3] & 0x0f,
+                    adaptation,
+                    payload: &packet[payload_start..],
+                }
+            })
+            .collect()
+    }
+
+    fn frame(size: usize) -> Vec<u8> {
+        let mut frame = vec![0x00, 0x00, 0x00, 0x01, 0x65];
+        frame.extend((0..size.saturating_sub(5)).map(|i| (i % 200) as u8 + 1));
+        frame
+    }
+
+    // PES packet of a single written frame
+    fn write_pes(muxer: &mut TsMuxer, frame: &[u8], time_90khz: u64, keyframe: bool) -> (Vec<u8>, Vec<u8>) {
+        let mut out = Vec::new();
+        muxer.write_frame(&mut out, frame, time_90khz, keyframe);
+        let pes = packets(&out).iter().flat_map(|packet| packet.payload.to_vec()).collect();
+        (out, pes)
+    }
+
+    fn read_timestamp(bytes: &[u8]) -> u64 {
+        ((bytes[0] as u64 >> 1) & 0x07) << 30
+            | (bytes[1] as u64) << 22
+            | (bytes[2] as u64 >> 1) << 15
+            | (bytes[3] as u64) << 7
+            | bytes[4] as u64 >> 1
+    }
+
+    #[test]
+    fn crc32_is_mpeg2() {
+        assert_eq!(crc32(b"123456789"), 0x0376_e6e7);
+    }
+
+    #[test]
+    fn pat_matches_reference() {
+        let mut out = Vec::new();
+        TsMuxer::new().write_tables(&mut out);
+        assert_eq!(out.len(), 2 * PACKET_SIZE);
+
+        let expected = [
+            0x47, 0x40, 0x00, 0x10, 0x00, 0x00, 0xb0, 0x0d, 0x00, 0x01, 0xc1, 0x00, 0x00, 0x00, 0x01, 0xf0, 0x00,
+            0x2a, 0xb1, 0x04, 0xb2,
+        ];
+        assert_eq!(&out[..expected.len()], &expected);
+        assert!(out[expected.len()..PACKET_SIZE].iter().all(|byte| *byte == 0xff));
+    }
+
+    #[test]
+    fn pmt_describes_the_video_stream() {
+        let mut out = Vec::new();
+        TsMuxer::new().write_tables(&mut out);
+        let pmt = &packets(&out)[1];
+        assert_eq!(pmt.pid, PMT_PID);
+        assert!(pmt.unit_start);
+
+        let section = &pmt.payload[1..];
+        assert_eq!(section[0], 0x02);
+        let length = (u16::from_be_bytes([section[1], section[2]]) & 0x0fff) as usize;
+        let section = &section[..3 + length];
+        // A section followed by its CRC has a remainder of zero
+        assert_eq!(crc32(section), 0);
+        assert_eq!(u16::from_be_bytes([section[8], section[9]]) & 0x1fff, VIDEO_PID);
+        assert_eq!(section[12], STREAM_TYPE_H264);
+        assert_eq!(u16::from_be_bytes([section[13], section[14]]) & 0x1fff, VIDEO_PID);
+    }
+
+    #[test]
+    fn pes_carries_delayed_pts() {
+        let mut muxer = TsMuxer::new();
+        let time = 5 * 90_000 + 1234;
+        let (_, pes) = write_pes(&mut muxer, &frame(100), time, true);
+
+        assert_eq!(&pes[..4], &[0x00, 0x00, 0x01, 0xe0]);
+        assert_eq!(pes[7], 0x80, "PTS only");
+        assert_eq!(pes[8], 5);
+        assert_eq!(pes[9] >> 4, 0x2);
+        assert_eq!(read_timestamp(&pes[9..14]), time + PTS_DELAY);
+
+        // The delimiter is added in front of the frame
+        assert_eq!(&pes[14..20], &ACCESS_UNIT_DELIMITER);
+        assert_eq!(&pes[20..], frame(100).as_slice());
+    }
+
+    #[test]
+    fn pts_wraps_at_33_bits() {
+        let mut muxer = TsMuxer::new();
+        let time = (1u64 << 33) - 10;
+        let (_, pes) = write_pes(&mut muxer, &frame(20), time, false);
+        assert_eq!(read_timestamp(&pes[9..14]), PTS_DELAY - 10);
+    }
+
+    #[test]
+    fn existing_delimiter_is_kept() {
+        let mut muxer = TsMuxer::new();
+        let mut with_delimiter = ACCESS_UNIT_DELIMITER.to_vec();
+        with_delimiter.extend_from_slice(&frame(50));
+        let (_, pes) = write_pes(&mut muxer, &with_delimiter, 0, true);
+        assert_eq!(&pes[14..], with_delimiter.as_slice());
+    }
+
+    #[test]
+    fn first_packet_carries_pcr_and_random_access() {
+        let mut muxer = TsMuxer::new();
+        let time = 123_456_789;
+        for keyframe in [true, false] {
+            let mut out = Vec::new();
+            muxer.write_frame(&mut out, &frame(1000), time, keyframe);
+            let packets = packets(&out);
+
+            let adaptation = packets[0].adaptation.expect("no adaptation field");
+            assert_eq!(adaptation[0] & 0x40 != 0, keyframe, "random access indicator");
+            assert_ne!(adaptation[0] & 0x10, 0, "PCR flag");
+            let pcr_base = (adaptation[1] as u64) << 25
+                | (adaptation[2] as u64) << 17
+                | (adaptation[3] as u64) << 9
+                | (adaptation[4] as u64) << 1
+                | (adaptation[5] as u64) >> 7;
+            assert_eq!(pcr_base, time);
+            assert_eq!(adaptation[6], 0, "PCR extension");
+
+            assert!(packets[0].unit_start);
+            assert!(packets[1..].iter().all(|packet| !packet.unit_start));
+        }
+    }
+
+    #[test]
+    fn last_packet_is_stuffed_for_every_size() {
+        let mut muxer = TsMuxer::new();
+        let mut continuity = None;
+        // Covers stuffing of 0, 1, 2 and more bytes, in the first and later packets
+        for size in 5..600 {
+            let data = frame(size);
+            let (out, pes) = write_pes(&mut muxer, &data, 0, false);
+            assert_eq!(&pes[20..], data.as_slice(), "size {}", size);
+
+            for packet in packets(&out) {
+                assert_eq!(packet.pid, VIDEO_PID);
+                if let Some(previous) = continuity {
+                    assert_eq!(packet.continuity, (previous + 1) & 0x0f);
+                }
+                continuity = Some(packet.continuity);
+
+                // Anything after the flags (and the PCR) is stuffing
+                if let Some(adaptation) = packet.adaptation {
+                    let fields = if packet.unit_start { 7 } else { 1 };
+                    assert!(adaptation.iter().skip(fields).all(|byte| *byte == 0xff), "size {}", size);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,291 @@
+// Image watermark / logo composited onto streams
+//
+// A gdkpixbufoverlay element is added to the media pipeline. Its position is
+// worked out from the negotiated frame size and the image size, and both are
+// re-checked every second so resolution changes and edits to the image file
+// are picked up while the media runs.
+
+use anyhow::{Context, Result};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use log::{info, warn};
+use std::fs;
+use std::io::Read;
+use std::time::SystemTime;
+
+use crate::config::WatermarkConfig;
+use crate::overlay;
+
+/// Name of the gdkpixbufoverlay element in the media pipeline
+const ELEMENT_NAME: &str = "watermark";
+
+/// Pipeline fragment to insert before the encoder (empty if disabled)
+pub fn launch_fragment(config: &WatermarkConfig) -> String {
+    if config.image.is_empty() {
+        String::new()
+    } else {
+        format!("gdkpixbufoverlay name={} positioning-mode=pixels-absolute ! ", ELEMENT_NAME)
+    }
+}
+
+/// Configure the watermark of a newly created media and keep it up to date
+pub fn attach(bin: &gst::Bin, config: &WatermarkConfig) {
+    if config.image.is_empty() {
+        return;
+    }
+
+    let overlay = match bin.by_name(ELEMENT_NAME) {
+        Some(overlay) => overlay,
+        None => {
+            warn!("No watermark overlay found in media for {}", config.image);
+            return;
+        }
+    };
+
+    overlay.set_property("alpha", config.opacity.clamp(0.0, 1.0));
+
+    let config = config.clone();
+    let mut state = WatermarkState::default();
+    state.update(&overlay, &config);
+
+    // Refreshed from the calling thread's default main context
+    let overlay = overlay.downgrade();
+    let refresh = glib::timeout_source_new_seconds(1, None, glib::PRIORITY_DEFAULT, move || {
+        match overlay.upgrade() {
+            Some(overlay) => {
+                state.update(&overlay, &config);
+                glib::Continue(true)
+            }
+            // The media was torn down
+            None => glib::Continue(false),
+        }
+    });
+    refresh.attach(Some(&glib::MainContext::ref_thread_default()));
+}
+
+// What has been applied to the overlay element so far
+#[derive(Default)]
+struct WatermarkState {
+    modified: Option<SystemTime>,
+    image_size: (u32, u32),
+    frame_size: (u32, u32),
+}
+
+impl WatermarkState {
+    fn update(&mut self, overlay: &gst::Element, config: &WatermarkConfig) {
+        let mut changed = false;
+
+        // (Re)load the image when the file changes
+        let modified = fs::metadata(&config.image).and_then(|m| m.modified()).ok();
+        if modified != self.modified {
+            match png_size(&config.image) {
+                Ok(size) => {
+                    overlay.set_property("location", &config.image);
+                    if self.modified.is_some() {
+                        info!("Reloaded watermark image {}", config.image);
+                    }
+                    self.image_size = size;
+                    changed = true;
+                }
+                Err(e) => warn!("Failed to load watermark image: {:#}", e),
+            }
+            self.modified = modified;
+        }
+
+        if let Some(frame_size) = frame_size(overlay) {
+            if frame_size != self.frame_size {
+                self.frame_size = frame_size;
+                changed = true;
+            }
+        }
+
+        if changed && self.frame_size != (0, 0) && self.image_size != (0, 0) {
+            self.apply(overlay, config);
+        }
+    }
+
+    // Scale and position the image inside the frame
+    fn apply(&self, overlay: &gst::Element, config: &WatermarkConfig) {
+        let scale = if config.scale > 0.0 { config.scale } else { 1.0 };
+        let width = (self.image_size.0 as f64 * scale).round() as i32;
+        let height = (self.image_size.1 as f64 * scale).round() as i32;
+        let (frame_width, frame_height) = (self.frame_size.0 as i32, self.frame_size.1 as i32);
+        let margin = config.margin as i32;
+
+        let (vertical, horizontal) = overlay::alignment(&config.position);
+
+        let x = match horizontal {
+            "left" => margin,
+            "right" => frame_width - width - margin,
+            _ => (frame_width - width) / 2,
+        };
+        let y = match vertical {
+            "top" => margin,
+            "bottom" => frame_height - height - margin,
+            _ => (frame_height - height) / 2,
+        };
+
+        overlay.set_property("overlay-width", width);
+        overlay.set_property("overlay-height", height);
+        overlay.set_property("offset-x", x.max(0));
+        overlay.set_property("offset-y", y.max(0));
+    }
+}
+
+// Size of the frames currently flowing into the overlay
+fn frame_size(overlay: &gst::Element) -> Option<(u32, u32)> {
+    let caps = overlay.static_pad("sink")?.current_caps()?;
+    let structure = caps.structure(0)?;
+    let width = structure.get::<i32>("width").ok()?;
+    let height = structure.get::<i32>("height").ok()?;
+    Some((width as u32, height as u32))
+}
+
+// Read the image size from the PNG header
+fn png_size(path: &str) -> Result<(u32, u32)> {
+    const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+    let mut data = [0u8; 24];
+    fs::File::open(path)
+        .and_then(|mut file| file.read_exact(&mut data))
+        .with_context(|| format!("Failed to read {}", path))?;
+    if &data[..8] != SIGNATURE || &data[12..16] != b"IHDR" {
+        anyhow::bail!("{} is not a PNG image", path);
+    }
+
+    let width = u32::from_be_bytes([data[16], data[17], data[18], data[19]]);
+    let height = u32::from_be_bytes([data[20], data[21], data[22], data[23]]);
+    Ok((width, height))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gstreamer_app::{AppSink, AppSrc};
+    use std::path::PathBuf;
+    use std::thread;
+    use std::time::Duration;
+
+    const WIDTH: usize = 64;
+    const HEIGHT: usize = 48;
+    const IMAGE_SIZE: usize = 16;
+    const MARGIN: usize = 4;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("display_rtsp_streamer-{}-{}", std::process::id(), name))
+    }
+
+    // Run a pipeline until it ends
+    fn run(launch_str: &str) {
+        let pipeline = gst::parse_launch(launch_str).unwrap();
+        pipeline.set_state(gst::State::Playing).unwrap();
+        let message = pipeline.bus().unwrap().timed_pop_filtered(
+            gst::ClockTime::from_seconds(10),
+            &[gst::MessageType::Eos, gst::MessageType::Error],
+        );
+        pipeline.set_state(gst::State::Null).unwrap();
+        assert!(matches!(message.as_ref().map(|m| m.view()), Some(gst::MessageView::Eos(_))));
+    }
+
+    // Push a black BGRx frame through the pipeline and return what comes out
+    fn process_frame(appsrc: &AppSrc, appsink: &AppSink, index: u64) -> Vec<u8> {
+        let mut buffer = gst::Buffer::from_mut_slice(vec![0u8; WIDTH * HEIGHT * 4]);
+        buffer.get_mut().unwrap().set_pts(gst::ClockTime::from_mseconds(index * 100));
+        appsrc.push_buffer(buffer).unwrap();
+
+        let sample = appsink.try_pull_sample(gst::ClockTime::from_seconds(5)).expect("No frame");
+        let frame = sample.buffer().unwrap().map_readable().unwrap().to_vec();
+        frame
+    }
+
+    fn pixel(frame: &[u8], x: usize, y: usize) -> (u8, u8, u8) {
+        let i = (y * WIDTH + x) * 4;
+        (frame[i], frame[i + 1], frame[i + 2])
+    }
+
+    fn is_red((b, g, r): (u8, u8, u8)) -> bool {
+        r > 200 && g < 60 && b < 60
+    }
+
+    fn is_black((b, g, r): (u8, u8, u8)) -> bool {
+        r < 30 && g < 30 && b < 30
+    }
+
+    // Needs GStreamer with videotestsrc, pngenc and gdkpixbufoverlay:
+    // cargo test watermark -- --ignored
+    #[test]
+    #[ignore]
+    fn watermark_is_drawn_at_its_position() {
+        crate::rtsp::init().unwrap();
+
+        let image = temp_path("watermark.png");
+        run(&format!(
+            "videotestsrc num-buffers=1 pattern=solid-color foreground-color=0xffff0000 ! \
+             video/x-raw,format=RGBA,width={},height={} ! pngenc ! filesink location=\"{}\"",
+            IMAGE_SIZE,
+            IMAGE_SIZE,
+            image.display()
+        ));
+
+        let config = WatermarkConfig {
+            image: image.to_string_lossy().into_owned(),
+            position: "bottom-right".to_string(),
+            margin: MARGIN as u32,
+            opacity: 1.0,
+            scale: 1.0,
+        };
+
+        // The same conversion as a media pipeline, then back to BGRx
+        let pipeline = gst::parse_launch(&format!(
+            "appsrc name=source format=time caps=video/x-raw,format=BGRx,width={},height={},framerate=10/1 ! \
+             videoconvert ! video/x-raw,format=I420 ! {}videoconvert ! video/x-raw,format=BGRx ! \
+             appsink name=sink sync=false",
+            WIDTH,
+            HEIGHT,
+            launch_fragment(&config)
+        ))
+        .unwrap()
+        .downcast::<gst::Pipeline>()
+        .unwrap();
+        let appsrc = pipeline.by_name("source").unwrap().downcast::<AppSrc>().unwrap();
+        let appsink = pipeline.by_name("sink").unwrap().downcast::<AppSink>().unwrap();
+
+        // Refresh on a context of our own, which only this test iterates
+        let context = glib::MainContext::new();
+        context.with_thread_default(|| attach(pipeline.upcast_ref(), &config)).unwrap();
+        pipeline.set_state(gst::State::Playing).unwrap();
+
+        // The image is placed once the frame size is known, which the
+        // periodic refresh picks up
+        process_frame(&appsrc, &appsink, 0);
+        thread::sleep(Duration::from_millis(1100));
+        while context.iteration(false) {}
+
+        let frame = process_frame(&appsrc, &appsink, 1);
+        pipeline.set_state(gst::State::Null).unwrap();
+        let _ = fs::remove_file(&image);
+
+        let left = WIDTH - MARGIN - IMAGE_SIZE;
+        let top = HEIGHT - MARGIN - IMAGE_SIZE;
+        for (x, y) in [(left + 2, top + 2), (left + 8, top + 8), (WIDTH - MARGIN - 3, HEIGHT - MARGIN - 3)] {
+            assert!(is_red(pixel(&frame, x, y)), "({}, {}) is {:?}", x, y, pixel(&frame, x, y));
+        }
+        for (x, y) in [(0, 0), (left - 2, top + 8), (left + 8, top - 2), (WIDTH - 2, HEIGHT - 2)] {
+            assert!(is_black(pixel(&frame, x, y)), "({}, {}) is {:?}", x, y, pixel(&frame, x, y));
+        }
+    }
+
+    #[test]
+    fn png_size_reads_the_header() {
+        let path = temp_path("header.png");
+        let mut data = b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR".to_vec();
+        data.extend_from_slice(&640u32.to_be_bytes());
+        data.extend_from_slice(&480u32.to_be_bytes());
+        fs::write(&path, &data).unwrap();
+        assert_eq!(png_size(path.to_str().unwrap()).unwrap(), (640, 480));
+
+        fs::write(&path, b"GIF89a not a png at all").unwrap();
+        assert!(png_size(path.to_str().unwrap()).is_err());
+        let _ = fs::remove_file(&path);
+    }
+}
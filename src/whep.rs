@@ -0,0 +1,477 @@
+// WebRTC egress using WHEP (WebRTC-HTTP Egress Protocol)
+//
+// A viewer POSTs its SDP offer to /whep/display<N> and gets the answer back
+// with every ICE candidate already in it (host candidates only, which is
+// all a LAN needs), so no trickle ICE or STUN/TURN is involved. Each session
+// runs its own webrtcbin pipeline; DELETE on the session URL returned in
+// Location ends it, and so does the peer connection failing, closing or
+// staying disconnected (a closed browser tab) for DISCONNECTED_TIMEOUT.
+//
+// The video comes from the display's encoder in encoder.rs, shared with HLS,
+// recording and publishing, rather than from the RTSP server: its media are
+// created per RTSP client by gst-rtsp-server and aren't there to tap when
+// nobody watches over RTSP. The encoder only runs while a session (or another
+// output) wants it, so an idle WHEP endpoint costs no encoding.
+
+use anyhow::{Context, Result};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use gstreamer_app::AppSrc;
+use gstreamer_sdp as gst_sdp;
+use gstreamer_webrtc as gst_webrtc;
+use log::{info, warn};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+use tiny_http::{Method, Request, Response};
+
+use crate::encoder::{self, EncodedFrame, EncoderHold, Encoders};
+use crate::http::{self, HttpServer};
+
+const PATH_PREFIX: &str = "/whep/";
+
+/// Longest wait for ICE candidate gathering before answering
+const GATHERING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a disconnected viewer has to come back before its session ends
+const DISCONNECTED_TIMEOUT: Duration = Duration::from_secs(10);
+
+type Sessions = Arc<Mutex<HashMap<String, Session>>>;
+
+// A connected viewer
+struct Session {
+    slot: usize,
+    pipeline: gst::Pipeline,
+    // Keeps the display's encoder running
+    _hold: EncoderHold,
+    // Set once the session ended; stops the encoder subscription
+    closed: Arc<AtomicBool>,
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::SeqCst);
+        let _ = self.pipeline.set_state(gst::State::Null);
+    }
+}
+
+struct WhepService {
+    encoders: Encoders,
+    sessions: Sessions,
+}
+
+/// Serve the WHEP endpoint of each display on the HTTP server
+pub fn register(http_server: &HttpServer, encoders: Encoders) {
+    let service = Arc::new(WhepService {
+        encoders,
+        sessions: Sessions::default(),
+    });
+    http_server.route(PATH_PREFIX, move |request| service.handle(request));
+}
+
+impl WhepService {
+    fn handle(&self, mut request: Request) {
+        let path = request.url().split('?').next().unwrap_or("").to_string();
+        let (slot, session_id) = match path
+            .strip_prefix(PATH_PREFIX)
+            .and_then(|rest| rest.strip_prefix("display"))
+            .map(|rest| rest.split_once('/').unwrap_or((rest, "")))
+            .and_then(|(slot, session_id)| Some((slot.parse::<usize>().ok()?, session_id.to_string())))
+        {
+            Some(target) => target,
+            None => {
+                respond(request, Response::empty(404));
+                return;
+            }
+        };
+
+        match (request.method(), session_id.is_empty()) {
+            // CORS preflight of browser players on another origin
+            (Method::Options, _) => {
+                let response = Response::empty(204)
+                    .with_header(http::header("Access-Control-Allow-Methods", "POST, DELETE, OPTIONS"))
+                    .with_header(http::header("Access-Control-Allow-Headers", "Content-Type, Authorization"));
+                respond(request, response);
+            }
+            (Method::Post, true) => {
+                let offer = match http::read_body(&mut request) {
+                    Ok(offer) => offer,
+                    Err(e) => {
+                        warn!("WHEP request failed: {:#}", e);
+                        respond(request, Response::empty(400));
+                        return;
+                    }
+                };
+
+                match self.start_session(slot, &offer) {
+                    Ok((session_id, answer)) => {
+                        info!("WHEP session {} started for display {}", session_id, slot);
+                        let location = format!("{}display{}/{}", PATH_PREFIX, slot, session_id);
+                        let response = Response::from_data(answer.into_bytes())
+                            .with_status_code(201)
+                            .with_header(http::header("Content-Type", "application/sdp"))
+                            .with_header(http::header("Location", &location))
+                            .with_header(http::header("Access-Control-Expose-Headers", "Location"));
+                        respond(request, response);
+                    }
+                    Err(e) => {
+                        warn!("Failed to start WHEP session for display {}: {:#}", slot, e);
+                        respond(request, Response::from_string(format!("{:#}", e)).with_status_code(400));
+                    }
+                }
+            }
+            (Method::Delete, false) => {
+                let session = self.sessions.lock().unwrap().remove(&session_id);
+                match session {
+                    Some(_) => {
+                        info!("WHEP session {} ended by the viewer", session_id);
+                        respond(request, Response::empty(200));
+                    }
+                    None => respond(request, Response::empty(404)),
+                }
+            }
+            // Trickle ICE and ICE restarts aren't supported
+            _ => respond(request, Response::empty(405)),
+        }
+    }
+
+    // Answer `offer` with a new webrtcbin session fed by the display's encoder
+    fn start_session(&self, slot: usize, offer: &str) -> Result<(String, String)> {
+        let encoder = self
+            .encoders
+            .lock()
+            .unwrap()
+            .get(&slot)
+            .cloned()
+            .context("Display is not being streamed")?;
+
+        let offer = gst_sdp::SDPMessage::parse_buffer(offer.as_bytes()).context("Invalid SDP offer")?;
+        let payload_type = h264_payload_type(&offer).context("Offer does not accept H.264 video")?;
+
+        // The encoder's access units go out as they are, only repacketized
+        let launch_str = format!(
+            "appsrc name=source is-live=true format=time do-timestamp=true \
+             caps=\"video/x-h264,stream-format=byte-stream,alignment=au\" ! \
+             h264parse ! rtph264pay config-interval=-1 aggregate-mode=zero-latency pt={} ! \
+             application/x-rtp,media=video,encoding-name=H264,clock-rate=90000,payload={} ! \
+             webrtcbin name=webrtc bundle-policy=max-bundle",
+            payload_type, payload_type
+        );
+        let pipeline = gst::parse_launch(&launch_str)
+            .context("Failed to create WebRTC pipeline")?
+            .downcast::<gst::Pipeline>()
+            .map_err(|_| anyhow::anyhow!("WebRTC pipeline is not a pipeline"))?;
+        let appsrc = pipeline
+            .by_name("source")
+            .and_then(|source| source.downcast::<AppSrc>().ok())
+            .context("No appsrc in WebRTC pipeline")?;
+        let webrtcbin = pipeline.by_name("webrtc").context("No webrtcbin in WebRTC pipeline")?;
+
+        let session_id = new_session_id()?;
+        let closed = Arc::new(AtomicBool::new(false));
+        {
+            let sessions = Arc::downgrade(&self.sessions);
+            let session_id = session_id.clone();
+            let closed = closed.clone();
+            webrtcbin.connect_notify(Some("connection-state"), move |webrtcbin, _| {
+                match webrtcbin.property::<gst_webrtc::WebRTCPeerConnectionState>("connection-state") {
+                    gst_webrtc::WebRTCPeerConnectionState::Failed | gst_webrtc::WebRTCPeerConnectionState::Closed => {
+                        end_session(&sessions, &session_id, &closed, None);
+                    }
+                    gst_webrtc::WebRTCPeerConnectionState::Disconnected => {
+                        end_session(&sessions, &session_id, &closed, Some(webrtcbin.downgrade()));
+                    }
+                    _ => {}
+                }
+            });
+        }
+
+        {
+            let sessions = Arc::downgrade(&self.sessions);
+            let session_id = session_id.clone();
+            let closed = closed.clone();
+            encoder::watch_bus(&pipeline, &format!("WebRTC session for display {}", slot), move || {
+                end_session(&sessions, &session_id, &closed, None);
+            });
+        }
+
+        let session = Session {
+            slot,
+            pipeline: pipeline.clone(),
+            _hold: encoder.hold(),
+            closed: closed.clone(),
+        };
+
+        pipeline
+            .set_state(gst::State::Playing)
+            .context("Failed to start WebRTC pipeline")?;
+
+        // Offer/answer; the promises are resolved by webrtcbin's own thread
+        let offer = gst_webrtc::WebRTCSessionDescription::new(gst_webrtc::WebRTCSDPType::Offer, offer);
+        webrtcbin.emit_by_name::<()>("set-remote-description", &[&offer, &None::<gst::Promise>]);
+
+        let promise = gst::Promise::new();
+        webrtcbin.emit_by_name::<()>("create-answer", &[&None::<gst::Structure>, &promise]);
+        promise.wait();
+        let answer = promise
+            .get_reply()
+            .and_then(|reply| reply.get::<gst_webrtc::WebRTCSessionDescription>("answer").ok())
+            .context("webrtcbin did not create an answer")?;
+        webrtcbin.emit_by_name::<()>("set-local-description", &[&answer, &None::<gst::Promise>]);
+
+        // Answer with every candidate, the client has no other way to get them
+        let deadline = Instant::now() + GATHERING_TIMEOUT;
+        while webrtcbin.property::<gst_webrtc::WebRTCICEGatheringState>("ice-gathering-state")
+            != gst_webrtc::WebRTCICEGatheringState::Complete
+        {
+            if Instant::now() >= deadline {
+                warn!("ICE gathering for display {} timed out, answering with the candidates so far", slot);
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        let answer = webrtcbin
+            .property::<Option<gst_webrtc::WebRTCSessionDescription>>("local-description")
+            .context("webrtcbin has no local description")?
+            .sdp()
+            .as_text()
+            .context("Failed to serialize SDP answer")?;
+
+        // Start streaming at the next keyframe, which we ask for right away
+        let mut started = false;
+        let subscribed = closed.clone();
+        encoder.subscribe(move |frame: &EncodedFrame| {
+            if subscribed.load(Ordering::SeqCst) {
+                return false;
+            }
+            started |= frame.keyframe;
+            if !started {
+                return true;
+            }
+
+            appsrc.push_buffer(frame.unstamped_buffer()).is_ok()
+        });
+        encoder.force_keyframe();
+
+        // The connection may already have failed while we were answering
+        let mut sessions = self.sessions.lock().unwrap();
+        if closed.load(Ordering::SeqCst) {
+            anyhow::bail!("Peer connection failed");
+        }
+        sessions.insert(session_id.clone(), session);
+
+        Ok((session_id, answer))
+    }
+}
+
+// End a session whose peer went away without a DELETE. With `disconnected`,
+// only once the connection is still disconnected after DISCONNECTED_TIMEOUT.
+// Runs on its own thread: stopping the pipeline from one of its own
+// threads (signal or bus handlers) would deadlock.
+fn end_session(
+    sessions: &Weak<Mutex<HashMap<String, Session>>>,
+    session_id: &str,
+    closed: &Arc<AtomicBool>,
+    disconnected: Option<glib::WeakRef<gst::Element>>,
+) {
+    let sessions = sessions.clone();
+    let session_id = session_id.to_string();
+    let closed = closed.clone();
+    thread::spawn(move || {
+        if let Some(webrtcbin) = disconnected {
+            thread::sleep(DISCONNECTED_TIMEOUT);
+            let still_disconnected = webrtcbin.upgrade().is_some_and(|webrtcbin| {
+                webrtcbin.property::<gst_webrtc::WebRTCPeerConnectionState>("connection-state")
+                    == gst_webrtc::WebRTCPeerConnectionState::Disconnected
+            });
+            if !still_disconnected {
+                return;
+            }
+        }
+
+        // Hold the lock so a session being set up sees the flag
+        let session = sessions.upgrade().and_then(|sessions| {
+            let mut sessions = sessions.lock().unwrap();
+            closed.store(true, Ordering::SeqCst);
+            sessions.remove(&session_id)
+        });
+        if let Some(session) = session {
+            info!("WHEP session {} for display {} closed", session_id, session.slot);
+        }
+    });
+}
+
+// Payload type of the offer's first H.264 format using non-interleaved
+// packetization, the one browsers decode
+fn h264_payload_type(offer: &gst_sdp::SDPMessage) -> Option<u32> {
+    let video = offer.medias().find(|media| media.media() == Some("video"))?;
+
+    let h264_formats: Vec<u32> = video
+        .attributes()
+        .filter(|attribute| attribute.key() == "rtpmap")
+        .filter_map(|attribute| attribute.value())
+        .filter_map(|rtpmap| rtpmap.split_once(' '))
+        .filter(|(_, encoding)| encoding.to_ascii_uppercase().starts_with("H264/90000"))
+        .filter_map(|(payload_type, _)| payload_type.parse().ok())
+        .collect();
+
+    let packetization_mode_1 = |payload_type: &u32| {
+        video
+            .attributes()
+            .filter(|attribute| attribute.key() == "fmtp")
+            .filter_map(|attribute| attribute.value())
+            .filter_map(|fmtp| fmtp.split_once(' '))
+            .any(|(format, parameters)| {
+                format.parse() == Ok(*payload_type) && parameters.contains("packetization-mode=1")
+            })
+    };
+
+    h264_formats
+        .iter()
+        .copied()
+        .find(packetization_mode_1)
+        .or_else(|| h264_formats.first().copied())
+}
+
+// Unguessable session id for the resource URL, which is all it takes to
+// end the session: 128 bits from the operating system's random source
+fn new_session_id() -> Result<String> {
+    let mut bytes = [0u8; 16];
+    getrandom::getrandom(&mut bytes).map_err(|e| anyhow::anyhow!("Failed to generate session id: {}", e))?;
+    Ok(bytes.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+fn respond<R: std::io::Read>(request: Request, response: Response<R>) {
+    let _ = request.respond(response.with_header(http::header("Access-Control-Allow-Origin", "*")));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, StreamConfig};
+    use crate::encoder::Encoder;
+    use std::sync::mpsc;
+
+    #[test]
+    fn session_ids_are_random_hex() {
+        let first = new_session_id().unwrap();
+        assert_eq!(first.len(), 32);
+        assert!(first.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(first, new_session_id().unwrap());
+    }
+
+    // A receive-only webrtcbin in this process plays the viewer. Needs
+    // GStreamer with x264, webrtcbin and libnice:
+    // cargo test local_client -- --ignored
+    #[test]
+    #[ignore]
+    fn local_client_receives_video() {
+        gst::init().unwrap();
+        let (width, height) = (320, 240);
+        let config = Config::default();
+        let stream = StreamConfig::default();
+        let encoder = Encoder::start("WHEP test", width, height, &config, &stream, Duration::from_secs(1), false);
+        let encoder = Arc::new(encoder.unwrap());
+        let service = WhepService {
+            encoders: Arc::new(Mutex::new(HashMap::from([(0, encoder.clone())]))),
+            sessions: Sessions::default(),
+        };
+
+        // Something that changes, like a real screen
+        let running = Arc::new(AtomicBool::new(true));
+        let feeder = {
+            let mount = encoder.mount();
+            let running = running.clone();
+            thread::spawn(move || {
+                let mut value = 0u8;
+                while running.load(Ordering::SeqCst) {
+                    let frame = vec![value; (width * height * 3) as usize];
+                    let _ = mount.push_frame(&frame, Duration::from_millis(33));
+                    value = value.wrapping_add(8);
+                    thread::sleep(Duration::from_millis(33));
+                }
+            })
+        };
+
+        let client = gst::parse_launch("webrtcbin name=viewer bundle-policy=max-bundle")
+            .unwrap()
+            .downcast::<gst::Pipeline>()
+            .unwrap();
+        let viewer = client.by_name("viewer").unwrap();
+        let caps = gst::Caps::builder("application/x-rtp")
+            .field("media", "video")
+            .field("encoding-name", "H264")
+            .field("clock-rate", 90000i32)
+            .field("payload", 102i32)
+            .field("packetization-mode", "1")
+            .build();
+        viewer.emit_by_name::<gst_webrtc::WebRTCRTPTransceiver>(
+            "add-transceiver",
+            &[&gst_webrtc::WebRTCRTPTransceiverDirection::Recvonly, &caps],
+        );
+
+        // Count the RTP packets that make it to the viewer
+        let (packets_tx, packets_rx) = mpsc::channel();
+        let packets_tx = Mutex::new(packets_tx);
+        let weak_client = client.downgrade();
+        viewer.connect_pad_added(move |_, pad| {
+            let client = match weak_client.upgrade() {
+                Some(client) => client,
+                None => return,
+            };
+            let sink = gst::ElementFactory::make("fakesink")
+                .property("signal-handoffs", true)
+                .build()
+                .unwrap();
+            let packets_tx = Mutex::new(packets_tx.lock().unwrap().clone());
+            sink.connect("handoff", false, move |_| {
+                let _ = packets_tx.lock().unwrap().send(());
+                None
+            });
+            client.add(&sink).unwrap();
+            sink.sync_state_with_parent().unwrap();
+            pad.link(&sink.static_pad("sink").unwrap()).unwrap();
+        });
+        client.set_state(gst::State::Playing).unwrap();
+
+        // Like a WHEP player that doesn't trickle: offer with all candidates
+        let promise = gst::Promise::new();
+        viewer.emit_by_name::<()>("create-offer", &[&None::<gst::Structure>, &promise]);
+        promise.wait();
+        let offer = promise
+            .get_reply()
+            .and_then(|reply| reply.get::<gst_webrtc::WebRTCSessionDescription>("offer").ok())
+            .unwrap();
+        viewer.emit_by_name::<()>("set-local-description", &[&offer, &None::<gst::Promise>]);
+        let deadline = Instant::now() + GATHERING_TIMEOUT;
+        while viewer.property::<gst_webrtc::WebRTCICEGatheringState>("ice-gathering-state")
+            != gst_webrtc::WebRTCICEGatheringState::Complete
+            && Instant::now() < deadline
+        {
+            thread::sleep(Duration::from_millis(20));
+        }
+        let offer = viewer
+            .property::<Option<gst_webrtc::WebRTCSessionDescription>>("local-description")
+            .unwrap()
+            .sdp()
+            .as_text()
+            .unwrap();
+
+        let (session_id, answer) = service.start_session(0, &offer).unwrap();
+        let answer = gst_sdp::SDPMessage::parse_buffer(answer.as_bytes()).unwrap();
+        let answer = gst_webrtc::WebRTCSessionDescription::new(gst_webrtc::WebRTCSDPType::Answer, answer);
+        viewer.emit_by_name::<()>("set-remote-description", &[&answer, &None::<gst::Promise>]);
+
+        let received = packets_rx.recv_timeout(Duration::from_secs(15));
+
+        running.store(false, Ordering::SeqCst);
+        feeder.join().unwrap();
+        let session = service.sessions.lock().unwrap().remove(&session_id);
+        client.set_state(gst::State::Null).unwrap();
+
+        assert!(received.is_ok(), "the viewer got no video");
+        assert!(session.is_some(), "the session ended early");
+    }
+}
@@ -0,0 +1,6 @@
+// X11-specific capture backends (Linux only)
+
+pub mod screen;
+pub mod window;
+#[cfg(test)]
+mod xvfb;
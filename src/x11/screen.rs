@@ -0,0 +1,287 @@
+// Native X11 display capture: XShm for grabs, XDamage for change regions
+//
+// Frames are copied by the X server straight into a shared memory segment,
+// and the DAMAGE extension tells us which parts of the screen were drawn to
+// since the previous grab. The capture loop uses those dirty rectangles in
+// place of comparing frames pixel by pixel.
+//
+// Works against any X server with MIT-SHM, DAMAGE and XFIXES, including Xvfb,
+// e.g. `Xvfb :99 -screen 0 1920x1080x24` and `DISPLAY=:99`.
+
+use anyhow::{Context, Result};
+use log::info;
+use std::io;
+use std::ptr;
+use x11rb::connection::Connection;
+use x11rb::protocol::damage::{ConnectionExt as _, ReportLevel};
+use x11rb::protocol::shm::{ConnectionExt as _, Seg};
+use x11rb::protocol::xfixes::{ConnectionExt as _, Region};
+use x11rb::protocol::xproto::{ImageFormat, Window};
+use x11rb::rust_connection::RustConnection;
+
+use crate::capture::{CapturedFrame, DisplayMetadata, FrameSource};
+use crate::damage::Rect;
+
+pub struct ScreenSource {
+    conn: RustConnection,
+    root: Window,
+    display: DisplayMetadata,
+    shm: SharedMemory,
+    seg: Seg,
+    damage: u32,
+    region: Region,
+    dirty: Vec<Rect>,
+}
+
+impl ScreenSource {
+    /// Start capturing the part of the X screen covered by `display`
+    pub fn open(display: &DisplayMetadata) -> Result<Self> {
+        let (conn, screen_num) = x11rb::connect(None).context("Failed to connect to the X server")?;
+        let root = conn.setup().roots[screen_num].root;
+
+        conn.shm_query_version()
+            .context("Failed to query the MIT-SHM extension")?
+            .reply()
+            .context("The X server does not support the MIT-SHM extension")?;
+        conn.damage_query_version(1, 1)
+            .context("Failed to query the DAMAGE extension")?
+            .reply()
+            .context("The X server does not support the DAMAGE extension")?;
+        conn.xfixes_query_version(2, 0)
+            .context("Failed to query the XFIXES extension")?
+            .reply()
+            .context("The X server does not support the XFIXES extension")?;
+
+        // One 32 bits per pixel frame
+        let shm = SharedMemory::new(display.width as usize * display.height as usize * 4)?;
+        let seg = conn.generate_id()?;
+        conn.shm_attach(seg, shm.id as u32, false)?
+            .check()
+            .context("Failed to attach shared memory segment")?;
+
+        // NON_EMPTY only sends an event when the damage goes from empty to
+        // non-empty; the rectangles themselves are fetched on every grab
+        let damage = conn.generate_id()?;
+        conn.damage_create(damage, root, ReportLevel::NON_EMPTY)?
+            .check()
+            .context("Failed to create damage object")?;
+        let region = conn.generate_id()?;
+        conn.xfixes_create_region(region, &[])?
+            .check()
+            .context("Failed to create region")?;
+
+        info!("Using X11 XShm capture for display at {},{} ({}x{})",
+              display.x, display.y, display.width, display.height);
+
+        Ok(Self {
+            conn,
+            root,
+            display: display.clone(),
+            shm,
+            seg,
+            damage,
+            region,
+            dirty: Vec::new(),
+        })
+    }
+
+    fn grab(&mut self) -> Result<()> {
+        // Take the damage first: anything drawn between this and the grab
+        // below is reported again next time, so nothing is missed
+        self.conn.damage_subtract(self.damage, x11rb::NONE, self.region)?;
+        let region = self.conn.xfixes_fetch_region(self.region)?.reply()?;
+
+        // Drop the DamageNotify events so they don't pile up in the connection
+        while self.conn.poll_for_event()?.is_some() {}
+
+        let display = &self.display;
+        self.dirty = region
+            .rectangles
+            .iter()
+            .map(|r| Rect {
+                x: r.x as i32 - display.x,
+                y: r.y as i32 - display.y,
+                width: r.width as u32,
+                height: r.height as u32,
+            })
+            .collect();
+
+        self.conn
+            .shm_get_image(
+                self.root,
+                display.x as i16,
+                display.y as i16,
+                display.width as u16,
+                display.height as u16,
+                !0,
+                ImageFormat::Z_PIXMAP.into(),
+                self.seg,
+                0,
+            )?
+            .reply()?;
+
+        Ok(())
+    }
+}
+
+impl FrameSource for ScreenSource {
+    fn frame(&mut self) -> io::Result<CapturedFrame<'_>> {
+        match self.grab() {
+            Ok(()) => Ok(CapturedFrame::damaged(
+                self.shm.as_slice(),
+                self.display.width,
+                self.display.height,
+                &self.dirty,
+            )),
+            // Most likely the screen layout changed under us
+            Err(e) => Err(io::Error::other(format!("{:#}", e))),
+        }
+    }
+}
+
+impl Drop for ScreenSource {
+    fn drop(&mut self) {
+        // Failures only mean the connection is already gone
+        let _ = self.conn.damage_destroy(self.damage);
+        let _ = self.conn.xfixes_destroy_region(self.region);
+        let _ = self.conn.shm_detach(self.seg);
+        let _ = self.conn.flush();
+    }
+}
+
+// A System V shared memory segment mapped into this process
+struct SharedMemory {
+    id: i32,
+    addr: *mut u8,
+    size: usize,
+}
+
+impl SharedMemory {
+    fn new(size: usize) -> Result<Self> {
+        // SAFETY: plain system calls; the result of each one is checked
+        unsafe {
+            let id = libc::shmget(libc::IPC_PRIVATE, size, libc::IPC_CREAT | 0o600);
+            if id < 0 {
+                return Err(io::Error::last_os_error()).context("Failed to create shared memory segment");
+            }
+
+            let addr = libc::shmat(id, ptr::null(), 0);
+            // Mark the segment for removal now: it lives on until both we
+            // and the X server have detached from it, even if we crash
+            libc::shmctl(id, libc::IPC_RMID, ptr::null_mut());
+            if addr as isize == -1 {
+                return Err(io::Error::last_os_error()).context("Failed to map shared memory segment");
+            }
+
+            Ok(Self {
+                id,
+                addr: addr as *mut u8,
+                size,
+            })
+        }
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        // SAFETY: the mapping is `size` bytes long and lives as long as self;
+        // the X server only writes to it during a grab, which takes &mut self
+        unsafe { std::slice::from_raw_parts(self.addr, self.size) }
+    }
+}
+
+impl Drop for SharedMemory {
+    fn drop(&mut self) {
+        // SAFETY: addr was returned by shmat and is not used after this
+        unsafe {
+            libc::shmdt(self.addr as *const libc::c_void);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::x11::xvfb::Xvfb;
+    use x11rb::protocol::xproto::{ConnectionExt as _, CreateGCAux, Rectangle};
+    use x11rb::wrapper::ConnectionExt as _;
+
+    const WIDTH: u32 = 320;
+    const HEIGHT: u32 = 240;
+
+    fn display(x: i32, width: u32) -> DisplayMetadata {
+        DisplayMetadata {
+            index: 0,
+            x,
+            y: 0,
+            width,
+            height: HEIGHT,
+            is_primary: true,
+            name: String::new(),
+        }
+    }
+
+    // Draw a rectangle of `color` (0xRRGGBB) straight onto the root window
+    fn fill(conn: &RustConnection, root: Window, color: u32, x: i16, y: i16, width: u16, height: u16) {
+        let gc = conn.generate_id().unwrap();
+        conn.create_gc(gc, root, &CreateGCAux::new().foreground(color)).unwrap();
+        conn.poly_fill_rectangle(root, gc, &[Rectangle { x, y, width, height }]).unwrap();
+        conn.free_gc(gc).unwrap();
+        conn.sync().unwrap();
+    }
+
+    // BGR of the pixel at (x, y) of a frame `width` pixels wide
+    fn pixel(frame: &[u8], width: u32, x: u32, y: u32) -> [u8; 3] {
+        let i = ((y * width + x) * 4) as usize;
+        [frame[i], frame[i + 1], frame[i + 2]]
+    }
+
+    fn covers(rects: &[Rect], x: i32, y: i32) -> bool {
+        rects
+            .iter()
+            .any(|r| x >= r.x && y >= r.y && x < r.x + r.width as i32 && y < r.y + r.height as i32)
+    }
+
+    // Needs Xvfb: cargo test -- --ignored xvfb
+    #[test]
+    #[ignore]
+    fn xvfb_capture_reports_drawing() {
+        let xvfb = Xvfb::start(WIDTH, HEIGHT);
+        std::env::set_var("DISPLAY", &xvfb.display);
+        let (conn, screen_num) = x11rb::connect(Some(&xvfb.display)).unwrap();
+        let root = conn.setup().roots[screen_num].root;
+
+        // The whole screen, and its right half as a second "display"
+        let mut screen = ScreenSource::open(&display(0, WIDTH)).unwrap();
+        let mut right = ScreenSource::open(&display(160, WIDTH - 160)).unwrap();
+        screen.frame().unwrap();
+        right.frame().unwrap();
+
+        fill(&conn, root, 0xff0000, 200, 50, 40, 20);
+
+        let frame = screen.frame().unwrap();
+        assert_eq!(frame.size(), (WIDTH, HEIGHT));
+        let rects = frame.dirty_rects().unwrap();
+        assert!(covers(rects, 200, 50) && covers(rects, 239, 69), "{:?}", rects);
+        assert!(!covers(rects, 10, 10), "{:?}", rects);
+        assert_eq!(pixel(&frame, WIDTH, 210, 60), [0, 0, 255]);
+        assert_eq!(pixel(&frame, WIDTH, 199, 60), [0, 0, 0]);
+        assert_eq!(pixel(&frame, WIDTH, 10, 10), [0, 0, 0]);
+
+        // Rects and pixels are relative to the display
+        let frame = right.frame().unwrap();
+        assert_eq!(frame.size(), (WIDTH - 160, HEIGHT));
+        let rects = frame.dirty_rects().unwrap();
+        assert!(covers(rects, 40, 50) && covers(rects, 79, 69), "{:?}", rects);
+        assert_eq!(pixel(&frame, WIDTH - 160, 50, 60), [0, 0, 255]);
+        assert_eq!(pixel(&frame, WIDTH - 160, 39, 60), [0, 0, 0]);
+
+        // Nothing drawn since the last grab
+        assert!(screen.frame().unwrap().dirty_rects().unwrap().is_empty());
+
+        // Drawing outside a display is not its damage
+        fill(&conn, root, 0x00ff00, 10, 10, 20, 20);
+        assert!(right.frame().unwrap().dirty_rects().unwrap().iter().all(|r| r.x + r.width as i32 <= 0));
+        let frame = screen.frame().unwrap();
+        assert!(covers(frame.dirty_rects().unwrap(), 15, 15));
+        assert_eq!(pixel(&frame, WIDTH, 15, 15), [0, 255, 0]);
+    }
+}
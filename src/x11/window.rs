@@ -0,0 +1,330 @@
+// Capture of a single application window on X11
+//
+// The window is redirected off-screen with the Composite extension, so its
+// contents can be read even while it is covered by other windows. A window
+// that is minimised or not yet mapped is reported as unavailable, which lets
+// the mount's placeholder take over until it comes back. The redirection is
+// undone when the capture stops.
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+use std::io;
+use x11rb::connection::Connection;
+use x11rb::protocol::composite::{ConnectionExt as _, Redirect};
+use x11rb::protocol::xproto::{AtomEnum, ConnectionExt as _, ImageFormat, MapState, Window};
+use x11rb::rust_connection::RustConnection;
+
+use crate::capture::{CapturedFrame, FrameSource};
+use crate::config::WindowConfig;
+
+pub struct WindowSource {
+    conn: RustConnection,
+    window: Window,
+    width: u32,
+    height: u32,
+    buffer: Vec<u8>,
+    viewable: bool,
+}
+
+impl WindowSource {
+    /// Find the window matching `config` and start redirecting it
+    pub fn open(config: &WindowConfig) -> Result<Self> {
+        let (conn, screen_num) = x11rb::connect(None).context("Failed to connect to the X server")?;
+        let root = conn.setup().roots[screen_num].root;
+
+        conn.composite_query_version(0, 4)
+            .context("Failed to query the Composite extension")?
+            .reply()
+            .context("The X server does not support the Composite extension")?;
+
+        let window = find_window(&conn, root, config)?
+            .with_context(|| format!("No window found matching {}", describe(config)))?;
+
+        conn.composite_redirect_window(window, Redirect::AUTOMATIC)
+            .context("Failed to redirect window")?;
+
+        let geometry = conn.get_geometry(window)
+            .context("Failed to get window geometry")?
+            .reply()
+            .context("Failed to get window geometry")?;
+
+        info!("Capturing window 0x{:x} for {}", window, describe(config));
+
+        Ok(Self {
+            conn,
+            window,
+            width: geometry.width as u32,
+            height: geometry.height as u32,
+            buffer: Vec::new(),
+            viewable: true,
+        })
+    }
+
+    fn grab(&mut self) -> Result<bool> {
+        let attributes = self.conn.get_window_attributes(self.window)?.reply()?;
+        self.viewable = attributes.map_state == MapState::VIEWABLE;
+        if !self.viewable {
+            return Ok(false);
+        }
+
+        let geometry = self.conn.get_geometry(self.window)?.reply()?;
+        self.width = geometry.width as u32;
+        self.height = geometry.height as u32;
+
+        // The pixmap only stays valid until the window is resized or unmapped,
+        // so it is named again for every frame
+        let pixmap = self.conn.generate_id()?;
+        self.conn.composite_name_window_pixmap(self.window, pixmap)?.check()?;
+        let image = self.conn
+            .get_image(ImageFormat::Z_PIXMAP, pixmap, 0, 0, geometry.width, geometry.height, !0)?
+            .reply();
+        self.conn.free_pixmap(pixmap)?;
+
+        self.buffer = image?.data;
+        Ok(true)
+    }
+}
+
+impl Drop for WindowSource {
+    fn drop(&mut self) {
+        // The window may be gone already, which is fine
+        let result = self
+            .conn
+            .composite_unredirect_window(self.window, Redirect::AUTOMATIC)
+            .map_err(anyhow::Error::from)
+            .and_then(|_| self.conn.flush().map_err(anyhow::Error::from));
+        if let Err(e) = result {
+            warn!("Failed to unredirect window 0x{:x}: {}", self.window, e);
+        }
+    }
+}
+
+impl FrameSource for WindowSource {
+    fn frame(&mut self) -> io::Result<CapturedFrame<'_>> {
+        match self.grab() {
+            // The size comes with every frame as the window can be resized
+            Ok(true) => Ok(CapturedFrame::new(&self.buffer, self.width, self.height)),
+            Ok(false) => Err(io::ErrorKind::WouldBlock.into()),
+            // Most likely the window was closed
+            Err(e) => Err(io::Error::other(format!("{:#}", e))),
+        }
+    }
+
+    fn available(&self) -> bool {
+        self.viewable
+    }
+}
+
+// Look for a matching top-level window, preferring the window manager's
+// client list and falling back to the children of the root window
+fn find_window(conn: &RustConnection, root: Window, config: &WindowConfig) -> Result<Option<Window>> {
+    let client_list = intern(conn, "_NET_CLIENT_LIST")?;
+    let candidates: Vec<Window> = match conn
+        .get_property(false, root, client_list, AtomEnum::WINDOW, 0, u32::MAX)?
+        .reply()?
+        .value32()
+    {
+        Some(windows) => windows.collect(),
+        None => conn.query_tree(root)?.reply()?.children,
+    };
+
+    for window in candidates {
+        if matches(&WindowProperties::read(conn, window)?, config) {
+            return Ok(Some(window));
+        }
+    }
+
+    Ok(None)
+}
+
+// What windows are matched on
+#[derive(Debug, Default)]
+struct WindowProperties {
+    pid: Option<u32>,
+    // Instance and class names, NUL separated
+    wm_class: String,
+    title: String,
+}
+
+impl WindowProperties {
+    fn read(conn: &RustConnection, window: Window) -> Result<Self> {
+        let wm_pid = intern(conn, "_NET_WM_PID")?;
+        let pid = conn
+            .get_property(false, window, wm_pid, AtomEnum::CARDINAL, 0, 1)?
+            .reply()?
+            .value32()
+            .and_then(|mut values| values.next());
+
+        let wm_class = string_property(conn, window, AtomEnum::WM_CLASS.into())?;
+
+        let mut title = string_property(conn, window, intern(conn, "_NET_WM_NAME")?)?;
+        if title.is_empty() {
+            title = string_property(conn, window, AtomEnum::WM_NAME.into())?;
+        }
+
+        Ok(Self { pid, wm_class, title })
+    }
+}
+
+// Every criterion that is set has to match
+fn matches(window: &WindowProperties, config: &WindowConfig) -> bool {
+    if config.pid.is_some() && window.pid != config.pid {
+        return false;
+    }
+
+    if !config.class.is_empty() && !window.wm_class.split('\0').any(|name| name == config.class) {
+        return false;
+    }
+
+    config.title.is_empty() || window.title.contains(&config.title)
+}
+
+fn string_property(conn: &RustConnection, window: Window, property: u32) -> Result<String> {
+    let reply = conn
+        .get_property(false, window, property, AtomEnum::ANY, 0, u32::MAX)?
+        .reply()?;
+    Ok(String::from_utf8_lossy(&reply.value).into_owned())
+}
+
+fn intern(conn: &RustConnection, name: &str) -> Result<u32> {
+    Ok(conn.intern_atom(false, name.as_bytes())?.reply()?.atom)
+}
+
+fn describe(config: &WindowConfig) -> String {
+    let mut criteria = Vec::new();
+    if !config.title.is_empty() {
+        criteria.push(format!("title '{}'", config.title));
+    }
+    if !config.class.is_empty() {
+        criteria.push(format!("class '{}'", config.class));
+    }
+    if let Some(pid) = config.pid {
+        criteria.push(format!("pid {}", pid));
+    }
+    if criteria.is_empty() {
+        "any window".to_string()
+    } else {
+        criteria.join(", ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::x11::xvfb::Xvfb;
+    use x11rb::protocol::xproto::{CreateWindowAux, PropMode, WindowClass};
+    use x11rb::wrapper::ConnectionExt as _;
+
+    fn config(title: &str, class: &str, pid: Option<u32>) -> WindowConfig {
+        WindowConfig {
+            name: "test".to_string(),
+            title: title.to_string(),
+            class: class.to_string(),
+            pid,
+        }
+    }
+
+    fn terminal() -> WindowProperties {
+        WindowProperties {
+            pid: Some(4242),
+            wm_class: "xterm\0XTerm\0".to_string(),
+            title: "user@host: ~/src".to_string(),
+        }
+    }
+
+    #[test]
+    fn title_matches_a_substring() {
+        assert!(matches(&terminal(), &config("host: ~", "", None)));
+        assert!(matches(&terminal(), &config("user@host: ~/src", "", None)));
+        assert!(!matches(&terminal(), &config("Host", "", None)));
+    }
+
+    #[test]
+    fn class_matches_instance_or_class_name_exactly() {
+        assert!(matches(&terminal(), &config("", "xterm", None)));
+        assert!(matches(&terminal(), &config("", "XTerm", None)));
+        assert!(!matches(&terminal(), &config("", "Term", None)));
+        assert!(!matches(&terminal(), &config("", "xterm\0XTerm", None)));
+    }
+
+    #[test]
+    fn pid_must_be_set_on_the_window() {
+        assert!(matches(&terminal(), &config("", "", Some(4242))));
+        assert!(!matches(&terminal(), &config("", "", Some(1))));
+
+        let no_pid = WindowProperties { pid: None, ..terminal() };
+        assert!(!matches(&no_pid, &config("", "", Some(4242))));
+    }
+
+    #[test]
+    fn every_criterion_has_to_match() {
+        assert!(matches(&terminal(), &config("~/src", "XTerm", Some(4242))));
+        assert!(!matches(&terminal(), &config("~/src", "XTerm", Some(1))));
+        assert!(!matches(&terminal(), &config("~/src", "Firefox", Some(4242))));
+        assert!(!matches(&terminal(), &config("Mozilla", "XTerm", Some(4242))));
+
+        // No criteria match any window, even one without properties
+        assert!(matches(&WindowProperties::default(), &config("", "", None)));
+    }
+
+    // Needs Xvfb: cargo test -- --ignored xvfb
+    #[test]
+    #[ignore]
+    fn xvfb_window_is_found_and_captured() {
+        let xvfb = Xvfb::start(320, 240);
+        std::env::set_var("DISPLAY", &xvfb.display);
+        let (conn, screen_num) = x11rb::connect(Some(&xvfb.display)).unwrap();
+        let screen = &conn.setup().roots[screen_num];
+
+        // A red 100x80 window; without a window manager it is found among
+        // the root window's children
+        let window = conn.generate_id().unwrap();
+        conn.create_window(
+            screen.root_depth,
+            window,
+            screen.root,
+            10,
+            10,
+            100,
+            80,
+            0,
+            WindowClass::INPUT_OUTPUT,
+            screen.root_visual,
+            &CreateWindowAux::new().background_pixel(0xff0000),
+        )
+        .unwrap();
+        let utf8_string = intern(&conn, "UTF8_STRING").unwrap();
+        let net_wm_name = intern(&conn, "_NET_WM_NAME").unwrap();
+        let net_wm_pid = intern(&conn, "_NET_WM_PID").unwrap();
+        let wm_class = b"capture\0CaptureTest\0";
+        conn.change_property8(PropMode::REPLACE, window, AtomEnum::WM_CLASS, AtomEnum::STRING, wm_class)
+            .unwrap();
+        let title = "Window capture test".as_bytes();
+        conn.change_property8(PropMode::REPLACE, window, net_wm_name, utf8_string, title).unwrap();
+        conn.change_property32(PropMode::REPLACE, window, net_wm_pid, AtomEnum::CARDINAL, &[4242]).unwrap();
+        conn.map_window(window).unwrap();
+        conn.sync().unwrap();
+
+        for config in [
+            config("capture test", "", None),
+            config("", "CaptureTest", None),
+            config("", "", Some(4242)),
+        ] {
+            let mut source = WindowSource::open(&config).unwrap();
+            assert_eq!(source.window, window);
+
+            let frame = source.frame().unwrap();
+            assert_eq!(frame.size(), (100, 80));
+            let i = (40 * 100 + 50) * 4;
+            assert_eq!(frame[i..i + 3], [0, 0, 255]);
+        }
+
+        assert!(WindowSource::open(&config("", "", Some(1))).is_err());
+
+        // Once the capture stops the window is no longer redirected, so it
+        // has no off-screen pixmap to name
+        drop(WindowSource::open(&config("", "CaptureTest", None)).unwrap());
+        let pixmap = conn.generate_id().unwrap();
+        assert!(conn.composite_name_window_pixmap(window, pixmap).unwrap().check().is_err());
+    }
+}
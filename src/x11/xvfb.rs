@@ -0,0 +1,40 @@
+// Xvfb servers for the X11 capture tests
+
+use std::io::{BufRead, BufReader};
+use std::process::{Child, Command, Stdio};
+
+/// An Xvfb server with a black root window, stopped when dropped
+pub struct Xvfb {
+    child: Child,
+    /// Display name to connect to, like ":99"
+    pub display: String,
+}
+
+impl Xvfb {
+    pub fn start(width: u32, height: u32) -> Self {
+        let mut child = Command::new("Xvfb")
+            .args(["-displayfd", "1", "-br", "-nolisten", "tcp", "-screen", "0"])
+            .arg(format!("{}x{}x24", width, height))
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Failed to start Xvfb");
+
+        // Xvfb picks a free display number and prints it once it is ready
+        let mut number = String::new();
+        BufReader::new(child.stdout.take().unwrap()).read_line(&mut number).unwrap();
+        assert!(!number.trim().is_empty(), "Xvfb did not start");
+
+        Self {
+            child,
+            display: format!(":{}", number.trim()),
+        }
+    }
+}
+
+impl Drop for Xvfb {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}